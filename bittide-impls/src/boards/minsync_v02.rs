@@ -1,5 +1,5 @@
 use bittide::bittide::{BittideChannelControl, BittideFifo};
-use controllers::si5351::Si5351Controller;
+use controllers::{si5351::Si5351Controller, si5351_i2c::SharedSi5351I2c};
 use minsync::{
     hal::{
         gpio::{bank0::*, FunctionI2c, FunctionPio0, FunctionPio1, Pin, PullUp},
@@ -9,15 +9,25 @@ use minsync::{
     },
     pac::{I2C1, PIO0, PIO1, RESETS},
 };
-use pitopi::{LinkConfig, Pitopi};
-use rp_pico::hal::gpio::{bank0::Gpio0, DefaultTypeState};
+use pitopi::{
+    rate::{poll_negotiation, RateNegotiator},
+    LinkConfig, LinkStateMachines, Pitopi,
+};
+use rp_pico::hal::{
+    gpio::{bank0::Gpio0, DefaultTypeState},
+    pio::{SM0, SM1, SM2, SM3},
+};
 
 use crate::chips::{self, rp2040::Rp2040Links};
 
 pub const BUFFER_SIZE: usize = 64; // TODO: buffer size at compile time is inconvenient for fast iteration, what else can we use?
 
+/// The Si5351 is written from the control interrupt through a shared non-blocking driver, which the I2C1
+/// interrupt drains.
+pub type Si5351Bus = &'static SharedSi5351I2c<minsync::clocks::SiI2C>;
+
 pub type Control = BittideChannelControl<
-    Si5351Controller<si5351::Si5351Device<minsync::clocks::SiI2C>>,
+    Si5351Controller<Si5351Bus>,
     BUFFER_SIZE,
     Rp2040Links,
     4,
@@ -136,17 +146,101 @@ pub struct MinsyncLinkPins {
         Pin<Gpio29, <Gpio29 as DefaultTypeState>::Function, <Gpio29 as DefaultTypeState>::PullType>,
 }
 
+/// The state machines and FIFOs of the links, in the order of the link mask.
+pub struct MinsyncLinks {
+    pub north: LinkStateMachines<SM0, SM0>,
+    pub east: LinkStateMachines<SM1, SM1>,
+    pub south: LinkStateMachines<SM2, SM2>,
+    pub west: LinkStateMachines<SM3, SM3>,
+}
+
 pub struct MinsyncV02 {}
 impl MinsyncV02 {
+    /// Sets up the links at the default bit rate, or negotiates the rate of every enabled link with
+    /// its neighbor when `negotiate_link_rates` is set, which the neighbors then have to do too.
+    #[allow(clippy::too_many_arguments)]
     pub fn setup(
         link_mask: [bool; 4],
-        frequency_controller: Si5351Controller<si5351::Si5351Device<minsync::clocks::SiI2C>>,
+        negotiate_link_rates: bool,
+        frequency_controller: Si5351Controller<Si5351Bus>,
         pins: MinsyncLinkPins,
         pio0: PIO0,
         pio1: PIO1,
         resets: &mut RESETS,
         sio_fifo: SioFifo,
     ) -> Control {
+        let MinsyncLinks {
+            north: (_, mut rx0, mut tx_sm0, mut tx0),
+            east: (_, mut rx1, mut tx_sm1, mut tx1),
+            south: (_, mut rx2, mut tx_sm2, mut tx2),
+            west: (_, mut rx3, mut tx_sm3, mut tx3),
+        } = Self::setup_links(pitopi::DEFAULT_LINK_CONFIG, pins, pio0, pio1, resets);
+
+        let default_divisor = pitopi::DEFAULT_LINK_CONFIG.bit_clock_divisor;
+        let mut divisors = [default_divisor; 4];
+
+        if negotiate_link_rates {
+            let mut negotiators = [(); 4].map(|_| RateNegotiator::new());
+
+            while !negotiators
+                .iter()
+                .zip(link_mask)
+                .all(|(negotiator, enabled)| !enabled || negotiator.is_done())
+            {
+                let [n0, n1, n2, n3] = &mut negotiators;
+
+                if link_mask[0] {
+                    poll_negotiation(n0, &mut tx_sm0, &mut rx0, &mut tx0);
+                }
+                if link_mask[1] {
+                    poll_negotiation(n1, &mut tx_sm1, &mut rx1, &mut tx1);
+                }
+                if link_mask[2] {
+                    poll_negotiation(n2, &mut tx_sm2, &mut rx2, &mut tx2);
+                }
+                if link_mask[3] {
+                    poll_negotiation(n3, &mut tx_sm3, &mut rx3, &mut tx3);
+                }
+            }
+
+            for (link, negotiator) in negotiators.iter().enumerate() {
+                if link_mask[link] {
+                    divisors[link] = negotiator.divisor();
+                }
+            }
+
+            // Probes of the neighbor that arrived after ours were done are no messages.
+            while rx0.read().is_some() {}
+            while rx1.read().is_some() {}
+            while rx2.read().is_some() {}
+            while rx3.read().is_some() {}
+        }
+
+        let tide_fifos = [
+            BittideFifo::new(),
+            BittideFifo::new(),
+            BittideFifo::new(),
+            BittideFifo::new(),
+        ];
+
+        Control::new(
+            frequency_controller,
+            Rp2040Links::new(rx0, rx1, rx2, rx3, tx0, tx1, tx2, tx3)
+                .with_bit_clock_divisors(divisors),
+            link_mask,
+            chips::rp2040::SioFifo(sio_fifo),
+            tide_fifos,
+        )
+    }
+
+    /// Sets up the four links with the same configuration, without anything on top of them.
+    pub fn setup_links(
+        link_config: LinkConfig,
+        pins: MinsyncLinkPins,
+        pio0: PIO0,
+        pio1: PIO1,
+        resets: &mut RESETS,
+    ) -> MinsyncLinks {
         let (rx_pio, rx_sm0, rx_sm1, rx_sm2, rx_sm3) = pio0.split(resets);
         let (tx_pio, tx_sm0, tx_sm1, tx_sm2, tx_sm3) = pio1.split(resets);
 
@@ -186,9 +280,9 @@ impl MinsyncV02 {
 
         pitopi.install_programs();
 
-        let (_, rx0, _, tx0) = pitopi
+        let link0 = pitopi
             .setup_link(
-                pitopi::DEFAULT_LINK_CONFIG,
+                link_config,
                 rx_sm0,
                 rx0_data,
                 rx0_clk,
@@ -200,9 +294,9 @@ impl MinsyncV02 {
             )
             .unwrap();
 
-        let (_, rx1, _, tx1) = pitopi
+        let link1 = pitopi
             .setup_link(
-                pitopi::DEFAULT_LINK_CONFIG,
+                link_config,
                 rx_sm1,
                 rx1_data,
                 rx1_clk,
@@ -214,14 +308,9 @@ impl MinsyncV02 {
             )
             .unwrap();
 
-        let south_link_config = LinkConfig {
-            rx_program: pitopi::RxProgram::P023,
-            tx_program: pitopi::TxProgram::SidesetWC,
-        };
-
-        let (_, rx2, _, tx2) = pitopi
+        let link2 = pitopi
             .setup_link(
-                south_link_config,
+                link_config,
                 rx_sm2,
                 rx2_data,
                 rx2_clk,
@@ -233,9 +322,9 @@ impl MinsyncV02 {
             )
             .unwrap();
 
-        let (_, rx3, _, tx3) = pitopi
+        let link3 = pitopi
             .setup_link(
-                pitopi::DEFAULT_LINK_CONFIG,
+                link_config,
                 rx_sm3,
                 rx3_data,
                 rx3_clk,
@@ -247,19 +336,11 @@ impl MinsyncV02 {
             )
             .unwrap();
 
-        let tide_fifos = [
-            BittideFifo::new(),
-            BittideFifo::new(),
-            BittideFifo::new(),
-            BittideFifo::new(),
-        ];
-
-        Control::new(
-            frequency_controller,
-            Rp2040Links::new(rx0, rx1, rx2, rx3, tx0, tx1, tx2, tx3),
-            link_mask,
-            chips::rp2040::SioFifo(sio_fifo),
-            tide_fifos,
-        )
+        MinsyncLinks {
+            north: link0,
+            east: link1,
+            south: link2,
+            west: link3,
+        }
     }
 }
@@ -1,7 +1,17 @@
 use bittide::bittide::BittideChannelControl;
 use controllers::fbdiv::FbdivController;
+use rp_pico::pac::PLL_SYS;
 
-use crate::chips::rp2040::Rp2040Links;
+use crate::chips::rp2040::{Rp2040DualLinks, Rp2040Links};
 
 pub type Control =
     BittideChannelControl<FbdivController, 256, Rp2040Links, 4, crate::chips::rp2040::SioFifo>;
+
+/// Control for picos with only two links, e.g. in a line or triangle topology.
+pub type DualLinkControl = BittideChannelControl<
+    FbdivController<PLL_SYS, 2>,
+    256,
+    Rp2040DualLinks,
+    2,
+    crate::chips::rp2040::SioFifo,
+>;
@@ -0,0 +1,152 @@
+//! Measures the system clock with the frequency counter of the RP2040 and keeps the calibration table
+//! of the frequency actuator in the last sector of flash, which the memory.x of an installation that
+//! calibrates must leave out of its FLASH region.
+
+use controllers::calibration::{CalibrationTable, FrequencyCounter, CALIBRATION_BYTES};
+use fixed::types::U32F32;
+use rp_pico::{hal::rom_data, pac};
+
+const XIP_BASE: u32 = 0x1000_0000;
+const FLASH_SIZE: u32 = 2048 * 1024;
+const FLASH_SECTOR_SIZE: u32 = 4096;
+const FLASH_PAGE_SIZE: usize = 256;
+/// The ROM erases in 64K blocks where it can and in sectors otherwise.
+const FLASH_BLOCK_SIZE: u32 = 1 << 16;
+const FLASH_BLOCK_ERASE_CMD: u8 = 0xd8;
+
+/// Offset of the calibration sector from the start of flash.
+pub const CALIBRATION_FLASH_OFFSET: u32 = FLASH_SIZE - FLASH_SECTOR_SIZE;
+
+/// The table stored in flash is padded to whole pages, as the flash is programmed per page.
+const CALIBRATION_PAGES: usize = CALIBRATION_BYTES.div_ceil(FLASH_PAGE_SIZE);
+
+/// Counts the system clock against the reference clock. The reference must not run off the clock that
+/// is being calibrated, on the minsync board it runs from the ring oscillator. Its frequency is only
+/// known roughly, which scales all measurements by the same factor and so does not change the offsets
+/// in a calibration table.
+pub struct Rp2040FrequencyCounter {
+    reference_khz: u32,
+}
+
+impl Rp2040FrequencyCounter {
+    pub fn new(reference_khz: u32) -> Self {
+        Self { reference_khz }
+    }
+}
+
+impl FrequencyCounter for Rp2040FrequencyCounter {
+    /// Counts for about 32ms, with a resolution of 1/32kHz.
+    fn measure_hz(&mut self) -> Option<U32F32> {
+        // The HAL's clocks manager never touches the frequency counter registers.
+        let clocks = unsafe { &*pac::CLOCKS::ptr() };
+
+        while clocks.fc0_status().read().running().bit_is_set() {}
+
+        clocks
+            .fc0_ref_khz()
+            .write(|w| unsafe { w.fc0_ref_khz().bits(self.reference_khz) });
+        clocks
+            .fc0_interval()
+            .write(|w| unsafe { w.fc0_interval().bits(15) });
+        clocks
+            .fc0_min_khz()
+            .write(|w| unsafe { w.fc0_min_khz().bits(0) });
+        clocks
+            .fc0_max_khz()
+            .write(|w| unsafe { w.fc0_max_khz().bits(0x1ff_ffff) });
+        clocks.fc0_src().write(|w| w.fc0_src().clk_sys());
+
+        while clocks.fc0_status().read().done().bit_is_clear() {}
+
+        let status = clocks.fc0_status().read();
+        if status.fail().bit_is_set() || status.died().bit_is_set() {
+            return None;
+        }
+
+        let result = clocks.fc0_result().read();
+        let khz = (result.khz().bits() as u64) << 5 | result.frac().bits() as u64;
+
+        // 1/32kHz is 125/4Hz.
+        Some(U32F32::from_bits((khz * 125) << 30))
+    }
+
+    fn interval_us(&self) -> u32 {
+        1 << 15
+    }
+}
+
+/// The table in the calibration sector, `None` if the sector holds no valid table.
+pub fn load_calibration() -> Option<CalibrationTable> {
+    let bytes = unsafe {
+        core::slice::from_raw_parts(
+            (XIP_BASE + CALIBRATION_FLASH_OFFSET) as *const u8,
+            CALIBRATION_BYTES,
+        )
+    };
+
+    CalibrationTable::from_bytes(bytes)
+}
+
+/// Erases the calibration sector and writes `table` to it. Runs with interrupts disabled for tens of
+/// milliseconds, and the other core must not run from flash in the meantime.
+pub fn store_calibration(table: &CalibrationTable) {
+    let mut pages = [0xff; CALIBRATION_PAGES * FLASH_PAGE_SIZE];
+    pages[..CALIBRATION_BYTES].copy_from_slice(&table.to_bytes());
+
+    // Execute in place is off while the flash is written, so everything that is called from then
+    // on must be in RAM: the ROM functions are looked up beforehand, and the second stage bootloader
+    // that puts the flash back into its fast read mode is copied.
+    let mut boot2 = [0u32; 64];
+    unsafe {
+        core::ptr::copy_nonoverlapping(XIP_BASE as *const u32, boot2.as_mut_ptr(), boot2.len());
+    }
+
+    let rom = RomFlash {
+        connect_internal_flash: rom_data::connect_internal_flash::ptr(),
+        flash_exit_xip: rom_data::flash_exit_xip::ptr(),
+        flash_range_erase: rom_data::flash_range_erase::ptr(),
+        flash_range_program: rom_data::flash_range_program::ptr(),
+        flash_flush_cache: rom_data::flash_flush_cache::ptr(),
+        // Thumb code, so the lowest bit of the address is set.
+        enter_xip: boot2.as_ptr() as usize + 1,
+    };
+
+    cortex_m::interrupt::free(|_| unsafe {
+        write_flash(&rom, CALIBRATION_FLASH_OFFSET, &pages);
+    });
+}
+
+/// Addresses of the functions `write_flash` calls.
+struct RomFlash {
+    connect_internal_flash: usize,
+    flash_exit_xip: usize,
+    flash_range_erase: usize,
+    flash_range_program: usize,
+    flash_flush_cache: usize,
+    enter_xip: usize,
+}
+
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn write_flash(rom: &RomFlash, offset: u32, data: &[u8]) {
+    let connect_internal_flash: extern "C" fn() = core::mem::transmute(rom.connect_internal_flash);
+    let flash_exit_xip: extern "C" fn() = core::mem::transmute(rom.flash_exit_xip);
+    let flash_range_erase: extern "C" fn(u32, usize, u32, u8) =
+        core::mem::transmute(rom.flash_range_erase);
+    let flash_range_program: extern "C" fn(u32, *const u8, usize) =
+        core::mem::transmute(rom.flash_range_program);
+    let flash_flush_cache: extern "C" fn() = core::mem::transmute(rom.flash_flush_cache);
+    let enter_xip: extern "C" fn() = core::mem::transmute(rom.enter_xip);
+
+    connect_internal_flash();
+    flash_exit_xip();
+    flash_range_erase(
+        offset,
+        FLASH_SECTOR_SIZE as usize,
+        FLASH_BLOCK_SIZE,
+        FLASH_BLOCK_ERASE_CMD,
+    );
+    flash_range_program(offset, data.as_ptr(), data.len());
+    flash_flush_cache();
+    enter_xip();
+}
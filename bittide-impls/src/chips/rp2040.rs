@@ -1,15 +1,38 @@
-use bittide::bittide::{BittideMessage, Fifo, Links};
+use bittide::bittide::{BittideMessage, Fifo, Links, MAX_WORDS_PER_READ};
 use cortex_m::peripheral::syst::SystClkSource;
 use heapless::Vec;
+use pitopi::bert::{poll_bert, BertResult, BertTest, Prbs};
+use pitopi::status::{take_rx_errors, RxErrors};
 // TODO: should not really import from rp_pico but from the rp2040 crates
 use rp_pico::{
-    hal::pio::{Rx, Tx, SM0, SM1, SM2, SM3},
+    hal::pio::{Rx, Tx, ValidStateMachine, SM0, SM1, SM2, SM3},
     pac::{PIO0, PIO1, SYST},
 };
 
+/// Receive errors of a link since it was set up, counted once per read however often they happened in
+/// between, see [`pitopi::status`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RxErrorCounters {
+    /// Partial words the receiver dropped before it realigned.
+    pub misalignments: u32,
+    /// Words the receiver dropped on a full RX FIFO.
+    pub overflows: u32,
+}
+
+impl RxErrorCounters {
+    fn count(&mut self, errors: RxErrors) {
+        self.misalignments += errors.misaligned as u32;
+        self.overflows += errors.overflowed as u32;
+    }
+}
+
+/// Default amount of consecutive reads without any message after which a link is considered inactive.
+pub const NO_MESSAGE_LIMIT: usize = 3;
+
 pub struct Rp2040Links {
     rxs: Rp2040Rxs,
     txs: Rp2040Txs,
+    bit_clock_divisors: [u16; 4],
 }
 
 impl Rp2040Links {
@@ -27,8 +50,50 @@ impl Rp2040Links {
         Self {
             rxs: Rp2040Rxs::new(rx0, rx1, rx2, rx3),
             txs: Rp2040Txs::new(tx0, tx1, tx2, tx3),
+            bit_clock_divisors: [pitopi::DEFAULT_LINK_CONFIG.bit_clock_divisor; 4],
         }
     }
+
+    /// Reads without any message after which a link is considered inactive.
+    pub fn set_no_message_limit(&mut self, limit: usize) {
+        self.rxs.no_message_limit = limit;
+    }
+
+    /// Records the TX clock divisors the links were set up with or negotiated.
+    pub fn with_bit_clock_divisors(mut self, divisors: [u16; 4]) -> Self {
+        self.bit_clock_divisors = divisors;
+        self
+    }
+
+    /// The TX clock divisor of every link, see [`pitopi::rate`].
+    pub fn bit_clock_divisors(&self) -> [u16; 4] {
+        self.bit_clock_divisors
+    }
+
+    pub fn rx_errors(&self) -> [RxErrorCounters; 4] {
+        self.rxs.rx_errors
+    }
+
+    /// Sends `words[i]` words of `prbs` over link `i` instead of bittide messages, and measures the
+    /// bit error rate of what arrives, see [`pitopi::bert`]. Blocks until every link is done, so run
+    /// it before the control interrupt is set up.
+    pub fn run_bert(&mut self, prbs: Prbs, words: [u32; 4]) -> [BertResult; 4] {
+        let mut tests = words.map(|words| BertTest::new(prbs, words));
+        let [north, east, south, west] = &mut tests;
+        let (rxs, txs) = (&mut self.rxs, &mut self.txs);
+
+        while ![
+            poll_bert(north, &mut rxs.rx0, &mut txs.tx0),
+            poll_bert(east, &mut rxs.rx1, &mut txs.tx1),
+            poll_bert(south, &mut rxs.rx2, &mut txs.tx2),
+            poll_bert(west, &mut rxs.rx3, &mut txs.tx3),
+        ]
+        .iter()
+        .all(|&done| done)
+        {}
+
+        tests.map(|test| test.result())
+    }
 }
 
 impl Links<4> for Rp2040Links {
@@ -36,12 +101,80 @@ impl Links<4> for Rp2040Links {
         self.txs.write(messages);
     }
 
-    fn read(&mut self) -> [Vec<BittideMessage, 4>; 4] {
+    fn read(&mut self) -> [Vec<BittideMessage, MAX_WORDS_PER_READ>; 4] {
         self.rxs.read()
     }
 
     fn active_fifos(&self) -> [bool; 4] {
-        self.rxs.active_fifos()
+        active_fifos(&self.rxs.no_msg_counters, self.rxs.no_message_limit)
+    }
+}
+
+/// Two links on the first two state machines of both PIOs, for a line topology or a node with
+/// only two neighbors. Leaves the other two state machines and their pins free.
+pub struct Rp2040DualLinks {
+    rx0: Rx<(PIO0, SM0)>,
+    rx1: Rx<(PIO0, SM1)>,
+    tx0: Tx<(PIO1, SM0)>,
+    tx1: Tx<(PIO1, SM1)>,
+    no_msg_counters: [usize; 2],
+    no_message_limit: usize,
+    rx_errors: [RxErrorCounters; 2],
+}
+
+impl Rp2040DualLinks {
+    pub fn new(
+        rx0: Rx<(PIO0, SM0)>,
+        rx1: Rx<(PIO0, SM1)>,
+        tx0: Tx<(PIO1, SM0)>,
+        tx1: Tx<(PIO1, SM1)>,
+    ) -> Self {
+        Self {
+            rx0,
+            rx1,
+            tx0,
+            tx1,
+            no_msg_counters: [usize::MAX; 2],
+            no_message_limit: NO_MESSAGE_LIMIT,
+            rx_errors: [RxErrorCounters::default(); 2],
+        }
+    }
+
+    /// Reads without any message after which a link is considered inactive.
+    pub fn set_no_message_limit(&mut self, limit: usize) {
+        self.no_message_limit = limit;
+    }
+
+    pub fn rx_errors(&self) -> [RxErrorCounters; 2] {
+        self.rx_errors
+    }
+}
+
+impl Links<2> for Rp2040DualLinks {
+    fn write(&mut self, messages: [BittideMessage; 2]) {
+        self.tx0.write(messages[0].serialize());
+        self.tx1.write(messages[1].serialize());
+    }
+
+    fn read(&mut self) -> [Vec<BittideMessage, MAX_WORDS_PER_READ>; 2] {
+        [
+            read_link(
+                &mut self.rx0,
+                0,
+                &mut self.no_msg_counters[0],
+                &mut self.rx_errors[0],
+            ),
+            read_link(
+                &mut self.rx1,
+                1,
+                &mut self.no_msg_counters[1],
+                &mut self.rx_errors[1],
+            ),
+        ]
+    }
+
+    fn active_fifos(&self) -> [bool; 2] {
+        active_fifos(&self.no_msg_counters, self.no_message_limit)
     }
 }
 
@@ -76,11 +209,11 @@ pub struct Rp2040Rxs {
     rx2: Rx<(PIO0, SM2)>,
     rx3: Rx<(PIO0, SM3)>,
     no_msg_counters: [usize; 4],
+    no_message_limit: usize,
+    rx_errors: [RxErrorCounters; 4],
 }
 
 impl Rp2040Rxs {
-    const NO_MESSAGE_LIMIT: usize = 3;
-
     pub fn new(
         rx0: Rx<(PIO0, SM0)>,
         rx1: Rx<(PIO0, SM1)>,
@@ -92,62 +225,103 @@ impl Rp2040Rxs {
             rx1,
             rx2,
             rx3,
-            no_msg_counters: [Self::NO_MESSAGE_LIMIT; 4],
+            no_msg_counters: [usize::MAX; 4],
+            no_message_limit: NO_MESSAGE_LIMIT,
+            rx_errors: [RxErrorCounters::default(); 4],
         }
     }
 
-    /// Read at most 4 values from each RX fifo.
-    /// The FIFOs hold 4 values, and if a neighbor is driving them faster than this node is running,
-    /// it's possible for there to be more than one value present. So read exactly four times every
-    /// time the control algo runs to keep up with clocks up to 4x this node's frequency.
-    /// Also adjusts the message such that the neighbor field shows what neighbor it came from.
-    /// TODO: that adjustment has to be done on every impl of rxs..?
-    fn read(&mut self) -> [Vec<BittideMessage, 4>; 4] {
-        macro_rules! read {
-            ($rx:ident, $fifo_id:expr) => {{
-                let messages = (0..3)
-                    .filter_map(|_| {
-                        self.$rx.read().map(|w| {
-                            let mut message = BittideMessage::deserialize(w);
-
-                            if let BittideMessage::CommMessage { neighbor: _, data } = message {
-                                message = BittideMessage::CommMessage {
-                                    neighbor: $fifo_id,
-                                    data,
-                                }
-                            }
-
-                            message
-                        })
-                    })
-                    .collect::<Vec<_, 4>>();
-
-                if messages.is_empty() {
-                    self.no_msg_counters[$fifo_id] += 1;
-                } else {
-                    self.no_msg_counters[$fifo_id] = 0;
-                }
+    fn read(&mut self) -> [Vec<BittideMessage, MAX_WORDS_PER_READ>; 4] {
+        [
+            read_link(
+                &mut self.rx0,
+                0,
+                &mut self.no_msg_counters[0],
+                &mut self.rx_errors[0],
+            ),
+            read_link(
+                &mut self.rx1,
+                1,
+                &mut self.no_msg_counters[1],
+                &mut self.rx_errors[1],
+            ),
+            read_link(
+                &mut self.rx2,
+                2,
+                &mut self.no_msg_counters[2],
+                &mut self.rx_errors[2],
+            ),
+            read_link(
+                &mut self.rx3,
+                3,
+                &mut self.no_msg_counters[3],
+                &mut self.rx_errors[3],
+            ),
+        ]
+    }
+}
 
-                messages
-            }};
-        }
+/// Read at most 4 values from an RX fifo.
+/// The FIFOs hold 4 values, and if a neighbor is driving them faster than this node is running,
+/// it's possible for there to be more than one value present. So read exactly four times every
+/// time the control algo runs to keep up with clocks up to 4x this node's frequency.
+/// Also adjusts the message such that the neighbor field shows what neighbor it came from, and counts
+/// the errors the receiver flagged.
+fn read_link<SM: ValidStateMachine<PIO = PIO0>>(
+    rx: &mut Rx<SM>,
+    fifo_id: u8,
+    no_msg_counter: &mut usize,
+    errors: &mut RxErrorCounters,
+) -> Vec<BittideMessage, MAX_WORDS_PER_READ> {
+    errors.count(take_rx_errors(rx));
 
-        [read!(rx0, 0), read!(rx1, 1), read!(rx2, 2), read!(rx3, 3)]
-    }
+    let messages = (0..MAX_WORDS_PER_READ)
+        .filter_map(|_| {
+            rx.read().map(|w| {
+                let mut message = BittideMessage::deserialize(w);
 
-    /// Returns the amount of RX FIFO's that have seen messages on the last few runs.
-    /// Necessary to determine setpoints automatically in networks where not every node has the same amount of neighbors.
-    fn active_fifos(&self) -> [bool; 4] {
-        let mut actives = [false; 4];
+                match message {
+                    BittideMessage::CommMessage { neighbor: _, data } => {
+                        message = BittideMessage::CommMessage {
+                            neighbor: fifo_id,
+                            data,
+                        }
+                    }
+                    BittideMessage::ManagementMessage {
+                        neighbor: _,
+                        opcode,
+                        payload,
+                    } => {
+                        message = BittideMessage::ManagementMessage {
+                            neighbor: fifo_id,
+                            opcode,
+                            payload,
+                        }
+                    }
+                    BittideMessage::SyncMessage { .. } => (),
+                }
 
-        self.no_msg_counters
-            .iter()
-            .map(|&counter| counter < Self::NO_MESSAGE_LIMIT)
-            .enumerate()
-            .for_each(|(i, b)| actives[i] = b);
+                message
+            })
+        })
+        .collect::<Vec<_, MAX_WORDS_PER_READ>>();
 
-        actives
+    if messages.is_empty() {
+        *no_msg_counter = no_msg_counter.saturating_add(1);
+    } else {
+        *no_msg_counter = 0;
     }
+
+    messages
+}
+
+/// Returns the amount of RX FIFO's that have seen messages on the last few runs.
+/// Necessary to determine setpoints automatically in networks where not every node has the same amount of neighbors.
+fn active_fifos<const DEGREE: usize>(
+    no_msg_counters: &[usize; DEGREE],
+    no_message_limit: usize,
+) -> [bool; DEGREE] {
+    no_msg_counters.map(|counter| counter < no_message_limit)
 }
 
 pub struct SioFifo(pub rp_pico::hal::sio::SioFifo);
@@ -165,23 +339,20 @@ impl Fifo for SioFifo {
 /// Requires a setup like this in the main to define what happens on the systick connection:
 ///
 /// ```rust
-/// static GLOBAL_CONTROL: Mutex<RefCell<Option<bittide_impls::boards::pico1_and_si5351::Control>>> = Mutex::new(RefCell::new(None));
+/// static CONTROL: InterruptOwned<bittide_impls::boards::pico1_and_si5351::Control> = InterruptOwned::new();
 ///
 /// #[exception]
 /// fn SysTick() {
-///     static mut CONTROL: Option<bittide_impls::boards::pico1_and_si5351::Control> = None;
-///
-///     if CONTROL.is_none() {
-///         critical_section::with(|cs| {
-///             let _ = CONTROL.insert(GLOBAL_CONTROL.borrow(cs).take().unwrap());
-///         });
-///     }
+///     static mut OWNED: Option<bittide_impls::boards::pico1_and_si5351::Control> = None;
 ///
-///     if let Some(control) = CONTROL {
-///         control.interrupt();
+///     // Safety: this handler is the only caller.
+///     if let Some(owner) = unsafe { CONTROL.take_into(OWNED) } {
+///         owner.control.interrupt();
 ///     }
 /// }
 /// ```
+///
+/// See [`crate::interrupt::InterruptOwned`].
 pub fn setup_interrupt(clocks_per_sync_word: u32, systick: &mut SYST) {
     systick.set_reload(clocks_per_sync_word - 1);
     systick.clear_current();
@@ -0,0 +1,152 @@
+//! Hands the control object from the main thread to the interrupt that runs it, without taking a
+//! critical section on every tick, and shares its debug information back to the main thread.
+
+use core::{
+    cell::{RefCell, UnsafeCell},
+    mem::MaybeUninit,
+    sync::atomic::{compiler_fence, AtomicU32, Ordering},
+};
+
+use critical_section::Mutex;
+
+/// Holds a value until the interrupt that owns it moves it into its own `static mut` on first use.
+/// After that the interrupt uses it without any locking. Debug information of type `D` is published
+/// by the owner and can be read from any other context.
+///
+/// ```ignore
+/// static CONTROL: InterruptOwned<Control, DebugInfo> = InterruptOwned::new();
+///
+/// #[exception]
+/// fn SysTick() {
+///     static mut OWNED: Option<Control> = None;
+///
+///     // Safety: this handler is the only caller.
+///     if let Some(owner) = unsafe { CONTROL.take_into(OWNED) } {
+///         owner.control.interrupt().ok();
+///         let debug = *owner.control.debug();
+///         owner.publish(debug);
+///     }
+/// }
+/// ```
+pub struct InterruptOwned<T, D = ()> {
+    handoff: Mutex<RefCell<Option<T>>>,
+    snapshot: Seqlock<D>,
+}
+
+impl<T, D: Copy> InterruptOwned<T, D> {
+    pub const fn new() -> Self {
+        Self {
+            handoff: Mutex::new(RefCell::new(None)),
+            snapshot: Seqlock::new(),
+        }
+    }
+
+    /// Gives the value to the interrupt, call before enabling it.
+    pub fn give(&self, value: T) {
+        critical_section::with(|cs| {
+            self.handoff.borrow(cs).replace(Some(value));
+        });
+    }
+
+    /// Moves the value into `owned` the first time it is available, which costs one critical section.
+    ///
+    /// # Safety
+    /// Must only ever be called from a single context that cannot preempt itself, such as one
+    /// interrupt handler, with `owned` a `static mut` local to that handler. Every `Owner` publishes
+    /// debug information, and there may only be one writer of it.
+    pub unsafe fn take_into<'a>(&'a self, owned: &'a mut Option<T>) -> Option<Owner<'a, T, D>> {
+        if owned.is_none() {
+            *owned = critical_section::with(|cs| self.handoff.borrow(cs).take());
+        }
+
+        owned.as_mut().map(|control| Owner {
+            control,
+            snapshot: &self.snapshot,
+        })
+    }
+
+    /// The last published debug information, or `None` if nothing was published yet.
+    pub fn debug(&self) -> Option<D> {
+        self.snapshot.read()
+    }
+}
+
+impl<T, D: Copy> Default for InterruptOwned<T, D> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Exclusive access to the owned value from within the owning interrupt.
+pub struct Owner<'a, T, D> {
+    pub control: &'a mut T,
+    snapshot: &'a Seqlock<D>,
+}
+
+impl<T, D: Copy> Owner<'_, T, D> {
+    /// Makes a copy of the debug information available to readers. Readers that are interrupted by
+    /// a publish retry their read, so they never see a half written value.
+    pub fn publish(&self, debug: D) {
+        // Safe: an `Owner` only exists within the single context that calls `take_into`.
+        unsafe { self.snapshot.write(debug) }
+    }
+}
+
+/// Single writer sequence lock. The sequence number is odd while a write is in progress,
+/// and zero as long as nothing has been written.
+struct Seqlock<D> {
+    sequence: AtomicU32,
+    value: UnsafeCell<MaybeUninit<D>>,
+}
+
+// Safe because reads are validated against the sequence number and there is only one writer.
+unsafe impl<D: Copy + Send> Sync for Seqlock<D> {}
+
+impl<D: Copy> Seqlock<D> {
+    const fn new() -> Self {
+        Self {
+            sequence: AtomicU32::new(0),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// # Safety
+    /// Must only ever be called from a single context that cannot be preempted by another writer.
+    /// Only load and store are used since the RP2040 has no atomic read-modify-write instructions.
+    unsafe fn write(&self, value: D) {
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        self.sequence
+            .store(sequence.wrapping_add(1), Ordering::Relaxed);
+        compiler_fence(Ordering::Release);
+
+        core::ptr::write_volatile(self.value.get(), MaybeUninit::new(value));
+
+        compiler_fence(Ordering::Release);
+        // Skip zero on wrap around, it marks an unwritten value.
+        self.sequence
+            .store(sequence.wrapping_add(2).max(2), Ordering::Release);
+    }
+
+    fn read(&self) -> Option<D> {
+        loop {
+            let before = self.sequence.load(Ordering::Acquire);
+
+            if before == 0 {
+                return None;
+            }
+
+            if before % 2 == 1 {
+                continue;
+            }
+
+            compiler_fence(Ordering::Acquire);
+            let value = unsafe { core::ptr::read_volatile(self.value.get()) };
+            compiler_fence(Ordering::Acquire);
+
+            if self.sequence.load(Ordering::Acquire) == before {
+                // Safe because the sequence number is nonzero, so a complete value was written.
+                return Some(unsafe { value.assume_init() });
+            }
+        }
+    }
+}
@@ -1,3 +1,5 @@
 #![no_std]
 pub mod boards;
+pub mod calibration;
 pub mod chips;
+pub mod interrupt;
@@ -1,4 +1,4 @@
-use controllers::controller::FrequencyController;
+use controllers::controller::{FaultReason, FrequencyController};
 use heapless::{Deque, Vec};
 
 // TODO: debugging, starts with keeping track of things:
@@ -14,6 +14,17 @@ use heapless::{Deque, Vec};
 //      - topology discovery or definition
 //      - routing of messages
 
+/// Amount of opcodes available to management messages, limited by the width of the opcode field.
+pub const MANAGEMENT_OPCODES: usize = 1 << 6;
+
+/// Amount of management messages that can be waiting to be sent per link.
+const MANAGEMENT_QUEUE_SIZE: usize = 4;
+
+/// Called on core0 when a management message with the registered opcode leaves a tide fifo.
+/// Receives the neighbor the message came from and its payload. If it returns an `(opcode, payload)`
+/// pair, that is queued as a management message back to the same neighbor.
+pub type ManagementHandler = fn(neighbor: u8, payload: u32) -> Option<(u8, u32)>;
+
 /// Generic over the frequency controller F, and the buffer size B.
 pub struct BittideChannelControl<
     F: FrequencyController<B>,
@@ -27,15 +38,83 @@ pub struct BittideChannelControl<
     link_mask: [bool; DEGREE],
     sio_fifo: FIFO,
     tide_fifos: [BittideFifo<B>; DEGREE],
-    debug_info: BittideChannelControlDebugInfo<F::Debug>,
+    management_handlers: [Option<ManagementHandler>; MANAGEMENT_OPCODES],
+    management_queues: [Deque<(u8, u32), MANAGEMENT_QUEUE_SIZE>; DEGREE],
+    tx_sequences: [u16; DEGREE],
+    rx_sequences: [SequenceTracker; DEGREE],
+    debug_info: BittideChannelControlDebugInfo<F::Debug, DEGREE>,
 }
 
-#[derive(Debug, Default)]
-pub struct BittideChannelControlDebugInfo<FD> {
+#[derive(Debug, Clone, Copy)]
+pub struct BittideChannelControlDebugInfo<FD, const DEGREE: usize> {
     pub frequency_controller_debug: FD,
-    pub buffer_levels: [u32; 4],
+    pub buffer_levels: [u32; DEGREE],
     pub rx_sync_message_counter: u32,
     pub rx_comm_message_counter: u32,
+    pub rx_management_message_counter: u32,
+    pub tx_management_message_counter: u32,
+    /// Management messages received for an opcode without a registered handler.
+    pub unhandled_management_message_counter: u32,
+    pub link_errors: [LinkErrorCounters; DEGREE],
+}
+
+impl<FD: Default, const DEGREE: usize> Default for BittideChannelControlDebugInfo<FD, DEGREE> {
+    fn default() -> Self {
+        Self {
+            frequency_controller_debug: FD::default(),
+            buffer_levels: [0; DEGREE],
+            rx_sync_message_counter: 0,
+            rx_comm_message_counter: 0,
+            rx_management_message_counter: 0,
+            tx_management_message_counter: 0,
+            unhandled_management_message_counter: 0,
+            link_errors: [LinkErrorCounters::default(); DEGREE],
+        }
+    }
+}
+
+/// Errors on a single link, detected by comparing the sequence numbers in sync messages
+/// against the amount of words that were received since the previous sync message.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct LinkErrorCounters {
+    /// Words that never arrived, e.g. dropped by a full RX FIFO.
+    pub lost_words: u32,
+    /// Words that arrived twice, e.g. shifted out of the receiver twice.
+    pub duplicated_words: u32,
+    /// Sync messages with a sequence number further back than a single duplicate.
+    pub reordered_words: u32,
+}
+
+/// Keeps track of the sequence number the next word on a link should have.
+#[derive(Debug, Default, Clone, Copy)]
+struct SequenceTracker {
+    expected: Option<u16>,
+}
+
+impl SequenceTracker {
+    /// Every word on a link increments the sequence number, but only sync messages carry it.
+    /// So data words are still covered: a lost or duplicated data word shows up at the next sync message.
+    fn observe(&mut self, message: &BittideMessage, errors: &mut LinkErrorCounters) {
+        match *message {
+            BittideMessage::SyncMessage { sequence } => {
+                if let Some(expected) = self.expected {
+                    match sequence.wrapping_sub(expected) as i16 {
+                        0 => (),
+                        -1 => errors.duplicated_words += 1,
+                        gap @ 1.. => errors.lost_words += gap as u32,
+                        _ => errors.reordered_words += 1,
+                    }
+                }
+
+                self.expected = Some(sequence.wrapping_add(1));
+            }
+            _ => {
+                if let Some(expected) = self.expected.as_mut() {
+                    *expected = expected.wrapping_add(1);
+                }
+            }
+        }
+    }
 }
 
 impl<F, const B: usize, L, const DEGREE: usize, FIFO> BittideChannelControl<F, B, L, DEGREE, FIFO>
@@ -45,12 +124,20 @@ where
     FIFO: Fifo,
 {
     pub fn new(
-        frequency_controller: F,
+        mut frequency_controller: F,
         links: L,
         link_mask: [bool; DEGREE],
         sio_fifo: FIFO,
         tide_fifos: [BittideFifo<B>; DEGREE],
     ) -> Self {
+        const {
+            assert!(
+                DEGREE <= MAX_DEGREE,
+                "more links than the neighbor field can address"
+            )
+        };
+
+        frequency_controller.set_link_mask(&link_mask);
         let frequency_controller_debug_info = frequency_controller.debug();
 
         Self {
@@ -59,15 +146,59 @@ where
             link_mask,
             sio_fifo,
             tide_fifos,
+            management_handlers: [None; MANAGEMENT_OPCODES],
+            management_queues: core::array::from_fn(|_| Deque::new()),
+            tx_sequences: [0; DEGREE],
+            rx_sequences: [SequenceTracker::default(); DEGREE],
             debug_info: BittideChannelControlDebugInfo {
                 frequency_controller_debug: frequency_controller_debug_info,
-                buffer_levels: [0; 4],
+                buffer_levels: [0; DEGREE],
                 rx_comm_message_counter: 0,
                 rx_sync_message_counter: 0,
+                rx_management_message_counter: 0,
+                tx_management_message_counter: 0,
+                unhandled_management_message_counter: 0,
+                link_errors: [LinkErrorCounters::default(); DEGREE],
             },
         }
     }
 
+    /// Registers the handler that is called for every received management message with this opcode.
+    /// Replaces any handler that was registered for the opcode before.
+    pub fn register_management_handler(
+        &mut self,
+        opcode: u8,
+        handler: ManagementHandler,
+    ) -> Result<(), BittideChannelControlError> {
+        let slot = self
+            .management_handlers
+            .get_mut(opcode as usize)
+            .ok_or(BittideChannelControlError::InvalidManagementOpcode)?;
+
+        *slot = Some(handler);
+
+        Ok(())
+    }
+
+    /// Queues a management message for a neighbor. It is sent in place of a sync message on the next
+    /// interrupt in which user code does not send data to that neighbor.
+    pub fn send_management(
+        &mut self,
+        neighbor: usize,
+        opcode: u8,
+        payload: u32,
+    ) -> Result<(), BittideChannelControlError> {
+        if opcode as usize >= MANAGEMENT_OPCODES {
+            return Err(BittideChannelControlError::InvalidManagementOpcode);
+        }
+
+        self.management_queues
+            .get_mut(neighbor)
+            .ok_or(BittideChannelControlError::InvalidNeigbor)?
+            .push_back((opcode, payload))
+            .map_err(|_| BittideChannelControlError::ManagementQueueFull)
+    }
+
     /// All the logic to execute on a scheduled basis.
     /// This function must be called _exactly_ every `CLOCKS_PER_SYNC_WORD` system clock cycles.
     /// All clocks should be set up such that the execution of this function takes fewer clocks than that
@@ -82,45 +213,83 @@ where
         let user_word = self.sio_fifo.read();
 
         // Send words on channel
-        let mut messages = [BittideMessage::SyncMessage; DEGREE];
+        let mut messages = [BittideMessage::SyncMessage { sequence: 0 }; DEGREE];
 
         if let Some(message) = user_word.map(BittideMessage::deserialize) {
             match message {
-                BittideMessage::SyncMessage => {
+                BittideMessage::SyncMessage { .. } => {
                     return Err(BittideChannelControlError::SyncMessageFromUserCode)
                 }
                 BittideMessage::CommMessage { neighbor, data: _ } => {
                     let neighbor: usize = neighbor as usize;
-                    if (neighbor) < 4 {
+                    if (neighbor) < DEGREE {
                         messages[neighbor] = message;
                     } else {
                         return Err(BittideChannelControlError::InvalidNeigbor);
                     }
                 }
+                BittideMessage::ManagementMessage { .. } => {
+                    return Err(BittideChannelControlError::ManagementMessageFromUserCode)
+                }
             }
         }
 
+        // Management messages only take the place of sync messages, user data always goes first.
+        for (neighbor, (message, queue)) in messages
+            .iter_mut()
+            .zip(self.management_queues.iter_mut())
+            .enumerate()
+        {
+            if !matches!(message, BittideMessage::SyncMessage { .. }) {
+                continue;
+            }
+
+            if let Some((opcode, payload)) = queue.pop_front() {
+                *message = BittideMessage::ManagementMessage {
+                    neighbor: neighbor as u8,
+                    opcode,
+                    payload,
+                };
+                self.debug_info.tx_management_message_counter += 1;
+            }
+        }
+
+        for (message, sequence) in messages.iter_mut().zip(self.tx_sequences.iter_mut()) {
+            if let BittideMessage::SyncMessage { sequence: stamp } = message {
+                *stamp = *sequence;
+            }
+            *sequence = sequence.wrapping_add(1);
+        }
+
         self.links.write(messages);
 
         // Read rx fifos and put on tide fifos
         let messages = self.links.read();
 
-        for (&enabled, (fifo, message)) in self
+        for (id, (&enabled, (fifo, message))) in self
             .link_mask
             .iter()
             .zip(self.tide_fifos.iter_mut().zip(messages.into_iter()))
+            .enumerate()
         {
             if !enabled {
                 continue;
             }
 
             for message in message {
+                self.rx_sequences[id].observe(&message, &mut self.debug_info.link_errors[id]);
+
                 match message {
-                    BittideMessage::SyncMessage => self.debug_info.rx_sync_message_counter += 1,
+                    BittideMessage::SyncMessage { .. } => {
+                        self.debug_info.rx_sync_message_counter += 1
+                    }
                     BittideMessage::CommMessage {
                         neighbor: _,
                         data: _,
                     } => self.debug_info.rx_comm_message_counter += 1,
+                    BittideMessage::ManagementMessage { .. } => {
+                        self.debug_info.rx_management_message_counter += 1
+                    }
                 }
                 fifo.fifo
                     .push_back(message)
@@ -129,13 +298,22 @@ where
             }
         }
 
+        let mut management_messages: Vec<(usize, u8, u32), DEGREE> = Vec::new();
+
+        // Links that stopped receiving keep their buffer level until they come back, so the level
+        // still matches the neighbor's when they do. A link counts as inactive until its first word
+        // arrives, so a neighbor that starts later finds the buffer at its midpoint rather than this
+        // node failing on an empty buffer in the meantime.
+        let active = self.links.active_fifos();
+
         // Read one message from front of tide fifos and if necessary, put on SIO fifo.
-        for (&enabled, (id, fifo)) in self
+        for ((&enabled, &active), (id, fifo)) in self
             .link_mask
             .iter()
+            .zip(active.iter())
             .zip(self.tide_fifos.iter_mut().enumerate())
         {
-            if !enabled {
+            if !enabled || !active {
                 continue;
             }
 
@@ -144,7 +322,7 @@ where
             if let Some(message) = message {
                 match message {
                     // Do nothing with sync messages, they're only in the fifo for sync
-                    BittideMessage::SyncMessage => (),
+                    BittideMessage::SyncMessage { .. } => (),
                     // Send comm message to core1
                     BittideMessage::CommMessage {
                         neighbor: _,
@@ -152,48 +330,85 @@ where
                     } => {
                         self.sio_fifo.write(message.serialize());
                     }
+                    // Management messages are handled on this core and never reach user code
+                    BittideMessage::ManagementMessage {
+                        neighbor: _,
+                        opcode,
+                        payload,
+                    } => {
+                        // At most one message per link is popped, so this always fits.
+                        management_messages.push((id, opcode, payload)).ok();
+                    }
                 }
             } else {
                 return Err(BittideChannelControlError::BittideFifoEmpty);
             }
         }
 
+        for (neighbor, opcode, payload) in management_messages {
+            self.handle_management(neighbor, opcode, payload);
+        }
+
         let buffer_levels: Vec<usize, DEGREE> =
             self.tide_fifos.iter().map(|f| f.buffer_levels()).collect();
 
         self.debug_info
             .buffer_levels
             .iter_mut()
-            .enumerate()
-            .for_each(|(index, level)| {
-                *level = self
-                    .tide_fifos
-                    .get(index)
-                    .map(|fifo| fifo.buffer_levels())
-                    .unwrap_or_default() as u32
-            });
-
-        let current_degree = self.links.active_fifos().iter().filter(|&&b| b).count();
+            .zip(buffer_levels.iter())
+            .for_each(|(debug_level, &level)| *debug_level = level as u32);
 
-        // self.frequency_controller.set_degree(current_degree);
+        self.frequency_controller.set_active_links(&active);
         self.frequency_controller
             .run(&buffer_levels)
-            .map_err(|_| BittideChannelControlError::FrequenceControllerError)?;
+            .map_err(|error| BittideChannelControlError::FrequenceControllerError(error.into()))?;
 
         Ok(())
     }
 
-    pub fn debug(&mut self) -> &BittideChannelControlDebugInfo<F::Debug> {
+    fn handle_management(&mut self, neighbor: usize, opcode: u8, payload: u32) {
+        let Some(handler) = self
+            .management_handlers
+            .get(opcode as usize)
+            .copied()
+            .flatten()
+        else {
+            self.debug_info.unhandled_management_message_counter += 1;
+            return;
+        };
+
+        if let Some((reply_opcode, reply_payload)) = handler(neighbor as u8, payload) {
+            // A full queue drops the reply, the requesting side has to retry.
+            self.send_management(neighbor, reply_opcode, reply_payload)
+                .ok();
+        }
+    }
+
+    /// For changes between two interrupts, e.g. of parameters.
+    pub fn frequency_controller_mut(&mut self) -> &mut F {
+        &mut self.frequency_controller
+    }
+
+    pub fn links_mut(&mut self) -> &mut L {
+        &mut self.links
+    }
+
+    pub fn debug(&mut self) -> &BittideChannelControlDebugInfo<F::Debug, DEGREE> {
         self.debug_info.frequency_controller_debug = self.frequency_controller.debug();
         &self.debug_info
     }
 }
 
+/// The most words a single link may return from one read. This bounds how much faster than this node a
+/// neighbor can run, and is independent of the degree. Matches the depth of the RP2040 PIO RX FIFOs.
+pub const MAX_WORDS_PER_READ: usize = 4;
+
 /// Encapsulates all hardware resources for all possible bittide links for a device.
 /// Methods should implement a read and write on every link available.
 pub trait Links<const DEGREE: usize> {
     fn write(&mut self, messages: [BittideMessage; DEGREE]);
-    fn read(&mut self) -> [Vec<BittideMessage, 4>; DEGREE];
+    fn read(&mut self) -> [Vec<BittideMessage, MAX_WORDS_PER_READ>; DEGREE];
+    /// Links that received a word recently, which is none of them before the first words arrive.
     fn active_fifos(&self) -> [bool; DEGREE];
 }
 
@@ -212,7 +427,8 @@ impl<const B: usize> BittideFifo<B> {
     pub fn new() -> Self {
         let mut fifo = Deque::new();
         for _ in 0..(B / 2) {
-            fifo.push_back(BittideMessage::SyncMessage).unwrap()
+            fifo.push_back(BittideMessage::SyncMessage { sequence: 0 })
+                .unwrap()
         }
         Self { fifo }
     }
@@ -228,50 +444,113 @@ impl<const B: usize> Default for BittideFifo<B> {
     }
 }
 
-#[derive(Debug, Clone, Copy, defmt::Format)]
+/// Width of the neighbor field in comm and management messages. It is the same for every degree, so
+/// nodes with a different amount of links read each other's words, and wide enough for a hex grid.
+pub const NEIGHBOR_BITS: u32 = 3;
+
+/// The most links a node can have, limited by the width of the neighbor field.
+pub const MAX_DEGREE: usize = 1 << NEIGHBOR_BITS;
+
+/// Width of the user data in a comm message.
+pub const DATA_BITS: u32 = 31 - NEIGHBOR_BITS;
+
+/// Width of the payload in a management message.
+pub const PAYLOAD_BITS: u32 = 24 - NEIGHBOR_BITS;
+
+/// The class of a word is encoded in its lowest bits:
+/// - `...0`: comm message
+/// - `..01`: sync message
+/// - `..11`: management message
+///
+/// The neighbor field is [`NEIGHBOR_BITS`] wide whatever the degree of the node. Receivers overwrite
+/// it with the link a word came in on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
 pub enum BittideMessage {
-    /// Constant message used for sync purposes when no user message is ready
-    SyncMessage,
-    /// Communication message for user code. 1 bit is dedicated to signaling comm, 3 bits for the neigbor, the remaining 28 are for user data.
+    /// Message used for sync purposes when no user message is ready. 2 bits are dedicated to signaling sync,
+    /// the next 16 hold a sequence number that counts every word sent on the link, the remaining bits are zero.
+    SyncMessage { sequence: u16 },
+    /// Communication message for user code. 1 bit is dedicated to signaling comm, then the neighbor,
+    /// the remaining [`DATA_BITS`] are for user data.
     CommMessage { neighbor: u8, data: u32 },
+    /// Message between the control cores of neighboring nodes, never passed to user code.
+    /// 2 bits are dedicated to signaling management, then the neighbor, 6 bits for the opcode,
+    /// the remaining [`PAYLOAD_BITS`] are payload.
+    ManagementMessage {
+        neighbor: u8,
+        opcode: u8,
+        payload: u32,
+    },
 }
 
 impl BittideMessage {
     pub fn serialize(self) -> u32 {
+        let neighbor_mask = (1 << NEIGHBOR_BITS) - 1;
+
         match self {
-            BittideMessage::SyncMessage => 0b0001,
+            BittideMessage::SyncMessage { sequence } => 0b01 | (sequence as u32) << 2,
             BittideMessage::CommMessage { neighbor, data } => {
-                let data = data & 0x0fff_ffff;
-                let neighbor = neighbor & 0b111;
+                let data = data & (u32::MAX >> (32 - DATA_BITS));
+                let neighbor = neighbor as u32 & neighbor_mask;
 
-                (neighbor << 1) as u32 | (data << 4)
+                (neighbor << 1) | (data << (1 + NEIGHBOR_BITS))
+            }
+            BittideMessage::ManagementMessage {
+                neighbor,
+                opcode,
+                payload,
+            } => {
+                let neighbor = neighbor as u32 & neighbor_mask;
+                let opcode = (opcode & 0b11_1111) as u32;
+                let payload = payload & (u32::MAX >> (32 - PAYLOAD_BITS));
+
+                0b11 | (neighbor << 2)
+                    | (opcode << (2 + NEIGHBOR_BITS))
+                    | (payload << (8 + NEIGHBOR_BITS))
             }
         }
     }
 
     pub fn deserialize(raw: u32) -> Self {
-        match raw {
-            0b0001 => BittideMessage::SyncMessage,
-            raw => {
-                let data = raw >> 4 & 0x0fff_ffff;
-                let neighbor = (raw >> 1 & 0b111) as u8;
+        let neighbor_mask = (1 << NEIGHBOR_BITS) - 1;
+
+        match raw & 0b11 {
+            0b01 => BittideMessage::SyncMessage {
+                sequence: (raw >> 2 & 0xffff) as u16,
+            },
+            0b11 => {
+                let neighbor = (raw >> 2 & neighbor_mask) as u8;
+                let opcode = (raw >> (2 + NEIGHBOR_BITS) & 0b11_1111) as u8;
+                let payload = raw >> (8 + NEIGHBOR_BITS);
+                BittideMessage::ManagementMessage {
+                    neighbor,
+                    opcode,
+                    payload,
+                }
+            }
+            _ => {
+                let data = raw >> (1 + NEIGHBOR_BITS);
+                let neighbor = (raw >> 1 & neighbor_mask) as u8;
                 BittideMessage::CommMessage { neighbor, data }
             }
         }
     }
 }
 
-#[derive(Debug, defmt::Format)]
+#[derive(Debug, Clone, Copy, defmt::Format)]
 pub enum BittideChannelControlError {
     DecodeError,
     SyncMessageFromUserCode,
     InvalidNeigbor,
     BittideFifoFull,
-    FrequenceControllerError,
+    FrequenceControllerError(FaultReason),
     BittideFifoEmpty,
+    ManagementMessageFromUserCode,
+    InvalidManagementOpcode,
+    ManagementQueueFull,
 }
 
 impl BittideChannelControlError {
+    /// The kind of error in the low byte, the reason of a frequency controller error in the byte above.
     pub fn encode(result: Result<(), Self>) -> u32 {
         match result {
             Ok(()) => 0,
@@ -280,18 +559,30 @@ impl BittideChannelControlError {
             Err(Self::InvalidNeigbor) => 3,
             Err(Self::BittideFifoFull) => 4,
             Err(Self::BittideFifoEmpty) => 5,
-            Err(Self::FrequenceControllerError) => 6,
+            Err(Self::FrequenceControllerError(reason)) => 6 | (reason.code() as u32) << 8,
+            Err(Self::ManagementMessageFromUserCode) => 7,
+            Err(Self::InvalidManagementOpcode) => 8,
+            Err(Self::ManagementQueueFull) => 9,
         }
     }
 
     pub fn decode(value: u32) -> Result<(), Self> {
+        if value & 0xff == 6 {
+            return match FaultReason::from_code((value >> 8) as u8) {
+                Some(reason) if value >> 16 == 0 => Err(Self::FrequenceControllerError(reason)),
+                _ => Err(Self::DecodeError),
+            };
+        }
+
         match value {
             0 => Ok(()),
             2 => Err(Self::SyncMessageFromUserCode),
             3 => Err(Self::InvalidNeigbor),
             4 => Err(Self::BittideFifoFull),
             5 => Err(Self::BittideFifoEmpty),
-            6 => Err(Self::FrequenceControllerError),
+            7 => Err(Self::ManagementMessageFromUserCode),
+            8 => Err(Self::InvalidManagementOpcode),
+            9 => Err(Self::ManagementQueueFull),
             _ => Err(Self::DecodeError),
         }
     }
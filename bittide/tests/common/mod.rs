@@ -0,0 +1,137 @@
+//! Stand-ins for the hardware around a [`BittideChannelControl`], so a node runs on the host.
+
+#![allow(dead_code)]
+
+use std::{cell::RefCell, collections::VecDeque, rc::Rc};
+
+use bittide::bittide::{
+    BittideChannelControl, BittideFifo, BittideMessage, Fifo, Links, MAX_WORDS_PER_READ,
+};
+use controllers::controller::{FaultReason, FrequencyController};
+use heapless::Vec;
+
+/// Room for a single word besides the one every link starts with, so a received word leaves the tide
+/// fifo on the next interrupt.
+pub const BUFFER_SIZE: usize = 2;
+
+pub type Node<const DEGREE: usize> =
+    BittideChannelControl<IdleController, BUFFER_SIZE, MockLinks<DEGREE>, DEGREE, MockFifo>;
+
+/// A node with all links enabled, and the SIO FIFO to core1 it talks over.
+pub fn node<const DEGREE: usize>() -> (Node<DEGREE>, MockFifo) {
+    node_with_mask([true; DEGREE])
+}
+
+pub fn node_with_mask<const DEGREE: usize>(link_mask: [bool; DEGREE]) -> (Node<DEGREE>, MockFifo) {
+    let fifo = MockFifo::default();
+    let node = BittideChannelControl::new(
+        IdleController,
+        MockLinks::default(),
+        link_mask,
+        fifo.clone(),
+        core::array::from_fn(|_| BittideFifo::new()),
+    );
+
+    (node, fifo)
+}
+
+/// Never moves the frequency.
+pub struct IdleController;
+
+impl<const B: usize> FrequencyController<B> for IdleController {
+    type Error = FaultReason;
+    type Debug = ();
+
+    fn run(&mut self, _buffer_levels: &[usize]) -> Result<(), FaultReason> {
+        Ok(())
+    }
+
+    fn set_degree(&mut self, _new_degree: usize) {}
+
+    fn debug(&self) {}
+}
+
+/// Links that keep what was written to them, and hand out at most one queued word per link per read.
+/// Like the hardware links, words pass in their wire format and get the link they came in on as
+/// neighbor.
+pub struct MockLinks<const DEGREE: usize> {
+    pub sent: std::vec::Vec<[BittideMessage; DEGREE]>,
+    /// What the links report as active, all of them unless a test says otherwise.
+    pub active: [bool; DEGREE],
+    incoming: [VecDeque<u32>; DEGREE],
+}
+
+impl<const DEGREE: usize> Default for MockLinks<DEGREE> {
+    fn default() -> Self {
+        Self {
+            sent: std::vec::Vec::new(),
+            active: [true; DEGREE],
+            incoming: core::array::from_fn(|_| VecDeque::new()),
+        }
+    }
+}
+
+impl<const DEGREE: usize> MockLinks<DEGREE> {
+    /// Queues a word to arrive on `link`.
+    pub fn receive(&mut self, link: usize, message: BittideMessage) {
+        self.incoming[link].push_back(message.serialize());
+    }
+
+    /// What was sent on `link` in the last interrupt.
+    pub fn last_sent(&self, link: usize) -> BittideMessage {
+        self.sent.last().expect("nothing was sent")[link]
+    }
+}
+
+impl<const DEGREE: usize> Links<DEGREE> for MockLinks<DEGREE> {
+    fn write(&mut self, messages: [BittideMessage; DEGREE]) {
+        self.sent.push(messages);
+    }
+
+    fn read(&mut self) -> [Vec<BittideMessage, MAX_WORDS_PER_READ>; DEGREE] {
+        core::array::from_fn(|link| {
+            let neighbor = link as u8;
+
+            self.incoming[link]
+                .pop_front()
+                .map(|word| match BittideMessage::deserialize(word) {
+                    BittideMessage::CommMessage { data, .. } => {
+                        BittideMessage::CommMessage { neighbor, data }
+                    }
+                    BittideMessage::ManagementMessage {
+                        opcode, payload, ..
+                    } => BittideMessage::ManagementMessage {
+                        neighbor,
+                        opcode,
+                        payload,
+                    },
+                    sync @ BittideMessage::SyncMessage { .. } => sync,
+                })
+                .into_iter()
+                .collect()
+        })
+    }
+
+    fn active_fifos(&self) -> [bool; DEGREE] {
+        self.active
+    }
+}
+
+/// Both directions of the SIO FIFO between the cores, shared between the node and the test.
+#[derive(Clone, Default)]
+pub struct MockFifo {
+    /// Words from core1 to the control core.
+    pub to_control: Rc<RefCell<VecDeque<u32>>>,
+    /// Words from the control core to core1.
+    pub to_user: Rc<RefCell<VecDeque<u32>>>,
+}
+
+impl Fifo for MockFifo {
+    fn read(&mut self) -> Option<u32> {
+        self.to_control.borrow_mut().pop_front()
+    }
+
+    fn write(&mut self, data: u32) {
+        self.to_user.borrow_mut().push_back(data);
+    }
+}
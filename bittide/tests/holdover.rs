@@ -0,0 +1,63 @@
+mod common;
+
+use bittide::bittide::{BittideChannelControlError, BittideMessage};
+use common::{node, Node, BUFFER_SIZE};
+
+const SYNC: BittideMessage = BittideMessage::SyncMessage { sequence: 0 };
+
+/// Runs an interrupt in which the links flagged in `words` receive a word, and returns the levels.
+fn interrupt(node: &mut Node<2>, words: [bool; 2]) -> [u32; 2] {
+    for (link, _) in words.into_iter().enumerate().filter(|&(_, word)| word) {
+        node.links_mut().receive(link, SYNC);
+    }
+    node.interrupt().unwrap();
+
+    node.debug().buffer_levels
+}
+
+#[test]
+fn test_inactive_link_keeps_its_level() {
+    let (mut node, _core1) = node::<2>();
+
+    for _ in 0..4 {
+        assert_eq!(interrupt(&mut node, [true, true]), [1, 1]);
+    }
+
+    // A link without words pops from its buffer until it counts as inactive.
+    assert_eq!(interrupt(&mut node, [true, false]), [1, 0]);
+
+    // From then on its level stays where it was, while the other link carries on.
+    node.links_mut().active[1] = false;
+    for _ in 0..16 {
+        assert_eq!(interrupt(&mut node, [true, false]), [1, 0]);
+    }
+
+    // Once words arrive again, the link picks up at the level it left off.
+    node.links_mut().active[1] = true;
+    for _ in 0..4 {
+        assert_eq!(interrupt(&mut node, [true, true]), [1, 0]);
+    }
+}
+
+#[test]
+fn test_link_before_its_first_word() {
+    // The neighbor on link 1 has not started yet.
+    let (mut node, _core1) = node::<2>();
+    node.links_mut().active[1] = false;
+
+    for _ in 0..BUFFER_SIZE * 4 {
+        node.links_mut().receive(0, SYNC);
+        node.interrupt().unwrap();
+    }
+    assert_eq!(node.debug().buffer_levels, [1, BUFFER_SIZE as u32 / 2]);
+
+    // An active link without words runs its buffer dry.
+    node.links_mut().active[1] = true;
+    node.links_mut().receive(0, SYNC);
+    node.interrupt().unwrap();
+    node.links_mut().receive(0, SYNC);
+    assert!(matches!(
+        node.interrupt(),
+        Err(BittideChannelControlError::BittideFifoEmpty)
+    ));
+}
@@ -0,0 +1,166 @@
+mod common;
+
+use std::sync::Mutex;
+
+use bittide::bittide::{BittideChannelControlError, BittideMessage, MANAGEMENT_OPCODES};
+use common::{node, node_with_mask, Node};
+
+const SYNC: BittideMessage = BittideMessage::SyncMessage { sequence: 0 };
+
+/// Lets `message` arrive on `link` and runs a single interrupt.
+fn step<const DEGREE: usize>(node: &mut Node<DEGREE>, link: usize, message: BittideMessage) {
+    node.links_mut().receive(link, message);
+    node.interrupt().unwrap();
+}
+
+fn management(neighbor: u8, opcode: u8, payload: u32) -> BittideMessage {
+    BittideMessage::ManagementMessage {
+        neighbor,
+        opcode,
+        payload,
+    }
+}
+
+static PINGS: Mutex<Vec<(u8, u32)>> = Mutex::new(Vec::new());
+static RESETS: Mutex<Vec<(u8, u32)>> = Mutex::new(Vec::new());
+
+fn on_ping(neighbor: u8, payload: u32) -> Option<(u8, u32)> {
+    PINGS.lock().unwrap().push((neighbor, payload));
+    Some((2, payload + 1))
+}
+
+fn on_reset(neighbor: u8, payload: u32) -> Option<(u8, u32)> {
+    RESETS.lock().unwrap().push((neighbor, payload));
+    None
+}
+
+#[test]
+fn test_management_dispatch() {
+    let (mut node, _fifo) = node_with_mask([false, false, true, false]);
+    node.register_management_handler(1, on_ping).unwrap();
+    node.register_management_handler(3, on_reset).unwrap();
+
+    // The neighbor field on the wire is the sender's, handlers get the link the message came in on.
+    step(&mut node, 2, management(0, 1, 42));
+    assert!(PINGS.lock().unwrap().is_empty());
+    step(&mut node, 2, management(0, 3, 7));
+    assert_eq!(*PINGS.lock().unwrap(), [(2, 42)]);
+    assert!(RESETS.lock().unwrap().is_empty());
+
+    // The reply goes back over the same link on the next interrupt.
+    step(&mut node, 2, SYNC);
+    assert_eq!(*RESETS.lock().unwrap(), [(2, 7)]);
+    assert_eq!(node.links_mut().last_sent(2), management(2, 2, 43));
+    assert!(matches!(
+        node.links_mut().last_sent(1),
+        BittideMessage::SyncMessage { .. }
+    ));
+
+    step(&mut node, 2, SYNC);
+    assert!(matches!(
+        node.links_mut().last_sent(2),
+        BittideMessage::SyncMessage { .. }
+    ));
+
+    let debug = node.debug();
+    assert_eq!(debug.rx_management_message_counter, 2);
+    assert_eq!(debug.tx_management_message_counter, 1);
+    assert_eq!(debug.unhandled_management_message_counter, 0);
+}
+
+#[test]
+fn test_unhandled_management_message() {
+    let (mut node, fifo) = node_with_mask([true, false]);
+
+    step(&mut node, 0, management(0, 9, 1));
+    step(&mut node, 0, SYNC);
+    step(&mut node, 0, SYNC);
+
+    // Management messages never reach core1, handled or not.
+    assert!(fifo.to_user.borrow().is_empty());
+    assert_eq!(node.debug().unhandled_management_message_counter, 1);
+    assert!(matches!(
+        node.links_mut().last_sent(0),
+        BittideMessage::SyncMessage { .. }
+    ));
+}
+
+#[test]
+fn test_management_queue_full() {
+    let (mut node, _fifo) = node_with_mask([false; 4]);
+
+    for payload in 0..4 {
+        node.send_management(3, 5, payload).unwrap();
+    }
+    assert!(matches!(
+        node.send_management(3, 5, 4),
+        Err(BittideChannelControlError::ManagementQueueFull)
+    ));
+
+    // One message leaves per interrupt, in the order they were queued, which makes room again.
+    node.interrupt().unwrap();
+    assert_eq!(node.links_mut().last_sent(3), management(3, 5, 0));
+    node.send_management(3, 5, 4).unwrap();
+
+    for payload in 1..5 {
+        node.interrupt().unwrap();
+        assert_eq!(node.links_mut().last_sent(3), management(3, 5, payload));
+    }
+
+    node.interrupt().unwrap();
+    assert!(matches!(
+        node.links_mut().last_sent(3),
+        BittideMessage::SyncMessage { .. }
+    ));
+    assert_eq!(node.debug().tx_management_message_counter, 5);
+}
+
+#[test]
+fn test_user_data_goes_first() {
+    let (mut node, fifo) = node_with_mask([false; 2]);
+    let data = BittideMessage::CommMessage {
+        neighbor: 1,
+        data: 1234,
+    };
+
+    node.send_management(1, 5, 6).unwrap();
+    fifo.to_control.borrow_mut().push_back(data.serialize());
+
+    node.interrupt().unwrap();
+    assert_eq!(node.links_mut().last_sent(1), data);
+    assert!(matches!(
+        node.links_mut().last_sent(0),
+        BittideMessage::SyncMessage { .. }
+    ));
+
+    node.interrupt().unwrap();
+    assert_eq!(node.links_mut().last_sent(1), management(1, 5, 6));
+}
+
+#[test]
+fn test_invalid_management() {
+    let (mut node, fifo) = node::<4>();
+    let opcode = MANAGEMENT_OPCODES as u8;
+
+    assert!(matches!(
+        node.register_management_handler(opcode, on_reset),
+        Err(BittideChannelControlError::InvalidManagementOpcode)
+    ));
+    assert!(matches!(
+        node.send_management(0, opcode, 0),
+        Err(BittideChannelControlError::InvalidManagementOpcode)
+    ));
+    assert!(matches!(
+        node.send_management(4, 0, 0),
+        Err(BittideChannelControlError::InvalidNeigbor)
+    ));
+
+    // Core1 cannot send management messages, they are for the control cores only.
+    fifo.to_control
+        .borrow_mut()
+        .push_back(management(0, 1, 0).serialize());
+    assert!(matches!(
+        node.interrupt(),
+        Err(BittideChannelControlError::ManagementMessageFromUserCode)
+    ));
+}
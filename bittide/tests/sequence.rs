@@ -0,0 +1,88 @@
+mod common;
+
+use bittide::bittide::{BittideMessage, LinkErrorCounters};
+use common::node_with_mask;
+
+fn sync(sequence: u16) -> BittideMessage {
+    BittideMessage::SyncMessage { sequence }
+}
+
+const DATA: BittideMessage = BittideMessage::CommMessage {
+    neighbor: 0,
+    data: 0,
+};
+
+/// The errors counted on a link that receives `words`, one per interrupt.
+fn link_errors(words: &[BittideMessage]) -> LinkErrorCounters {
+    let (mut node, _fifo) = node_with_mask([true, false]);
+
+    for &word in words {
+        node.links_mut().receive(0, word);
+        node.interrupt().unwrap();
+    }
+
+    node.debug().link_errors[0]
+}
+
+fn counters(lost_words: u32, duplicated_words: u32, reordered_words: u32) -> LinkErrorCounters {
+    LinkErrorCounters {
+        lost_words,
+        duplicated_words,
+        reordered_words,
+    }
+}
+
+#[test]
+fn test_in_order_words() {
+    // The first sync message sets the expectation, whatever its number.
+    assert_eq!(
+        link_errors(&[DATA, sync(1000), sync(1001), DATA, DATA, sync(1004)]),
+        counters(0, 0, 0)
+    );
+}
+
+#[test]
+fn test_lost_words() {
+    assert_eq!(link_errors(&[sync(0), sync(1), sync(4)]), counters(2, 0, 0));
+    // Data words do not carry a number, a lost one shows at the next sync message.
+    assert_eq!(
+        link_errors(&[sync(0), DATA, sync(3), DATA, sync(5)]),
+        counters(1, 0, 0)
+    );
+}
+
+#[test]
+fn test_duplicated_words() {
+    assert_eq!(
+        link_errors(&[sync(0), sync(1), sync(1), sync(2)]),
+        counters(0, 1, 0)
+    );
+    assert_eq!(
+        link_errors(&[sync(0), DATA, DATA, sync(2)]),
+        counters(0, 1, 0)
+    );
+}
+
+#[test]
+fn test_reordered_words() {
+    assert_eq!(
+        link_errors(&[sync(10), sync(5), sync(6)]),
+        counters(0, 0, 1)
+    );
+}
+
+#[test]
+fn test_sequence_wraparound() {
+    assert_eq!(
+        link_errors(&[sync(u16::MAX - 1), sync(u16::MAX), sync(0), DATA, sync(2)]),
+        counters(0, 0, 0)
+    );
+    assert_eq!(
+        link_errors(&[sync(u16::MAX - 1), sync(u16::MAX), sync(2)]),
+        counters(2, 0, 0)
+    );
+    assert_eq!(
+        link_errors(&[sync(u16::MAX), sync(0), sync(0)]),
+        counters(0, 1, 0)
+    );
+}
@@ -0,0 +1,205 @@
+mod common;
+
+use std::sync::Mutex;
+
+use bittide::bittide::{BittideMessage, DATA_BITS, MAX_DEGREE, PAYLOAD_BITS};
+use common::{node_with_mask, MockFifo};
+
+fn round_trip(message: BittideMessage) -> BittideMessage {
+    BittideMessage::deserialize(message.serialize())
+}
+
+#[test]
+fn test_sync_message_round_trip() {
+    for sequence in [0, 1, 0x1234, u16::MAX] {
+        let message = BittideMessage::SyncMessage { sequence };
+
+        assert_eq!(round_trip(message), message);
+        assert_eq!(message.serialize() & 0b11, 0b01);
+    }
+}
+
+#[test]
+fn test_comm_message_round_trip() {
+    let max_data = u32::MAX >> (32 - DATA_BITS);
+
+    for neighbor in 0..MAX_DEGREE as u8 {
+        for data in [0, 1, 0x0555_5555 & max_data, max_data] {
+            let message = BittideMessage::CommMessage { neighbor, data };
+
+            assert_eq!(round_trip(message), message);
+            assert_eq!(message.serialize() & 0b1, 0);
+        }
+    }
+
+    // Data beyond the field is cut off rather than spilling into the class bits.
+    assert_eq!(
+        round_trip(BittideMessage::CommMessage {
+            neighbor: 1,
+            data: u32::MAX
+        }),
+        BittideMessage::CommMessage {
+            neighbor: 1,
+            data: max_data
+        }
+    );
+}
+
+#[test]
+fn test_management_message_round_trip() {
+    let max_payload = u32::MAX >> (32 - PAYLOAD_BITS);
+
+    for neighbor in 0..MAX_DEGREE as u8 {
+        for opcode in [0, 1, 0b10_1010, 0b11_1111] {
+            for payload in [0, 1, max_payload] {
+                let message = BittideMessage::ManagementMessage {
+                    neighbor,
+                    opcode,
+                    payload,
+                };
+
+                assert_eq!(round_trip(message), message);
+                assert_eq!(message.serialize() & 0b11, 0b11);
+            }
+        }
+    }
+}
+
+static REQUESTS: Mutex<Vec<(u8, u32)>> = Mutex::new(Vec::new());
+static REPLIES: Mutex<Vec<(u8, u32)>> = Mutex::new(Vec::new());
+
+fn on_request(neighbor: u8, payload: u32) -> Option<(u8, u32)> {
+    REQUESTS.lock().unwrap().push((neighbor, payload));
+    Some((10, payload - 1))
+}
+
+fn on_reply(neighbor: u8, payload: u32) -> Option<(u8, u32)> {
+    REPLIES.lock().unwrap().push((neighbor, payload));
+    None
+}
+
+/// The words a node passed to core1.
+fn received(core1: &MockFifo) -> Vec<BittideMessage> {
+    core1
+        .to_user
+        .borrow()
+        .iter()
+        .map(|&word| BittideMessage::deserialize(word))
+        .collect()
+}
+
+#[test]
+fn test_words_between_degrees() {
+    let max_data = u32::MAX >> (32 - DATA_BITS);
+    let max_payload = u32::MAX >> (32 - PAYLOAD_BITS);
+
+    // Link 1 of a two-link node is wired to link 3 of a four-link node.
+    let (mut small, small_core1) = node_with_mask([false, true]);
+    let (mut large, large_core1) = node_with_mask([false, false, false, true]);
+    large.register_management_handler(9, on_request).unwrap();
+    small.register_management_handler(10, on_reply).unwrap();
+
+    small_core1.to_control.borrow_mut().push_back(
+        BittideMessage::CommMessage {
+            neighbor: 1,
+            data: max_data,
+        }
+        .serialize(),
+    );
+    large_core1.to_control.borrow_mut().push_back(
+        BittideMessage::CommMessage {
+            neighbor: 3,
+            data: 12345,
+        }
+        .serialize(),
+    );
+    small.send_management(1, 9, max_payload).unwrap();
+
+    // As if the large node sent a word just before, so the small node has one to start with.
+    small
+        .links_mut()
+        .receive(1, BittideMessage::SyncMessage { sequence: u16::MAX });
+
+    for _ in 0..8 {
+        small.interrupt().unwrap();
+        let word = small.links_mut().last_sent(1);
+        large.links_mut().receive(3, word);
+
+        large.interrupt().unwrap();
+        let word = large.links_mut().last_sent(3);
+        small.links_mut().receive(1, word);
+    }
+
+    assert_eq!(
+        received(&large_core1),
+        [BittideMessage::CommMessage {
+            neighbor: 3,
+            data: max_data
+        }]
+    );
+    assert_eq!(
+        received(&small_core1),
+        [BittideMessage::CommMessage {
+            neighbor: 1,
+            data: 12345
+        }]
+    );
+    assert_eq!(*REQUESTS.lock().unwrap(), [(3, max_payload)]);
+    assert_eq!(*REPLIES.lock().unwrap(), [(1, max_payload - 1)]);
+
+    assert_eq!(small.debug().link_errors[1], Default::default());
+    assert_eq!(large.debug().link_errors[3], Default::default());
+}
+
+#[test]
+fn test_degree_six_round_trip() {
+    // Link 5 of one node of a hex grid is wired to link 2 of another.
+    let (mut a, a_core1) = node_with_mask(core::array::from_fn::<_, 6, _>(|link| link == 5));
+    let (mut b, b_core1) = node_with_mask(core::array::from_fn::<_, 6, _>(|link| link == 2));
+
+    a_core1.to_control.borrow_mut().push_back(
+        BittideMessage::CommMessage {
+            neighbor: 5,
+            data: 6,
+        }
+        .serialize(),
+    );
+    b_core1.to_control.borrow_mut().push_back(
+        BittideMessage::CommMessage {
+            neighbor: 2,
+            data: 7,
+        }
+        .serialize(),
+    );
+
+    a.links_mut()
+        .receive(5, BittideMessage::SyncMessage { sequence: u16::MAX });
+
+    for _ in 0..8 {
+        a.interrupt().unwrap();
+        let word = a.links_mut().last_sent(5);
+        b.links_mut().receive(2, word);
+
+        b.interrupt().unwrap();
+        let word = b.links_mut().last_sent(2);
+        a.links_mut().receive(5, word);
+    }
+
+    assert_eq!(
+        received(&b_core1),
+        [BittideMessage::CommMessage {
+            neighbor: 2,
+            data: 6
+        }]
+    );
+    assert_eq!(
+        received(&a_core1),
+        [BittideMessage::CommMessage {
+            neighbor: 5,
+            data: 7
+        }]
+    );
+
+    assert_eq!(a.debug().link_errors[5], Default::default());
+    assert_eq!(b.debug().link_errors[2], Default::default());
+}
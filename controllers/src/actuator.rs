@@ -0,0 +1,21 @@
+use core::ops::RangeInclusive;
+
+use fixed::types::I16F16;
+
+/// A hardware knob that changes the system clock frequency of a node. Control laws compute a
+/// relative frequency correction, so any law can drive any of these knobs.
+pub trait FrequencyActuator {
+    type Error;
+
+    /// Offsets in ppm around the nominal frequency that this actuator can reach.
+    fn range(&self) -> RangeInclusive<I16F16>;
+    /// The smallest change in frequency this actuator can make, in ppm.
+    fn resolution(&self) -> I16F16;
+    /// Time in microseconds between setting an offset and the frequency changing.
+    fn apply_latency_us(&self) -> u32;
+    /// Sets the frequency to `offset` ppm away from the nominal frequency of this actuator.
+    /// Offsets outside of the range of the actuator are clamped to that range.
+    fn set_offset_ppm(&mut self, offset: I16F16) -> Result<(), Self::Error>;
+    /// The value last written to the hardware, like a frac or fbdiv, for debugging.
+    fn setting(&self) -> u32;
+}
@@ -0,0 +1,196 @@
+//! Relay-feedback auto-tuning after Åström and Hägglund. A relay steps the correction up while the total
+//! buffer deviation is above the hysteresis and down while it is below, which makes the buffers oscillate
+//! at the frequency where the loop has a phase lag of 180 degrees. The amplitude and period of that
+//! oscillation give the ultimate gain and period of the loop, and from those the gains follow with the
+//! Ziegler-Nichols rules.
+
+use fixed::types::I16F16;
+
+use crate::pid::PidSettings;
+
+/// Cycles at the start of the experiment that are not measured, while the bias settles.
+const SETTLE_CYCLES: u32 = 2;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RelaySettings {
+    /// Step of the correction around the bias in ppm. Must be larger than the frequency difference
+    /// with the neighbors, or the buffers never turn around.
+    pub amplitude: I16F16,
+    /// Deviation in words the total buffer deviation has to cross before the relay switches. At least
+    /// a word, as buffer levels only change in whole words.
+    pub hysteresis: I16F16,
+    /// Cycles of the oscillation to average over.
+    pub cycles: u32,
+    /// Runs after which the experiment is given up.
+    pub timeout_runs: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum TuningError {
+    /// The buffers did not oscillate for the requested number of cycles in time.
+    Timeout,
+    /// The measured gain or period does not fit the fixed point gains.
+    Overflow,
+}
+
+/// Outcome of a relay experiment.
+#[derive(Debug, Clone, Copy)]
+pub struct TuningResult {
+    /// Gain in ppm per word at which the loop oscillates.
+    pub ultimate_gain: I16F16,
+    /// Period of the oscillation in runs.
+    pub ultimate_period: u32,
+    /// Correction around which the relay switched, the correction that keeps the buffers level.
+    pub bias: I16F16,
+    /// Ziegler-Nichols PI gains of a PID from the total buffer deviation to the correction, with the
+    /// integral per run. There is no derivative gain, as it would amplify the whole-word steps of the
+    /// buffer levels.
+    pub settings: PidSettings,
+}
+
+pub enum RelayStep {
+    /// The correction to apply this run.
+    Output(I16F16),
+    Done(TuningResult),
+    Failed(TuningError),
+}
+
+/// Runs a relay experiment, one [`RelayTuner::update`] per run of the controller.
+pub struct RelayTuner {
+    settings: RelaySettings,
+    bias: I16F16,
+    high: bool,
+    runs: u32,
+    /// Run at which the relay last switched up, which starts a cycle.
+    cycle_start: Option<u32>,
+    high_runs: u32,
+    max: I16F16,
+    min: I16F16,
+    cycles: u32,
+    amplitude_sum: I16F16,
+    period_sum: u32,
+}
+
+impl RelayTuner {
+    pub fn new(settings: RelaySettings) -> Self {
+        Self {
+            settings,
+            bias: I16F16::ZERO,
+            high: false,
+            runs: 0,
+            cycle_start: None,
+            high_runs: 0,
+            max: I16F16::MIN,
+            min: I16F16::MAX,
+            cycles: 0,
+            amplitude_sum: I16F16::ZERO,
+            period_sum: 0,
+        }
+    }
+
+    pub fn settings(&self) -> &RelaySettings {
+        &self.settings
+    }
+
+    /// Cycles measured so far, not counting the ones the bias settles in.
+    pub fn cycles(&self) -> u32 {
+        self.cycles.saturating_sub(SETTLE_CYCLES)
+    }
+
+    /// Takes the total deviation of the buffers from their midpoints and returns what to do this run.
+    /// The bias moves to the average output of every cycle, so the relay ends up switching around the
+    /// correction that matches the neighbors.
+    pub fn update(&mut self, deviation: I16F16) -> RelayStep {
+        let RelaySettings {
+            amplitude,
+            hysteresis,
+            cycles,
+            timeout_runs,
+        } = self.settings;
+
+        self.runs += 1;
+        if self.runs > timeout_runs {
+            return RelayStep::Failed(TuningError::Timeout);
+        }
+
+        self.max = self.max.max(deviation);
+        self.min = self.min.min(deviation);
+
+        if !self.high && deviation > hysteresis {
+            self.high = true;
+
+            if let Some(start) = self.cycle_start {
+                let period = self.runs - start;
+                let low_runs = period - self.high_runs;
+
+                let shift = amplitude.to_bits() as i64 * (self.high_runs as i64 - low_runs as i64)
+                    / period.max(1) as i64;
+                self.bias = self.bias.saturating_add(I16F16::from_bits(shift as i32));
+                self.cycles += 1;
+
+                if self.cycles > SETTLE_CYCLES {
+                    self.amplitude_sum = self
+                        .amplitude_sum
+                        .saturating_add((self.max.saturating_sub(self.min)) / 2);
+                    self.period_sum = self.period_sum.saturating_add(period);
+                }
+
+                if self.cycles() >= cycles.max(1) {
+                    return self.result();
+                }
+            }
+
+            self.cycle_start = Some(self.runs);
+            self.high_runs = 0;
+            self.max = deviation;
+            self.min = deviation;
+        } else if self.high && deviation < -hysteresis {
+            self.high = false;
+        }
+
+        if self.high {
+            self.high_runs += 1;
+        }
+
+        // Buffers above their midpoint mean the neighbors run faster, so the relay speeds up.
+        RelayStep::Output(if self.high {
+            self.bias.saturating_add(amplitude)
+        } else {
+            self.bias.saturating_sub(amplitude)
+        })
+    }
+
+    fn result(&self) -> RelayStep {
+        let cycles = self.cycles() as i32;
+        let amplitude = self.amplitude_sum / cycles;
+        let period = self.period_sum / cycles as u32;
+
+        // The describing function of a relay: a sine of amplitude `a` in gives a fundamental of
+        // 4d/(pi a) out.
+        let ultimate_gain = self
+            .settings
+            .amplitude
+            .checked_mul_int(4)
+            .and_then(|gain| gain.checked_div(I16F16::PI.checked_mul(amplitude)?));
+
+        let settings = ultimate_gain.and_then(|ku| {
+            Some(PidSettings {
+                kp: ku.checked_mul(I16F16::lit("0.45"))?,
+                ki: ku
+                    .checked_mul(I16F16::lit("0.54"))?
+                    .checked_div_int(period.max(1) as i32)?,
+                kd: I16F16::ZERO,
+            })
+        });
+
+        match (ultimate_gain, settings) {
+            (Some(ultimate_gain), Some(settings)) => RelayStep::Done(TuningResult {
+                ultimate_gain,
+                ultimate_period: period,
+                bias: self.bias,
+                settings,
+            }),
+            _ => RelayStep::Failed(TuningError::Overflow),
+        }
+    }
+}
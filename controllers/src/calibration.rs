@@ -0,0 +1,308 @@
+use core::ops::RangeInclusive;
+
+use fixed::types::{I16F16, U32F32};
+
+use crate::actuator::FrequencyActuator;
+
+/// Offsets measured over the range of an actuator, spread evenly from the start to the end.
+pub const CALIBRATION_POINTS: usize = 17;
+
+/// Size of a serialized [`CalibrationTable`].
+pub const CALIBRATION_BYTES: usize = 4 + 4 + CALIBRATION_POINTS * 8 + 4;
+
+/// Marks a stored table, the last byte is the version of the layout.
+const MAGIC: [u8; 4] = *b"CAL\x01";
+
+/// Measures the frequency of the clock that an actuator drives.
+pub trait FrequencyCounter {
+    /// Frequency in Hz, `None` if the counter could not measure it.
+    fn measure_hz(&mut self) -> Option<U32F32>;
+    /// Time in microseconds a measurement takes, 0 for a counter that reads the frequency at an
+    /// instant.
+    fn interval_us(&self) -> u32;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub enum CalibrationError<E> {
+    ActuatorError(E),
+    CounterFailed,
+    /// The measured frequency does not go up with every offset, e.g. because the actuator rounds
+    /// several of the offsets to the same setting.
+    NotMonotonic,
+}
+
+/// An offset set on the actuator and the offset it resulted in, both in ppm.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CalibrationPoint {
+    pub offset: I16F16,
+    pub measured: I16F16,
+}
+
+/// What an actuator actually does to the frequency: for every offset it was asked for, the offset
+/// that was measured relative to the frequency at offset zero. Between the points the relation is
+/// taken to be linear, beyond the ends it follows the outermost points.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CalibrationTable {
+    nominal_hz: u32,
+    points: [CalibrationPoint; CALIBRATION_POINTS],
+}
+
+impl CalibrationTable {
+    /// Both the offsets and the measured offsets must strictly go up.
+    pub fn new(
+        nominal_hz: u32,
+        points: [CalibrationPoint; CALIBRATION_POINTS],
+    ) -> Result<Self, CalibrationError<()>> {
+        let increasing = points
+            .windows(2)
+            .all(|pair| pair[0].offset < pair[1].offset && pair[0].measured < pair[1].measured);
+
+        if !increasing {
+            return Err(CalibrationError::NotMonotonic);
+        }
+
+        Ok(Self { nominal_hz, points })
+    }
+
+    /// Frequency measured at offset zero, in whole Hz.
+    pub fn nominal_hz(&self) -> u32 {
+        self.nominal_hz
+    }
+
+    pub fn points(&self) -> &[CalibrationPoint; CALIBRATION_POINTS] {
+        &self.points
+    }
+
+    /// The offset the frequency moves by when the actuator is set to `offset`.
+    pub fn measured(&self, offset: I16F16) -> I16F16 {
+        self.interpolate(offset, |point| (point.offset, point.measured))
+    }
+
+    /// The offset to set on the actuator to move the frequency by `offset`.
+    pub fn command(&self, offset: I16F16) -> I16F16 {
+        self.interpolate(offset, |point| (point.measured, point.offset))
+    }
+
+    fn interpolate(
+        &self,
+        x: I16F16,
+        axes: impl Fn(&CalibrationPoint) -> (I16F16, I16F16),
+    ) -> I16F16 {
+        // The segment that contains `x`, or the outermost one on that side.
+        let segment = self.points[1..CALIBRATION_POINTS - 1]
+            .iter()
+            .take_while(|point| axes(point).0 < x)
+            .count();
+        let (x0, y0) = axes(&self.points[segment]);
+        let (x1, y1) = axes(&self.points[segment + 1]);
+
+        let dx = x1.to_bits() as i64 - x0.to_bits() as i64;
+        let dy = y1.to_bits() as i64 - y0.to_bits() as i64;
+        let y = y0.to_bits() as i64 + (x.to_bits() as i64 - x0.to_bits() as i64) * dy / dx;
+
+        I16F16::from_bits(y.clamp(i32::MIN as i64, i32::MAX as i64) as i32)
+    }
+
+    /// Little endian, with a checksum such that erased or partially written flash is not taken for
+    /// a table.
+    pub fn to_bytes(&self) -> [u8; CALIBRATION_BYTES] {
+        let mut bytes = [0; CALIBRATION_BYTES];
+
+        bytes[0..4].copy_from_slice(&MAGIC);
+        bytes[4..8].copy_from_slice(&self.nominal_hz.to_le_bytes());
+
+        for (point, chunk) in self.points.iter().zip(bytes[8..].chunks_exact_mut(8)) {
+            chunk[0..4].copy_from_slice(&point.offset.to_bits().to_le_bytes());
+            chunk[4..8].copy_from_slice(&point.measured.to_bits().to_le_bytes());
+        }
+
+        let checksum = checksum(&bytes[..CALIBRATION_BYTES - 4]);
+        bytes[CALIBRATION_BYTES - 4..].copy_from_slice(&checksum.to_le_bytes());
+
+        bytes
+    }
+
+    /// `None` if `bytes` does not hold a valid table.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes = bytes.get(..CALIBRATION_BYTES)?;
+        let word = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+
+        if bytes[0..4] != MAGIC
+            || word(CALIBRATION_BYTES - 4) != checksum(&bytes[..CALIBRATION_BYTES - 4])
+        {
+            return None;
+        }
+
+        let points = core::array::from_fn(|i| CalibrationPoint {
+            offset: I16F16::from_bits(word(8 + i * 8) as i32),
+            measured: I16F16::from_bits(word(12 + i * 8) as i32),
+        });
+
+        Self::new(word(4), points).ok()
+    }
+}
+
+/// FNV-1a.
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, &byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Sweeps `actuator` over its range and measures the resulting frequency at every point with
+/// `counter`, averaging `samples` measurements. After every change the measurements until the
+/// actuator has applied it are thrown away, and one more, as the frequency may have changed halfway
+/// through it. Offsets are relative to the frequency measured at offset zero, so an error in the
+/// reference of the counter drops out. Leaves the actuator at offset zero.
+pub fn calibrate<A, C>(
+    actuator: &mut A,
+    counter: &mut C,
+    samples: u32,
+) -> Result<CalibrationTable, CalibrationError<A::Error>>
+where
+    A: FrequencyActuator,
+    C: FrequencyCounter,
+{
+    let span = actuator.range();
+    let nominal = average_hz(actuator, counter, I16F16::ZERO, samples)?;
+
+    if nominal == 0 {
+        return Err(CalibrationError::CounterFailed);
+    }
+
+    let mut points = [CalibrationPoint::default(); CALIBRATION_POINTS];
+
+    for (i, point) in points.iter_mut().enumerate() {
+        let offset = sweep_offset(&span, i);
+        let hz = average_hz(actuator, counter, offset, samples)?;
+        let ppm = (hz as i128 - nominal as i128) * 1_000_000 * (1 << 16) / nominal as i128;
+
+        *point = CalibrationPoint {
+            offset,
+            measured: I16F16::from_bits(ppm.clamp(i32::MIN as i128, i32::MAX as i128) as i32),
+        };
+    }
+
+    actuator
+        .set_offset_ppm(I16F16::ZERO)
+        .map_err(CalibrationError::ActuatorError)?;
+
+    CalibrationTable::new((nominal >> 32) as u32, points)
+        .map_err(|_| CalibrationError::NotMonotonic)
+}
+
+/// Average frequency at `offset`, in the bits of a `U32F32`.
+fn average_hz<A, C>(
+    actuator: &mut A,
+    counter: &mut C,
+    offset: I16F16,
+    samples: u32,
+) -> Result<u128, CalibrationError<A::Error>>
+where
+    A: FrequencyActuator,
+    C: FrequencyCounter,
+{
+    let samples = samples.max(1);
+
+    actuator
+        .set_offset_ppm(offset)
+        .map_err(CalibrationError::ActuatorError)?;
+
+    let settling = match counter.interval_us() {
+        0 => 0,
+        interval => actuator.apply_latency_us().div_ceil(interval),
+    };
+    for _ in 0..=settling {
+        counter
+            .measure_hz()
+            .ok_or(CalibrationError::CounterFailed)?;
+    }
+
+    let mut sum = 0;
+    for _ in 0..samples {
+        sum += counter
+            .measure_hz()
+            .ok_or(CalibrationError::CounterFailed)?
+            .to_bits() as u128;
+    }
+
+    Ok(sum / samples as u128)
+}
+
+fn sweep_offset(span: &RangeInclusive<I16F16>, i: usize) -> I16F16 {
+    let (start, end) = (span.start().to_bits() as i64, span.end().to_bits() as i64);
+
+    I16F16::from_bits((start + (end - start) * i as i64 / (CALIBRATION_POINTS as i64 - 1)) as i32)
+}
+
+/// Sets offsets on an actuator through a [`CalibrationTable`], such that the frequency moves by the
+/// offset that was asked for rather than by what the nominal resolution of the actuator promises.
+/// Without a table the offsets go to the actuator as they are.
+pub struct Calibrated<A> {
+    actuator: A,
+    table: Option<CalibrationTable>,
+}
+
+impl<A: FrequencyActuator> Calibrated<A> {
+    pub fn new(actuator: A, table: Option<CalibrationTable>) -> Self {
+        Self { actuator, table }
+    }
+
+    pub fn set_table(&mut self, table: Option<CalibrationTable>) {
+        self.table = table;
+    }
+
+    pub fn table(&self) -> Option<&CalibrationTable> {
+        self.table.as_ref()
+    }
+
+    pub fn actuator(&self) -> &A {
+        &self.actuator
+    }
+
+    pub fn actuator_mut(&mut self) -> &mut A {
+        &mut self.actuator
+    }
+}
+
+impl<A: FrequencyActuator> FrequencyActuator for Calibrated<A> {
+    type Error = A::Error;
+
+    fn range(&self) -> RangeInclusive<I16F16> {
+        let range = self.actuator.range();
+
+        match &self.table {
+            Some(table) => table.measured(*range.start())..=table.measured(*range.end()),
+            None => range,
+        }
+    }
+
+    /// The step of the actuator around offset zero.
+    fn resolution(&self) -> I16F16 {
+        let resolution = self.actuator.resolution();
+
+        match &self.table {
+            Some(table) => table
+                .measured(resolution)
+                .saturating_sub(table.measured(I16F16::ZERO)),
+            None => resolution,
+        }
+    }
+
+    fn apply_latency_us(&self) -> u32 {
+        self.actuator.apply_latency_us()
+    }
+
+    fn set_offset_ppm(&mut self, offset: I16F16) -> Result<(), Self::Error> {
+        let offset = match &self.table {
+            Some(table) => table.command(offset),
+            None => offset,
+        };
+
+        self.actuator.set_offset_ppm(offset)
+    }
+
+    fn setting(&self) -> u32 {
+        self.actuator.setting()
+    }
+}
@@ -1,8 +1,20 @@
+use core::ops::RangeInclusive;
+
+use fixed::types::I16F16;
+
+use crate::{
+    actuator::FrequencyActuator,
+    autotune::{RelaySettings, RelayStep, RelayTuner, TuningResult},
+    law::{total_deviation, ControlLaw, Saturation},
+    parameters::ControllerParameters,
+    pid::PidSettings,
+};
+
 /// Trait for frequency Controllers generic over:
 /// B: the elastic buffer size.
 /// The controller should have access to the resources that control frequency.
 pub trait FrequencyController<const B: usize> {
-    type Error;
+    type Error: Into<FaultReason>;
     type Debug;
 
     /// Run the frequency control algorithm. This is called at a set interval (every N cycles)
@@ -10,6 +22,389 @@ pub trait FrequencyController<const B: usize> {
     fn run(&mut self, buffer_levels: &[usize]) -> Result<(), Self::Error>;
     /// Change the amount of neighboring nodes that the controller should assume.
     fn set_degree(&mut self, new_degree: usize);
+    /// Tell the controller which links are in use, masked links keep a buffer level that should be ignored.
+    /// Controllers that only look at the sum of the buffer levels ignore this.
+    fn set_link_mask(&mut self, _link_mask: &[bool]) {}
+    /// Tell the controller which links currently receive words. Buffers of inactive links do not
+    /// move, so their levels say nothing about the frequency of the neighbor.
+    fn set_active_links(&mut self, _active: &[bool]) {}
     /// Retrieve debug information
     fn debug(&self) -> Self::Debug;
 }
+
+/// Runs between two log lines with the state of the controller.
+const LOG_INTERVAL: u32 = 32768;
+
+/// The average correction held in holdover follows every correction by 2^-shift.
+const HOLDOVER_AVERAGE_SHIFT: u32 = 10;
+
+/// Drives a frequency actuator `A` with a control law `L`, for nodes with up to `DEGREE` links.
+///
+/// While none of the enabled links is active the controller is in holdover: the law does not run, and
+/// the actuator stays at the average of the corrections from before the links were lost. When links
+/// come back the law continues from that correction.
+///
+/// During a relay experiment started with [`Controller::start_autotune`] the relay drives the actuator
+/// instead of the law. The law gets the gains that come out of it and continues from the correction
+/// around which the relay switched.
+pub struct Controller<L, A, const DEGREE: usize> {
+    law: L,
+    actuator: A,
+    link_mask: [bool; DEGREE],
+    active: [bool; DEGREE],
+    /// Offset in words from the midpoint of the buffers to steer towards.
+    setpoint: I16F16,
+    /// Kept on top of the range of the actuator.
+    limits: RangeInclusive<I16F16>,
+    correction: I16F16,
+    average: I16F16,
+    average_shift: u32,
+    holdover: bool,
+    tuner: Option<RelayTuner>,
+    tuning: Option<TuningResult>,
+    health: ControllerHealth,
+    runs: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ControllerError<E> {
+    /// The law could not compute a correction, e.g. because of an overflow.
+    LawError,
+    ActuatorError(E),
+}
+
+/// Why a node cannot lock, small enough to pass on to a debugger as a code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum FaultReason {
+    /// The law could not compute a correction, e.g. because of an overflow in the PID.
+    LawOverflow,
+    /// Writing the correction to the actuator failed.
+    ActuatorWrite,
+    /// The correction is held at the top of the range, the neighbors are faster than this node can go.
+    SaturatedHigh,
+    /// The correction is held at the bottom of the range.
+    SaturatedLow,
+}
+
+impl FaultReason {
+    pub fn code(self) -> u8 {
+        match self {
+            FaultReason::LawOverflow => 1,
+            FaultReason::ActuatorWrite => 2,
+            FaultReason::SaturatedHigh => 3,
+            FaultReason::SaturatedLow => 4,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(FaultReason::LawOverflow),
+            2 => Some(FaultReason::ActuatorWrite),
+            3 => Some(FaultReason::SaturatedHigh),
+            4 => Some(FaultReason::SaturatedLow),
+            _ => None,
+        }
+    }
+}
+
+impl<E> From<ControllerError<E>> for FaultReason {
+    fn from(error: ControllerError<E>) -> Self {
+        match error {
+            ControllerError::LawError => FaultReason::LawOverflow,
+            ControllerError::ActuatorError(_) => FaultReason::ActuatorWrite,
+        }
+    }
+}
+
+/// Counters of the runs in which the controller could not steer freely.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct ControllerHealth {
+    /// End of the range the correction of the last run of the law was held at.
+    pub saturation: Option<Saturation>,
+    pub saturated_high_runs: u32,
+    pub saturated_low_runs: u32,
+    /// Times the correction ran into an end of the range, including restarts from the nominal
+    /// frequency of a recentering law.
+    pub clamp_events: u32,
+    pub law_failures: u32,
+    pub actuator_failures: u32,
+}
+
+impl ControllerHealth {
+    /// The reason the node cannot lock right now, if it is saturated.
+    pub fn reason(&self) -> Option<FaultReason> {
+        self.saturation.map(|saturation| match saturation {
+            Saturation::High => FaultReason::SaturatedHigh,
+            Saturation::Low => FaultReason::SaturatedLow,
+        })
+    }
+
+    fn update(&mut self, saturation: Option<Saturation>, correction: I16F16) {
+        match saturation {
+            Some(Saturation::High) => {
+                self.saturated_high_runs = self.saturated_high_runs.saturating_add(1)
+            }
+            Some(Saturation::Low) => {
+                self.saturated_low_runs = self.saturated_low_runs.saturating_add(1)
+            }
+            None => {}
+        }
+
+        if saturation.is_some() && saturation != self.saturation {
+            self.clamp_events = self.clamp_events.saturating_add(1);
+            defmt::warn!(
+                "Correction saturated {} at {}ppm",
+                saturation,
+                correction.to_num::<f32>()
+            );
+        }
+
+        self.saturation = saturation;
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ControllerDebug<LD> {
+    /// Last correction written to the actuator, in ppm.
+    pub correction: I16F16,
+    /// Value last written to the hardware by the actuator.
+    pub setting: u32,
+    /// Whether the frequency is held because no link is active.
+    pub holdover: bool,
+    /// Whether a relay experiment drives the actuator.
+    pub autotuning: bool,
+    pub health: ControllerHealth,
+    pub law: LD,
+}
+
+impl<L, A, const DEGREE: usize> Controller<L, A, DEGREE>
+where
+    L: ControlLaw<DEGREE>,
+    A: FrequencyActuator,
+{
+    /// Creates a controller with all links enabled.
+    pub fn new(law: L, actuator: A) -> Self {
+        Self {
+            law,
+            actuator,
+            link_mask: [true; DEGREE],
+            active: [true; DEGREE],
+            setpoint: I16F16::ZERO,
+            limits: I16F16::MIN..=I16F16::MAX,
+            correction: I16F16::ZERO,
+            average: I16F16::ZERO,
+            average_shift: HOLDOVER_AVERAGE_SHIFT,
+            holdover: false,
+            tuner: None,
+            tuning: None,
+            health: ControllerHealth::default(),
+            runs: 0,
+        }
+    }
+
+    /// Averages the corrections held in holdover over about 2^`shift` runs.
+    pub fn with_holdover_averaging(mut self, shift: u32) -> Self {
+        self.average_shift = shift.min(I16F16::FRAC_NBITS);
+        self
+    }
+
+    pub fn in_holdover(&self) -> bool {
+        self.holdover
+    }
+
+    /// Starts a relay experiment from the next run, which pauses while the controller is in holdover.
+    pub fn start_autotune(&mut self, relay: RelaySettings) {
+        self.tuner = Some(RelayTuner::new(relay));
+    }
+
+    pub fn autotuning(&self) -> bool {
+        self.tuner.is_some()
+    }
+
+    /// The result of the last relay experiment that finished.
+    pub fn tuning(&self) -> Option<&TuningResult> {
+        self.tuning.as_ref()
+    }
+
+    pub fn health(&self) -> &ControllerHealth {
+        &self.health
+    }
+
+    /// Applies parameters that changed at runtime. The message limit belongs to the links.
+    pub fn set_parameters(&mut self, parameters: &ControllerParameters) {
+        self.law.set_gains(parameters.gains);
+        self.setpoint = parameters.setpoint;
+        self.limits = parameters.clamp_min..=parameters.clamp_max;
+    }
+
+    /// The range of the actuator, narrowed down by the limits.
+    fn range(&self) -> RangeInclusive<I16F16> {
+        let range = self.actuator.range();
+        let start = (*range.start()).max(*self.limits.start()).min(*range.end());
+        let end = (*range.end()).min(*self.limits.end()).max(start);
+
+        start..=end
+    }
+
+    /// The gains of the law that [`Controller::set_parameters`] replaces, see [`ControlLaw::gains`].
+    pub fn gains(&self) -> Option<PidSettings> {
+        self.law.gains()
+    }
+
+    pub fn law(&self) -> &L {
+        &self.law
+    }
+
+    pub fn actuator(&self) -> &A {
+        &self.actuator
+    }
+
+    pub fn actuator_mut(&mut self) -> &mut A {
+        &mut self.actuator
+    }
+}
+
+impl<L, A, const B: usize, const DEGREE: usize> FrequencyController<B> for Controller<L, A, DEGREE>
+where
+    L: ControlLaw<DEGREE>,
+    A: FrequencyActuator,
+{
+    type Error = ControllerError<A::Error>;
+    type Debug = ControllerDebug<L::Debug>;
+
+    fn run(&mut self, buffer_levels: &[usize]) -> Result<(), Self::Error> {
+        self.runs = (self.runs + 1) % LOG_INTERVAL;
+        let midpoint = I16F16::from_num(B / 2).saturating_add(self.setpoint);
+
+        let deviations: [Option<I16F16>; DEGREE] = core::array::from_fn(|link| {
+            buffer_levels
+                .get(link)
+                .filter(|_| self.link_mask[link] && self.active[link])
+                .map(|&level| I16F16::saturating_from_num(level).saturating_sub(midpoint))
+        });
+
+        let range = self.range();
+        let holdover = deviations.iter().all(Option::is_none);
+
+        if holdover != self.holdover {
+            self.holdover = holdover;
+
+            if holdover {
+                defmt::warn!(
+                    "No active links, holding at {}ppm",
+                    self.average.to_num::<f32>()
+                );
+            } else {
+                defmt::info!(
+                    "Links are back, resuming from {}ppm",
+                    self.correction.to_num::<f32>()
+                );
+            }
+        }
+
+        let relay = match (&mut self.tuner, holdover) {
+            (Some(tuner), false) => Some(tuner.update(total_deviation(&deviations))),
+            _ => None,
+        };
+
+        let correction = if holdover {
+            let held = self.average.clamp(*range.start(), *range.end());
+            self.law.hold(held);
+            held
+        } else if let Some(relay) = relay {
+            match relay {
+                RelayStep::Output(correction) => correction.clamp(*range.start(), *range.end()),
+                RelayStep::Done(result) => {
+                    defmt::info!(
+                        "Auto-tuned: Ku={}ppm/word Tu={} runs bias={}ppm kp={} ki={}",
+                        result.ultimate_gain.to_num::<f32>(),
+                        result.ultimate_period,
+                        result.bias.to_num::<f32>(),
+                        result.settings.kp.to_num::<f32>(),
+                        result.settings.ki.to_num::<f32>(),
+                    );
+
+                    let bias = result.bias.clamp(*range.start(), *range.end());
+                    self.law.tune(result.settings);
+                    self.law.resume(bias);
+                    self.tuner = None;
+                    self.tuning = Some(result);
+                    bias
+                }
+                RelayStep::Failed(error) => {
+                    defmt::warn!("Auto-tuning failed: {}", error);
+
+                    self.law.resume(self.correction);
+                    self.tuner = None;
+                    self.correction
+                }
+            }
+        } else {
+            let Some(unclamped) = self.law.correction(&deviations, &range) else {
+                self.health.law_failures = self.health.law_failures.saturating_add(1);
+                return Err(ControllerError::LawError);
+            };
+            let correction = unclamped.clamp(*range.start(), *range.end());
+
+            let saturation = self.law.saturation().or(if unclamped >= *range.end() {
+                Some(Saturation::High)
+            } else if unclamped <= *range.start() {
+                Some(Saturation::Low)
+            } else {
+                None
+            });
+            self.health.update(saturation, correction);
+
+            self.average = self
+                .average
+                .saturating_add(correction.saturating_sub(self.average) >> self.average_shift);
+            correction
+        };
+
+        if let Err(error) = self.actuator.set_offset_ppm(correction) {
+            self.health.actuator_failures = self.health.actuator_failures.saturating_add(1);
+            return Err(ControllerError::ActuatorError(error));
+        }
+
+        self.correction = correction;
+
+        if self.runs == 0 {
+            defmt::info!(
+                "buffer_levels={} correction={}ppm setting={}",
+                buffer_levels,
+                correction.to_num::<f32>(),
+                self.actuator.setting(),
+            );
+        }
+
+        Ok(())
+    }
+
+    /// The degree follows from the link mask.
+    fn set_degree(&mut self, _new_degree: usize) {}
+
+    fn set_link_mask(&mut self, link_mask: &[bool]) {
+        self.link_mask
+            .iter_mut()
+            .zip(link_mask.iter().chain(core::iter::repeat(&false)))
+            .for_each(|(enabled, &new)| *enabled = new);
+    }
+
+    fn set_active_links(&mut self, active: &[bool]) {
+        self.active
+            .iter_mut()
+            .zip(active.iter().chain(core::iter::repeat(&false)))
+            .for_each(|(is_active, &new)| *is_active = new);
+    }
+
+    fn debug(&self) -> Self::Debug {
+        ControllerDebug {
+            correction: self.correction,
+            setting: self.actuator.setting(),
+            holdover: self.holdover,
+            autotuning: self.tuner.is_some(),
+            health: self.health,
+            law: self.law.debug(),
+        }
+    }
+}
@@ -1,117 +1,175 @@
 use core::ops::RangeInclusive;
 
 use fixed::types::I16F16;
-use heapless::Vec;
 use rp_pico::pac::PLL_SYS;
 
 use crate::{
-    controller::FrequencyController,
-    pid::{PidControl, PidSettings},
+    actuator::FrequencyActuator,
+    controller::Controller,
+    law::Integrating,
+    pid::{AntiWindup, PidControl, PidLaw, PidSettings},
 };
 
-/// Frequency controller that affects frequency through the fbdiv_int register on the rpi pico.
-/// This register must be of a value between 16 and 320, and a lower value results in a higher
-/// system clock frequency.
-pub struct FbdivController {
-    degree: usize,
-    pll_sys: PLL_SYS,
-    fbdiv_internal: I16F16,
-    pid: PidControl,
-
-    /// For debugging the first few runs of the controller
-    debug: Vec<(usize, I16F16), 256>,
-    i: u32,
+/// Access to the fbdiv_int register of a PLL, so the controller can also run against a simulated PLL.
+pub trait PllFbdiv {
+    fn read_fbdiv(&self) -> u16;
+    fn write_fbdiv(&mut self, fbdiv: u16);
 }
 
-impl FbdivController {
-    pub fn new(degree: usize, pll_sys: PLL_SYS, pid_settings: PidSettings) -> Self {
-        let initial_fbdiv = pll_sys.fbdiv_int().read().fbdiv_int().bits();
+impl PllFbdiv for PLL_SYS {
+    fn read_fbdiv(&self) -> u16 {
+        self.fbdiv_int().read().fbdiv_int().bits()
+    }
+
+    fn write_fbdiv(&mut self, fbdiv: u16) {
+        self.fbdiv_int()
+            .write(|w| unsafe { w.fbdiv_int().bits(fbdiv) });
+    }
+}
+
+/// How a fractional fbdiv is turned into the integer the register takes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dithering {
+    /// Round to the nearest fbdiv.
+    None,
+    /// First-order sigma-delta modulation: alternate between the two nearest fbdivs such that the
+    /// average over the runs follows the fractional fbdiv.
+    FirstOrder,
+    /// Second-order sigma-delta modulation, which moves the dithering noise to higher frequencies at
+    /// the cost of jumping up to two fbdivs away from the fractional fbdiv.
+    SecondOrder,
+}
+
+/// Drives the fbdiv_int register, with the fbdiv at construction as the nominal frequency. A single
+/// step of fbdiv is about 1% of the frequency, so without dithering small offsets round to the
+/// nominal frequency. With dithering the fbdiv changes on every run, so it should be written at a
+/// steady rate, which the control interrupt does.
+pub struct FbdivActuator<P = PLL_SYS> {
+    pll_sys: P,
+    nominal_fbdiv: i32,
+    /// One fbdiv step in ppm.
+    resolution: I16F16,
+    dithering: Dithering,
+    /// Quantization errors of the last two runs, in fbdiv steps.
+    errors: [I16F16; 2],
+}
+
+impl<P: PllFbdiv> FbdivActuator<P> {
+    pub fn new(pll_sys: P) -> Self {
+        let nominal_fbdiv = pll_sys.read_fbdiv() as i32;
 
         Self {
-            degree,
             pll_sys,
-            fbdiv_internal: I16F16::from_num(initial_fbdiv),
-            pid: PidControl::new(pid_settings),
-            debug: Vec::new(),
-            i: 0,
+            nominal_fbdiv,
+            resolution: I16F16::saturating_from_num(1_000_000 / nominal_fbdiv.max(1)),
+            dithering: Dithering::None,
+            errors: [I16F16::ZERO; 2],
         }
     }
 
-    pub fn read_fbdiv(&self) -> u16 {
-        self.pll_sys.fbdiv_int().read().fbdiv_int().bits()
+    pub fn with_dithering(mut self, dithering: Dithering) -> Self {
+        self.set_dithering(dithering);
+        self
     }
 
-    pub fn write_fbdiv(&mut self, new_fbdiv: i32) {
-        let new_fbdiv = if !FBDIV_RANGE.contains(&new_fbdiv) {
-            new_fbdiv.clamp(FBDIV_RANGE.min().unwrap(), FBDIV_RANGE.max().unwrap())
-        } else {
-            new_fbdiv
-        } as u16;
+    pub fn set_dithering(&mut self, dithering: Dithering) {
+        self.dithering = dithering;
+        self.errors = [I16F16::ZERO; 2];
+    }
 
-        self.pll_sys
-            .fbdiv_int()
-            .write(|w| unsafe { w.fbdiv_int().bits(new_fbdiv) });
+    /// Turns the fractional fbdiv into the next integer fbdiv and keeps track of the quantization error.
+    fn modulate(&mut self, fbdiv: I16F16) -> i32 {
+        let [previous, before_previous] = self.errors;
+
+        // Feeds back earlier errors such that the error in the output is shaped by (1 - z^-1)^order.
+        let target = match self.dithering {
+            Dithering::None => fbdiv,
+            Dithering::FirstOrder => fbdiv.saturating_sub(previous),
+            Dithering::SecondOrder => fbdiv
+                .saturating_sub(previous.saturating_mul_int(2))
+                .saturating_add(before_previous),
+        };
+
+        let output = target
+            .round()
+            .to_num::<i32>()
+            .clamp(*FBDIV_RANGE.start(), *FBDIV_RANGE.end());
+
+        // Against the end of the range the error would grow without bounds, so it is limited.
+        let error = (I16F16::from_num(output).saturating_sub(target))
+            .clamp(I16F16::from_num(-2), I16F16::from_num(2));
+
+        self.errors = match self.dithering {
+            Dithering::None => [I16F16::ZERO; 2],
+            _ => [error, previous],
+        };
+
+        output
     }
 }
 
-// const FBDIV_RANGE: RangeInclusive<u16> = 16..=320;
-const FBDIV_RANGE: RangeInclusive<i32> = 97..=103;
-
-impl<const B: usize> FrequencyController<B> for FbdivController {
+impl<P: PllFbdiv> FrequencyActuator for FbdivActuator<P> {
     type Error = ();
-    type Debug = ();
-
-    fn run(&mut self, buffer_levels: &[usize]) -> Result<(), Self::Error> {
-        self.i += 1;
-        assert!(buffer_levels.len() >= self.degree); // TODO: return Err?
-        let half_full = (self.degree * B) / 2;
-        let total_level: usize = buffer_levels.iter().sum();
-
-        if self.i % 32768 == 0 {
-            defmt::info!(
-                "{} buffer_levels={}->{} fbdiv={} (={})",
-                self.i,
-                buffer_levels,
-                half_full,
-                self.fbdiv_internal.to_num::<f32>(),
-                self.pll_sys.fbdiv_int().read().fbdiv_int().bits(),
-            );
-
-            // for (i, (dbg, _)) in self.debug.iter().enumerate() {
-            //     defmt::info!("{} = {}", i, dbg);
-            // }
-        }
 
-        let adjust = self
-            .pid
-            .run(I16F16::from_num(half_full), I16F16::from_num(total_level))
-            .ok_or(())?;
+    fn range(&self) -> RangeInclusive<I16F16> {
+        let steps = |fbdiv: i32| I16F16::saturating_from_num(fbdiv - self.nominal_fbdiv);
 
-        self.fbdiv_internal = self.fbdiv_internal.saturating_sub(adjust);
-
-        self.fbdiv_internal = self.fbdiv_internal.clamp(
-            I16F16::from_num(FBDIV_RANGE.min().unwrap()),
-            I16F16::from_num(FBDIV_RANGE.max().unwrap()),
-        );
+        self.resolution.saturating_mul(steps(*FBDIV_RANGE.start()))
+            ..=self.resolution.saturating_mul(steps(*FBDIV_RANGE.end()))
+    }
 
-        if self.fbdiv_internal == I16F16::from_num(FBDIV_RANGE.min().unwrap()) {
-            self.fbdiv_internal = I16F16::from_num(100);
-        }
+    fn resolution(&self) -> I16F16 {
+        self.resolution
+    }
 
-        if !self.debug.is_full() {
-            self.debug.push((total_level, self.fbdiv_internal)).unwrap();
-        }
+    /// The PLL relocks to a new fbdiv within tens of microseconds.
+    fn apply_latency_us(&self) -> u32 {
+        100
+    }
 
-        let fbdiv_int = self.fbdiv_internal.round().int().to_bits() >> 16;
+    fn set_offset_ppm(&mut self, offset: I16F16) -> Result<(), Self::Error> {
+        let fbdiv_offset = self.nominal_fbdiv as i64 * offset.to_bits() as i64 / 1_000_000;
+        let fbdiv = self.modulate(I16F16::from_num(self.nominal_fbdiv).saturating_add(
+            I16F16::from_bits(fbdiv_offset.clamp(i32::MIN as i64, i32::MAX as i64) as i32),
+        ));
 
-        self.write_fbdiv(fbdiv_int);
+        self.pll_sys.write_fbdiv(fbdiv as u16);
 
         Ok(())
     }
 
-    fn set_degree(&mut self, new_degree: usize) {
-        self.degree = new_degree
+    fn setting(&self) -> u32 {
+        self.pll_sys.read_fbdiv() as u32
     }
+}
 
-    fn debug(&self) -> Self::Debug {}
+/// The original fbdiv controller: a PID on the total buffer deviation, whose output is added to
+/// fbdiv. The integral stops while fbdiv is at the end of its range. The fbdiv_int register must be
+/// of a value between 16 and 320, and a higher value results in a higher system clock frequency.
+pub type FbdivController<P = PLL_SYS, const DEGREE: usize = 4> =
+    Controller<Integrating<PidLaw>, FbdivActuator<P>, DEGREE>;
+
+impl<P: PllFbdiv, const DEGREE: usize> FbdivController<P, DEGREE> {
+    pub fn with_pid(pll_sys: P, settings: PidSettings) -> Self {
+        let actuator = FbdivActuator::new(pll_sys);
+
+        Controller::new(
+            Integrating::new(
+                PidLaw::from_control(
+                    PidControl::new(settings).with_anti_windup(AntiWindup::Clamping),
+                ),
+                actuator.resolution(),
+            )
+            .with_recentering(),
+            actuator,
+        )
+    }
+
+    pub fn with_dithering(mut self, dithering: Dithering) -> Self {
+        self.actuator_mut().set_dithering(dithering);
+        self
+    }
 }
+
+// const FBDIV_RANGE: RangeInclusive<u16> = 16..=320;
+const FBDIV_RANGE: RangeInclusive<i32> = 97..=103;
@@ -0,0 +1,260 @@
+use core::ops::RangeInclusive;
+
+use fixed::types::{I16F16, I32F32};
+
+use crate::law::ControlLaw;
+
+/// The rate is kept in words per 2^16 runs, so the tiny change in level per run keeps its precision.
+const RATE_SHIFT: u32 = 16;
+
+/// Noise variances of the model the filter assumes for every link: the level moves with the rate, and
+/// the rate wanders as the frequencies of both nodes change.
+#[derive(Debug, Clone, Copy)]
+pub struct KalmanSettings {
+    /// The filter steps once every 2^`interval_shift` runs on the average level over those runs. At a
+    /// few ppm the level changes a word only every so many runs, and updates at every run would need
+    /// gains too small for fixed point. At most 16, a step of 2^16 runs, larger shifts are taken as 16.
+    pub interval_shift: u32,
+    /// Variance added to the level every step, in words squared.
+    pub level_noise: I32F32,
+    /// Variance added to the rate every step, in (words per 2^16 runs) squared.
+    pub rate_noise: I32F32,
+    /// Variance of a buffer level measurement in words squared. Levels are whole words, which alone
+    /// gives 1/12.
+    pub measurement_noise: I32F32,
+}
+
+impl Default for KalmanSettings {
+    fn default() -> Self {
+        Self {
+            interval_shift: 10,
+            level_noise: I32F32::ZERO,
+            rate_noise: I32F32::from_bits(1 << 12),
+            measurement_noise: I32F32::lit("0.0833"),
+        }
+    }
+}
+
+/// Estimated state of a single link.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NeighborEstimate {
+    /// Deviation of the buffer level from its midpoint in words, without the quantization.
+    pub level: I16F16,
+    /// How much faster the neighbor runs than this node, in ppm.
+    pub offset_ppm: I16F16,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct LinkFilter {
+    level: I32F32,
+    rate: I32F32,
+    /// Covariance of level and rate: `[level, cross, rate]`.
+    covariance: [I32F32; 3],
+    started: bool,
+    /// Sum and number of the measurements since the last step.
+    sum: I32F32,
+    count: u32,
+}
+
+impl LinkFilter {
+    const fn new() -> Self {
+        Self {
+            level: I32F32::ZERO,
+            rate: I32F32::ZERO,
+            covariance: [I32F32::ZERO; 3],
+            started: false,
+            sum: I32F32::ZERO,
+            count: 0,
+        }
+    }
+
+    fn predict(&mut self, settings: &KalmanSettings) {
+        let [level, cross, rate] = self.covariance;
+        let step = |x: I32F32| x >> (RATE_SHIFT - settings.interval_shift);
+
+        self.level = self.level.saturating_add(step(self.rate));
+        self.covariance = [
+            level
+                .saturating_add(step(cross).saturating_mul_int(2))
+                .saturating_add(step(step(rate)))
+                .saturating_add(settings.level_noise),
+            cross.saturating_add(step(rate)),
+            rate.saturating_add(settings.rate_noise),
+        ];
+    }
+
+    fn update(&mut self, measurement: I32F32, settings: &KalmanSettings) {
+        let [level, cross, rate] = self.covariance;
+        let innovation = measurement.saturating_sub(self.level);
+        let variance = level.saturating_add(settings.measurement_noise);
+
+        let Some((level_gain, rate_gain)) =
+            level.checked_div(variance).zip(cross.checked_div(variance))
+        else {
+            return;
+        };
+
+        self.level = self
+            .level
+            .saturating_add(level_gain.saturating_mul(innovation));
+        self.rate = self
+            .rate
+            .saturating_add(rate_gain.saturating_mul(innovation));
+        self.covariance = [
+            level.saturating_sub(level_gain.saturating_mul(level)),
+            cross.saturating_sub(level_gain.saturating_mul(cross)),
+            rate.saturating_sub(rate_gain.saturating_mul(cross)),
+        ];
+    }
+
+    fn estimate(&self) -> NeighborEstimate {
+        NeighborEstimate {
+            level: self.level.saturating_to_num(),
+            offset_ppm: (self.rate.saturating_mul_int(1_000_000) >> RATE_SHIFT).saturating_to_num(),
+        }
+    }
+}
+
+/// Estimates the level and rate of change of every link's buffer with a Kalman filter per link. The
+/// rate is the frequency offset of the neighbor relative to this node, which the buffer level only
+/// shows after it has drifted a whole word.
+pub struct NeighborEstimator<const DEGREE: usize> {
+    settings: KalmanSettings,
+    links: [LinkFilter; DEGREE],
+}
+
+impl<const DEGREE: usize> NeighborEstimator<DEGREE> {
+    pub fn new(settings: KalmanSettings) -> Self {
+        Self {
+            settings: KalmanSettings {
+                interval_shift: settings.interval_shift.min(RATE_SHIFT),
+                ..settings
+            },
+            links: [LinkFilter::new(); DEGREE],
+        }
+    }
+
+    /// Adds the deviations of a run, and steps the filter of every link that has been measured for a
+    /// whole interval. Links without a deviation keep their estimate, a link starts from its first
+    /// interval with the rate unknown.
+    pub fn update(&mut self, deviations: &[Option<I16F16>; DEGREE]) {
+        for (link, deviation) in self.links.iter_mut().zip(deviations) {
+            let Some(deviation) = deviation else {
+                continue;
+            };
+
+            link.sum = link.sum.saturating_add(I32F32::from_num(*deviation));
+            link.count += 1;
+
+            if link.count < 1 << self.settings.interval_shift {
+                continue;
+            }
+
+            let measurement = link.sum >> self.settings.interval_shift;
+            link.sum = I32F32::ZERO;
+            link.count = 0;
+
+            if !link.started {
+                *link = LinkFilter {
+                    level: measurement,
+                    rate: I32F32::ZERO,
+                    // Anything up to a few hundred ppm, which is a rate of tens of words per 2^16 runs.
+                    covariance: [
+                        self.settings.measurement_noise,
+                        I32F32::ZERO,
+                        I32F32::from_num(100),
+                    ],
+                    started: true,
+                    ..LinkFilter::new()
+                };
+                continue;
+            }
+
+            link.predict(&self.settings);
+            link.update(measurement, &self.settings);
+        }
+    }
+
+    /// Estimates of all links, `None` for links that have not been measured yet.
+    pub fn estimates(&self) -> [Option<NeighborEstimate>; DEGREE] {
+        self.links.map(|link| link.started.then(|| link.estimate()))
+    }
+
+    pub fn reset(&mut self) {
+        self.links = [LinkFilter::new(); DEGREE];
+    }
+}
+
+/// Corrects on the estimated frequency offset of the neighbors as well as on the estimated buffer
+/// level. The rate term damps the loop without the delay of waiting for whole words of drift.
+pub struct RateLaw<const DEGREE: usize> {
+    /// Correction in ppm per word of estimated buffer deviation.
+    kp: I16F16,
+    /// Correction in ppm per ppm of estimated neighbor offset.
+    kr: I16F16,
+    estimator: NeighborEstimator<DEGREE>,
+}
+
+impl<const DEGREE: usize> RateLaw<DEGREE> {
+    pub fn new(kp: I16F16, kr: I16F16) -> Self {
+        Self {
+            kp,
+            kr,
+            estimator: NeighborEstimator::new(KalmanSettings::default()),
+        }
+    }
+
+    pub fn with_settings(mut self, settings: KalmanSettings) -> Self {
+        self.estimator = NeighborEstimator::new(settings);
+        self
+    }
+
+    pub fn estimator(&self) -> &NeighborEstimator<DEGREE> {
+        &self.estimator
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RateDebug<const DEGREE: usize> {
+    pub estimates: [Option<NeighborEstimate>; DEGREE],
+}
+
+impl<const DEGREE: usize> Default for RateDebug<DEGREE> {
+    fn default() -> Self {
+        Self {
+            estimates: [None; DEGREE],
+        }
+    }
+}
+
+impl<const DEGREE: usize> ControlLaw<DEGREE> for RateLaw<DEGREE> {
+    type Debug = RateDebug<DEGREE>;
+
+    fn correction(
+        &mut self,
+        deviations: &[Option<I16F16>; DEGREE],
+        _range: &RangeInclusive<I16F16>,
+    ) -> Option<I16F16> {
+        self.estimator.update(deviations);
+
+        // Masked links keep being predicted, but only the enabled ones count.
+        let correction = self
+            .estimator
+            .estimates()
+            .iter()
+            .zip(deviations)
+            .filter_map(|(estimate, deviation)| deviation.and(*estimate))
+            .fold(I16F16::ZERO, |sum, estimate| {
+                sum.saturating_add(self.kp.saturating_mul(estimate.level))
+                    .saturating_add(self.kr.saturating_mul(estimate.offset_ppm))
+            });
+
+        Some(correction)
+    }
+
+    fn debug(&self) -> Self::Debug {
+        RateDebug {
+            estimates: self.estimator.estimates(),
+        }
+    }
+}
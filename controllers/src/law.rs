@@ -0,0 +1,234 @@
+use core::ops::RangeInclusive;
+
+use fixed::types::I16F16;
+
+use crate::pid::PidSettings;
+
+/// Computes a frequency correction from the buffer levels, independent of what actuator applies it.
+pub trait ControlLaw<const DEGREE: usize> {
+    type Debug;
+
+    /// Takes the deviation in words of every link's buffer level from its midpoint, `None` for links
+    /// that are masked, and returns the frequency correction in ppm. A buffer above its midpoint means the
+    /// neighbor runs faster, so a positive deviation should speed this node up. `range` is the range of
+    /// the actuator, corrections outside of it are clamped. Returns `None` if the law cannot compute a
+    /// correction, e.g. on overflow.
+    fn correction(
+        &mut self,
+        deviations: &[Option<I16F16>; DEGREE],
+        range: &RangeInclusive<I16F16>,
+    ) -> Option<I16F16>;
+
+    /// Called instead of [`ControlLaw::correction`] while no link is active and the frequency is held
+    /// at `correction`, so the law can continue from there once links come back.
+    fn hold(&mut self, _correction: I16F16) {}
+
+    /// Continues from `correction` after something other than the law drove the frequency while the
+    /// links were up, e.g. a relay experiment. Unlike [`ControlLaw::hold`] this is not a loss of the
+    /// links, so a law that changes its behavior in holdover keeps that as it was.
+    fn resume(&mut self, correction: I16F16) {
+        self.hold(correction);
+    }
+
+    /// End of the range that the last correction of a law that keeps its own state within the range
+    /// was held at, if it is not visible in the correction itself. Other laws return `None`, and the
+    /// controller compares their correction with the range.
+    fn saturation(&self) -> Option<Saturation> {
+        None
+    }
+
+    /// Replaces the gains of a law that has PID gains, e.g. at runtime. Other laws ignore them.
+    fn set_gains(&mut self, _settings: PidSettings) {}
+
+    /// The gains [`ControlLaw::set_gains`] replaces as the law has them now, which may have changed
+    /// since, e.g. by auto-tuning. `None` for laws without PID gains.
+    fn gains(&self) -> Option<PidSettings> {
+        None
+    }
+
+    /// Takes gains found by an auto-tuner for a PID from the total deviation to the correction.
+    fn tune(&mut self, settings: PidSettings) {
+        self.set_gains(settings);
+    }
+
+    fn debug(&self) -> Self::Debug;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Saturation {
+    High,
+    Low,
+}
+
+/// Sum of the deviations of all links that are not masked.
+pub fn total_deviation(deviations: &[Option<I16F16>]) -> I16F16 {
+    deviations
+        .iter()
+        .flatten()
+        .fold(I16F16::ZERO, |sum, &deviation| {
+            sum.saturating_add(deviation)
+        })
+}
+
+/// Steps the frequency up or down by a fixed amount depending on the sign of the total deviation,
+/// and leaves it at nominal within the deadband. Wrap it in [`Integrating`] to get the frequency
+/// increment/decrement control of bittide hardware.
+pub struct BangBangLaw {
+    step: I16F16,
+    deadband: I16F16,
+}
+
+impl BangBangLaw {
+    pub fn new(step: I16F16, deadband: I16F16) -> Self {
+        Self { step, deadband }
+    }
+}
+
+impl<const DEGREE: usize> ControlLaw<DEGREE> for BangBangLaw {
+    type Debug = ();
+
+    fn correction(
+        &mut self,
+        deviations: &[Option<I16F16>; DEGREE],
+        _range: &RangeInclusive<I16F16>,
+    ) -> Option<I16F16> {
+        let deviation = total_deviation(deviations);
+
+        Some(if deviation > self.deadband {
+            self.step
+        } else if deviation < -self.deadband {
+            -self.step
+        } else {
+            I16F16::ZERO
+        })
+    }
+
+    fn debug(&self) -> Self::Debug {}
+}
+
+/// Adds `gain` times the output of another law to the correction every run, so that law controls the
+/// rate at which the frequency changes. The correction is kept within the range of the actuator, and the
+/// inner law gets the range of steps that stays within it.
+pub struct Integrating<L> {
+    law: L,
+    gain: I16F16,
+    correction: I16F16,
+    recenter: bool,
+    saturation: Option<Saturation>,
+}
+
+impl<L> Integrating<L> {
+    pub fn new(law: L, gain: I16F16) -> Self {
+        Self {
+            law,
+            gain,
+            correction: I16F16::ZERO,
+            recenter: false,
+            saturation: None,
+        }
+    }
+
+    /// Restart from the nominal frequency when the correction reaches the bottom of the range. When all
+    /// nodes drift down together the buffers give no reason to move back up, and the network gets stuck
+    /// at the bottom of the range without this.
+    pub fn with_recentering(mut self) -> Self {
+        self.recenter = true;
+        self
+    }
+}
+
+impl<L: ControlLaw<DEGREE>, const DEGREE: usize> ControlLaw<DEGREE> for Integrating<L> {
+    type Debug = L::Debug;
+
+    fn correction(
+        &mut self,
+        deviations: &[Option<I16F16>; DEGREE],
+        range: &RangeInclusive<I16F16>,
+    ) -> Option<I16F16> {
+        // The steps that keep the correction within the range of the actuator, so the inner law
+        // knows when its output no longer has an effect.
+        let step_limit = |bound: I16F16| {
+            bound
+                .saturating_sub(self.correction)
+                .saturating_div(self.gain)
+        };
+        let step_range = if self.gain == 0 {
+            I16F16::MIN..=I16F16::MAX
+        } else if self.gain < 0 {
+            step_limit(*range.end())..=step_limit(*range.start())
+        } else {
+            step_limit(*range.start())..=step_limit(*range.end())
+        };
+
+        let step = self.law.correction(deviations, &step_range)?;
+
+        self.correction = self
+            .correction
+            .saturating_add(self.gain.saturating_mul(step))
+            .clamp(*range.start(), *range.end());
+
+        self.saturation = if self.correction == *range.end() {
+            Some(Saturation::High)
+        } else if self.correction == *range.start() {
+            Some(Saturation::Low)
+        } else {
+            None
+        };
+
+        if self.recenter && self.correction == *range.start() {
+            self.correction = I16F16::ZERO;
+        }
+
+        Some(self.correction)
+    }
+
+    /// Reports the bottom of the range in the run that the correction restarts from the nominal
+    /// frequency.
+    fn saturation(&self) -> Option<Saturation> {
+        self.saturation
+    }
+
+    /// The inner law holds at a step of zero.
+    fn hold(&mut self, correction: I16F16) {
+        self.correction = correction;
+        self.saturation = None;
+        self.law.hold(I16F16::ZERO);
+    }
+
+    fn resume(&mut self, correction: I16F16) {
+        self.correction = correction;
+        self.saturation = None;
+        self.law.resume(I16F16::ZERO);
+    }
+
+    /// The gains of the inner law, which sets the steps of the correction.
+    fn set_gains(&mut self, settings: PidSettings) {
+        self.law.set_gains(settings);
+    }
+
+    fn gains(&self) -> Option<PidSettings> {
+        self.law.gains()
+    }
+
+    /// The correction is the integral of the output of the inner law, so the proportional gain of
+    /// `settings` becomes the derivative gain of the inner law and the integral gain its proportional
+    /// gain. A derivative in `settings` has no counterpart.
+    fn tune(&mut self, settings: PidSettings) {
+        let inner = settings
+            .ki
+            .checked_div(self.gain)
+            .zip(settings.kp.checked_div(self.gain));
+
+        if let Some((kp, kd)) = inner {
+            self.law.set_gains(PidSettings {
+                kp,
+                ki: I16F16::ZERO,
+                kd,
+            });
+        }
+    }
+
+    fn debug(&self) -> Self::Debug {
+        self.law.debug()
+    }
+}
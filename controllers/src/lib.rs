@@ -1,9 +1,22 @@
 #![no_std]
+pub mod actuator;
+pub mod autotune;
+pub mod calibration;
 pub mod controller;
 pub mod fbdiv;
+pub mod kalman;
+pub mod law;
+pub mod parameters;
 pub mod pid;
+pub mod proportional;
+#[cfg(feature = "sim")]
+pub mod replay;
+pub mod schedule;
 pub mod si5351;
+pub mod si5351_i2c;
+#[cfg(feature = "sim")]
+pub mod sim;
 
-#[cfg(test)]
+#[cfg(any(test, feature = "sim"))]
 #[macro_use]
 extern crate std;
@@ -0,0 +1,324 @@
+//! Controller parameters that can change while the node runs. A [`ParameterConsole`] takes text commands
+//! from the host, e.g. over an RTT down channel, and publishes a complete set of parameters to a
+//! [`ParameterStore`]. The control interrupt applies a published set between two runs, so a run never
+//! sees half of an update.
+//!
+//! Commands are single lines:
+//! - `set <name> <value>` stages a value,
+//! - `apply` publishes the staged values,
+//! - `discard` drops the staged values,
+//! - `get` and `get <name>` read back the applied values, which the controller runs with from its
+//!   next run on.
+//!
+//! With a gain schedule `kp`, `ki` and `kd` are the gains of the tracking regime, the gains of the
+//! other regimes stay as they were set up.
+
+use core::{cell::RefCell, fmt::Write};
+
+use critical_section::Mutex;
+use fixed::types::I16F16;
+use heapless::{String, Vec};
+
+use crate::pid::PidSettings;
+
+/// Longest command line, longer lines are dropped.
+const LINE_LENGTH: usize = 64;
+/// Replies to the commands of a single `receive`, later replies are cut off.
+pub const REPLY_LENGTH: usize = 256;
+
+/// Everything that can be changed at runtime.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ControllerParameters {
+    /// The gains of the law, with a gain schedule those of the tracking regime.
+    pub gains: PidSettings,
+    /// Words from the midpoint of every buffer that the controller steers towards.
+    pub setpoint: I16F16,
+    /// Corrections in ppm are kept within these, on top of the range of the actuator.
+    pub clamp_min: I16F16,
+    pub clamp_max: I16F16,
+    /// Reads without a message after which a link counts as inactive.
+    pub no_message_limit: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Parameter {
+    Kp,
+    Ki,
+    Kd,
+    Setpoint,
+    ClampMin,
+    ClampMax,
+    NoMessageLimit,
+}
+
+impl Parameter {
+    pub const ALL: [Parameter; 7] = [
+        Parameter::Kp,
+        Parameter::Ki,
+        Parameter::Kd,
+        Parameter::Setpoint,
+        Parameter::ClampMin,
+        Parameter::ClampMax,
+        Parameter::NoMessageLimit,
+    ];
+
+    /// The name of the parameter in commands.
+    pub fn name(self) -> &'static str {
+        match self {
+            Parameter::Kp => "kp",
+            Parameter::Ki => "ki",
+            Parameter::Kd => "kd",
+            Parameter::Setpoint => "setpoint",
+            Parameter::ClampMin => "clamp_min",
+            Parameter::ClampMax => "clamp_max",
+            Parameter::NoMessageLimit => "no_message_limit",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|parameter| parameter.name() == name)
+    }
+}
+
+impl ControllerParameters {
+    pub fn get(&self, parameter: Parameter) -> I16F16 {
+        match parameter {
+            Parameter::Kp => self.gains.kp,
+            Parameter::Ki => self.gains.ki,
+            Parameter::Kd => self.gains.kd,
+            Parameter::Setpoint => self.setpoint,
+            Parameter::ClampMin => self.clamp_min,
+            Parameter::ClampMax => self.clamp_max,
+            Parameter::NoMessageLimit => I16F16::saturating_from_num(self.no_message_limit),
+        }
+    }
+
+    /// The message limit is rounded down to whole reads, and is at least one.
+    pub fn set(&mut self, parameter: Parameter, value: I16F16) {
+        match parameter {
+            Parameter::Kp => self.gains.kp = value,
+            Parameter::Ki => self.gains.ki = value,
+            Parameter::Kd => self.gains.kd = value,
+            Parameter::Setpoint => self.setpoint = value,
+            Parameter::ClampMin => self.clamp_min = value,
+            Parameter::ClampMax => self.clamp_max = value,
+            Parameter::NoMessageLimit => {
+                self.no_message_limit = value.saturating_to_num::<u16>().max(1)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum ParameterError {
+    UnknownCommand,
+    UnknownParameter,
+    InvalidValue,
+    NothingStaged,
+    /// `clamp_min` is above `clamp_max`.
+    EmptyClampRange,
+    /// The store holds no parameters yet.
+    Uninitialized,
+}
+
+struct StoreState {
+    active: Option<ControllerParameters>,
+    pending: Option<ControllerParameters>,
+}
+
+/// Hands parameters from the main loop to the control interrupt, meant to live in a static.
+pub struct ParameterStore {
+    state: Mutex<RefCell<StoreState>>,
+}
+
+impl Default for ParameterStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ParameterStore {
+    pub const fn new() -> Self {
+        Self {
+            state: Mutex::new(RefCell::new(StoreState {
+                active: None,
+                pending: None,
+            })),
+        }
+    }
+
+    /// The parameters the controller starts with.
+    pub fn init(&self, parameters: ControllerParameters) {
+        critical_section::with(|cs| self.state.borrow_ref_mut(cs).active = Some(parameters));
+    }
+
+    /// The parameters the controller runs with, `None` before `init`.
+    pub fn active(&self) -> Option<ControllerParameters> {
+        critical_section::with(|cs| self.state.borrow_ref(cs).active)
+    }
+
+    /// The parameters the controller runs with after the next [`apply_pending`](Self::apply_pending):
+    /// the published ones if there are any, the active ones otherwise.
+    pub fn latest(&self) -> Option<ControllerParameters> {
+        critical_section::with(|cs| {
+            let state = self.state.borrow_ref(cs);
+
+            state.pending.or(state.active)
+        })
+    }
+
+    /// Replaces any parameters that were published but not yet applied.
+    pub fn publish(&self, parameters: ControllerParameters) {
+        critical_section::with(|cs| self.state.borrow_ref_mut(cs).pending = Some(parameters));
+    }
+
+    /// To be called between two runs of the controller, with the `gains` it runs with: these become
+    /// the active gains, as they can change without the console, e.g. by auto-tuning. Then passes
+    /// published parameters to `apply`, after which they are the active ones. Does nothing else if
+    /// nothing was published.
+    pub fn apply_pending(
+        &self,
+        gains: Option<PidSettings>,
+        apply: impl FnOnce(&ControllerParameters),
+    ) {
+        critical_section::with(|cs| {
+            let mut state = self.state.borrow_ref_mut(cs);
+
+            if let (Some(active), Some(gains)) = (&mut state.active, gains) {
+                active.gains = gains;
+            }
+
+            if let Some(parameters) = state.pending.take() {
+                apply(&parameters);
+                state.active = Some(parameters);
+            }
+        });
+    }
+}
+
+/// Parses the commands of the host and keeps the staged parameters until they are applied.
+pub struct ParameterConsole {
+    line: Vec<u8, LINE_LENGTH>,
+    /// Set while the current line is too long, until its end.
+    overflow: bool,
+    staged: Option<ControllerParameters>,
+}
+
+impl Default for ParameterConsole {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ParameterConsole {
+    pub const fn new() -> Self {
+        Self {
+            line: Vec::new(),
+            overflow: false,
+            staged: None,
+        }
+    }
+
+    /// Takes the bytes the host sent, which may hold any part of a line, and runs the commands that
+    /// are complete. Returns a reply line per command.
+    pub fn receive(&mut self, bytes: &[u8], store: &ParameterStore) -> String<REPLY_LENGTH> {
+        let mut reply = String::new();
+
+        for &byte in bytes {
+            match byte {
+                b'\n' | b'\r' => {
+                    if !self.overflow && !self.line.is_empty() {
+                        let line = core::mem::take(&mut self.line);
+                        let command = core::str::from_utf8(&line).unwrap_or("");
+
+                        if let Err(error) = self.command(command, store, &mut reply) {
+                            writeln!(reply, "error: {:?}", error).ok();
+                        }
+                    }
+
+                    self.line.clear();
+                    self.overflow = false;
+                }
+                _ => {
+                    if self.line.push(byte).is_err() {
+                        self.overflow = true;
+                    }
+                }
+            }
+        }
+
+        reply
+    }
+
+    fn command(
+        &mut self,
+        command: &str,
+        store: &ParameterStore,
+        reply: &mut String<REPLY_LENGTH>,
+    ) -> Result<(), ParameterError> {
+        let mut words = command.split_whitespace();
+        let parameter = |name: Option<&str>| {
+            name.and_then(Parameter::from_name)
+                .ok_or(ParameterError::UnknownParameter)
+        };
+
+        match words.next() {
+            Some("set") => {
+                let parameter = parameter(words.next())?;
+                let value = words
+                    .next()
+                    .and_then(|value| value.parse::<I16F16>().ok())
+                    .ok_or(ParameterError::InvalidValue)?;
+                let mut staged = match self.staged {
+                    Some(staged) => staged,
+                    None => store.latest().ok_or(ParameterError::Uninitialized)?,
+                };
+
+                staged.set(parameter, value);
+                self.staged = Some(staged);
+                writeln!(
+                    reply,
+                    "staged {}={}",
+                    parameter.name(),
+                    staged.get(parameter)
+                )
+                .ok();
+            }
+            Some("apply") => {
+                let staged = self.staged.ok_or(ParameterError::NothingStaged)?;
+
+                if staged.clamp_min > staged.clamp_max {
+                    return Err(ParameterError::EmptyClampRange);
+                }
+
+                store.publish(staged);
+                self.staged = None;
+                writeln!(reply, "applied").ok();
+            }
+            Some("discard") => {
+                self.staged = None;
+                writeln!(reply, "discarded").ok();
+            }
+            Some("get") => {
+                let latest = store.latest().ok_or(ParameterError::Uninitialized)?;
+
+                match words.next() {
+                    Some(name) => {
+                        let parameter = parameter(Some(name))?;
+                        writeln!(reply, "{}={}", parameter.name(), latest.get(parameter)).ok();
+                    }
+                    None => {
+                        for parameter in Parameter::ALL {
+                            writeln!(reply, "{}={}", parameter.name(), latest.get(parameter)).ok();
+                        }
+                    }
+                }
+            }
+            _ => return Err(ParameterError::UnknownCommand),
+        }
+
+        Ok(())
+    }
+}
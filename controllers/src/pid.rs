@@ -1,42 +1,321 @@
+use core::ops::RangeInclusive;
+
 use fixed::types::I16F16;
 
+use crate::{
+    law::{total_deviation, ControlLaw},
+    schedule::{GainSchedule, Regime, Scheduler},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct PidSettings {
     pub kp: I16F16,
     pub ki: I16F16,
     pub kd: I16F16,
 }
 
+/// How the integral is kept from winding up while the output is limited.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AntiWindup {
+    /// Integrate regardless of the output limits.
+    None,
+    /// Stop integrating while the output is limited and the error would push it further out.
+    Clamping,
+    /// Feed back `gain` times the part of the output that was cut off by the limits into the integral.
+    BackCalculation { gain: I16F16 },
+}
+
 pub struct PidControl {
     k: PidSettings,
-    previous_error: I16F16,
+    anti_windup: AntiWindup,
+    /// Weight of the setpoint in the proportional and derivative terms, 1 acts on the error,
+    /// 0 only on the measurement.
+    proportional_setpoint_weight: I16F16,
+    derivative_setpoint_weight: I16F16,
+    /// Weight of a new derivative in the first-order filter on the derivative term, 1 disables the filter.
+    derivative_filter: I16F16,
+    previous_derivative_input: I16F16,
+    derivative: I16F16,
     integral: I16F16,
+    /// Input of the proportional term and output of the last run, for bumpless changes of the gains.
+    proportional_input: I16F16,
+    output: I16F16,
 }
 
 impl PidControl {
     pub fn new(k: PidSettings) -> Self {
         Self {
             k,
-            previous_error: I16F16::default(),
+            anti_windup: AntiWindup::None,
+            proportional_setpoint_weight: I16F16::ONE,
+            derivative_setpoint_weight: I16F16::ONE,
+            derivative_filter: I16F16::ONE,
+            previous_derivative_input: I16F16::default(),
+            derivative: I16F16::default(),
             integral: I16F16::default(),
+            proportional_input: I16F16::default(),
+            output: I16F16::default(),
+        }
+    }
+
+    pub fn settings(&self) -> &PidSettings {
+        &self.k
+    }
+
+    /// Changes the gains without a jump in the output: the integral is recomputed such that the new
+    /// gains give the output of the last run for the input of the last run. Without an integral gain
+    /// there is no integral to absorb the difference, and the output jumps.
+    pub fn set_settings(&mut self, k: PidSettings) {
+        let integral = self
+            .output
+            .saturating_sub(k.kp.saturating_mul(self.proportional_input))
+            .saturating_sub(k.kd.saturating_mul(self.derivative))
+            .checked_div(k.ki);
+
+        if let Some(integral) = integral {
+            self.integral = integral;
         }
+
+        self.k = k;
+    }
+
+    /// Sets the output of the last run to `output` with the derivative at rest, and the integral such
+    /// that the next run continues from it, e.g. after the output was held while the loop was open.
+    pub fn hold(&mut self, output: I16F16) {
+        self.derivative = I16F16::ZERO;
+        self.output = output;
+        self.set_settings(self.k);
+    }
+
+    pub fn with_anti_windup(mut self, anti_windup: AntiWindup) -> Self {
+        self.anti_windup = anti_windup;
+        self
+    }
+
+    /// Filters the derivative as `d += alpha * (new_d - d)`, with `alpha` between 0 and 1.
+    pub fn with_derivative_filter(mut self, alpha: I16F16) -> Self {
+        self.derivative_filter = alpha.clamp(I16F16::ZERO, I16F16::ONE);
+        self
+    }
+
+    /// Weights of the setpoint in the proportional and derivative terms. Lower weights make the
+    /// controller react less violently to setpoint changes, without changing how it rejects disturbances.
+    pub fn with_setpoint_weights(mut self, proportional: I16F16, derivative: I16F16) -> Self {
+        self.proportional_setpoint_weight = proportional;
+        self.derivative_setpoint_weight = derivative;
+        self
+    }
+
+    /// Forgets the integral and derivative history, e.g. after the loop was opened for a while.
+    pub fn reset(&mut self) {
+        self.previous_derivative_input = I16F16::default();
+        self.derivative = I16F16::default();
+        self.integral = I16F16::default();
+        self.proportional_input = I16F16::default();
+        self.output = I16F16::default();
     }
 
     pub fn run(&mut self, setpoint: I16F16, measurement: I16F16) -> Option<I16F16> {
+        self.run_limited(setpoint, measurement, &(I16F16::MIN..=I16F16::MAX))
+    }
+
+    /// Runs the PID with the output clamped to `limits`, which the anti-windup takes into account.
+    pub fn run_limited(
+        &mut self,
+        setpoint: I16F16,
+        measurement: I16F16,
+        limits: &RangeInclusive<I16F16>,
+    ) -> Option<I16F16> {
         let error = setpoint.checked_sub(measurement)?;
 
-        self.integral = self.integral.saturating_add(error);
-        let derivative = error.saturating_sub(self.previous_error);
+        let proportional_input = self
+            .proportional_setpoint_weight
+            .saturating_mul(setpoint)
+            .checked_sub(measurement)?;
+        let derivative_input = self
+            .derivative_setpoint_weight
+            .saturating_mul(setpoint)
+            .checked_sub(measurement)?;
+
+        let new_derivative = derivative_input.saturating_sub(self.previous_derivative_input);
+        self.derivative = self.derivative.saturating_add(
+            self.derivative_filter
+                .saturating_mul(new_derivative.saturating_sub(self.derivative)),
+        );
+        self.previous_derivative_input = derivative_input;
+        self.proportional_input = proportional_input;
+
+        let proportional = self.k.kp.saturating_mul(proportional_input);
+        let derivative = self.k.kd.saturating_mul(self.derivative);
+        let output_with = |integral: I16F16| {
+            proportional
+                .saturating_add(self.k.ki.saturating_mul(integral))
+                .saturating_add(derivative)
+        };
+
+        let integral = self.integral.saturating_add(error);
+        let output = output_with(integral);
+        let limited = output.clamp(*limits.start(), *limits.end());
+
+        self.integral = match self.anti_windup {
+            AntiWindup::None => integral,
+            AntiWindup::Clamping => {
+                let integration = self.k.ki.saturating_mul(error);
 
-        let proportional = self.k.kp.saturating_mul(error);
-        let integral = self.k.ki.saturating_mul(self.integral);
-        let derivative = self.k.kd.saturating_mul(derivative);
+                if (output > limited && integration > 0) || (output < limited && integration < 0) {
+                    self.integral
+                } else {
+                    integral
+                }
+            }
+            AntiWindup::BackCalculation { gain } => {
+                integral.saturating_add(gain.saturating_mul(limited.saturating_sub(output)))
+            }
+        };
+        self.output = limited;
 
-        let output = proportional
-            .saturating_add(integral)
-            .saturating_add(derivative);
+        Some(limited)
+    }
+}
 
-        self.previous_error = error;
+/// PID on the total deviation of all enabled links from their midpoints, towards `setpoint` words.
+pub struct PidLaw {
+    pid: PidControl,
+    setpoint: I16F16,
+    scheduler: Option<Scheduler>,
+    debug: PidDebug,
+}
+
+impl PidLaw {
+    pub fn new(settings: PidSettings) -> Self {
+        Self::from_control(PidControl::new(settings))
+    }
+
+    /// Creates the law from a configured PID, e.g. with anti-windup.
+    pub fn from_control(pid: PidControl) -> Self {
+        Self {
+            pid,
+            setpoint: I16F16::ZERO,
+            scheduler: None,
+            debug: Default::default(),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.pid.reset();
+    }
+
+    /// Offset in words of the total buffer level the controller steers towards.
+    pub fn with_setpoint(mut self, setpoint: I16F16) -> Self {
+        self.setpoint = setpoint;
+        self
+    }
+
+    /// Switches between the gains of `schedule`, starting with acquisition. In holdover the PID runs
+    /// as if the buffers were at the setpoint, so with a zero proportional gain and a nonzero integral
+    /// gain the output holds, and with all gains zero it goes to zero.
+    pub fn with_schedule(mut self, schedule: GainSchedule) -> Self {
+        let scheduler = Scheduler::new(schedule);
+
+        self.pid.set_settings(schedule.settings(scheduler.regime()));
+        self.scheduler = Some(scheduler);
+        self
+    }
+
+    pub fn regime(&self) -> Regime {
+        self.scheduler
+            .as_ref()
+            .map_or(Regime::Track, Scheduler::regime)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PidDebug {
+    pub error: I16F16,
+    pub output: I16F16,
+    pub regime: Regime,
+}
+
+impl<const DEGREE: usize> ControlLaw<DEGREE> for PidLaw {
+    type Debug = PidDebug;
+
+    fn correction(
+        &mut self,
+        deviations: &[Option<I16F16>; DEGREE],
+        range: &RangeInclusive<I16F16>,
+    ) -> Option<I16F16> {
+        let mut deviation = total_deviation(deviations);
+
+        if let Some(scheduler) = &mut self.scheduler {
+            let linked = deviations.iter().any(Option::is_some);
+            let error = linked.then(|| self.setpoint.saturating_sub(deviation));
+
+            if let Some(regime) = scheduler.update(error) {
+                self.pid.set_settings(scheduler.schedule().settings(regime));
+            }
+
+            if !linked {
+                deviation = self.setpoint;
+            }
+        }
+
+        // The output is the negated correction, so it is limited to the negated range.
+        let limits = range.end().saturating_neg()..=range.start().saturating_neg();
+        let output = self.pid.run_limited(self.setpoint, deviation, &limits)?;
+
+        self.debug = PidDebug {
+            error: self.setpoint.saturating_sub(deviation),
+            output,
+            regime: self.regime(),
+        };
+
+        // The error is positive when the buffers run empty, which means this node should slow down.
+        Some(output.saturating_neg())
+    }
+
+    /// With a schedule the gains become the tracking gains.
+    fn set_gains(&mut self, settings: PidSettings) {
+        if let Some(scheduler) = &mut self.scheduler {
+            scheduler.schedule_mut().track = settings;
+
+            if scheduler.regime() != Regime::Track {
+                return;
+            }
+        }
+
+        self.pid.set_settings(settings);
+    }
+
+    /// With a schedule the tracking gains.
+    fn gains(&self) -> Option<PidSettings> {
+        Some(
+            self.scheduler
+                .as_ref()
+                .map_or(*self.pid.settings(), |scheduler| scheduler.schedule().track),
+        )
+    }
+
+    fn hold(&mut self, correction: I16F16) {
+        if let Some(scheduler) = &mut self.scheduler {
+            if let Some(regime) = scheduler.update(None) {
+                self.pid.set_settings(scheduler.schedule().settings(regime));
+            }
+        }
+
+        ControlLaw::<DEGREE>::resume(self, correction);
+    }
+
+    /// Keeps the regime of the schedule.
+    fn resume(&mut self, correction: I16F16) {
+        self.pid.hold(correction.saturating_neg());
+        self.debug = PidDebug {
+            error: I16F16::ZERO,
+            output: correction.saturating_neg(),
+            regime: self.regime(),
+        };
+    }
 
-        Some(output)
+    fn debug(&self) -> Self::Debug {
+        self.debug
     }
 }
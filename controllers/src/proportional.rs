@@ -0,0 +1,61 @@
+use core::ops::RangeInclusive;
+
+use fixed::types::I16F16;
+
+use crate::law::ControlLaw;
+
+/// The bittide control law: the frequency correction is proportional to the weighted sum of the
+/// deviations of every link's buffer level from its own midpoint. Masked links are left out of the
+/// sum, regardless of the level their buffer is stuck at.
+pub struct ProportionalLaw<const DEGREE: usize> {
+    /// Correction in ppm per word of weighted buffer deviation.
+    kp: I16F16,
+    weights: [I16F16; DEGREE],
+    debug: ProportionalDebug,
+}
+
+impl<const DEGREE: usize> ProportionalLaw<DEGREE> {
+    /// Creates a law with all links equally weighted.
+    pub fn new(kp: I16F16) -> Self {
+        Self {
+            kp,
+            weights: [I16F16::ONE; DEGREE],
+            debug: Default::default(),
+        }
+    }
+
+    pub fn with_weights(mut self, weights: [I16F16; DEGREE]) -> Self {
+        self.weights = weights;
+        self
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ProportionalDebug {
+    /// Weighted sum of the buffer deviations of all enabled links, in words.
+    pub deviation: I16F16,
+}
+
+impl<const DEGREE: usize> ControlLaw<DEGREE> for ProportionalLaw<DEGREE> {
+    type Debug = ProportionalDebug;
+
+    fn correction(
+        &mut self,
+        deviations: &[Option<I16F16>; DEGREE],
+        _range: &RangeInclusive<I16F16>,
+    ) -> Option<I16F16> {
+        let deviation = deviations
+            .iter()
+            .zip(self.weights.iter())
+            .filter_map(|(deviation, &weight)| deviation.map(|d| weight.saturating_mul(d)))
+            .fold(I16F16::ZERO, |sum, deviation| sum.saturating_add(deviation));
+
+        self.debug = ProportionalDebug { deviation };
+
+        Some(self.kp.saturating_mul(deviation))
+    }
+
+    fn debug(&self) -> Self::Debug {
+        self.debug
+    }
+}
@@ -0,0 +1,315 @@
+//! Open-loop replay of buffer levels recorded on a node, to be run on the host. A candidate controller
+//! gets the recorded buffer levels run by run, and its corrections are compared with the corrections
+//! the node made.
+//!
+//! The recorded levels move with the difference between the neighbors and the node. A candidate that
+//! corrects by `d` ppm more than the node would have drained its buffers by `d * 1e-6` words per run
+//! more, so the candidate gets the recorded levels shifted by the accumulated difference. This predicts
+//! how the candidate locks, as long as the neighbors would not have reacted differently.
+//!
+//! A trace is text with one run per line: the buffer level of every link followed by the correction
+//! in ppm, separated by commas or spaces. The correction may be left out, and filled in by replaying
+//! the controller the node ran with [`record_corrections`]. Empty lines and lines starting with `#`
+//! are skipped.
+
+use std::{cell::Cell, ops::RangeInclusive, rc::Rc, vec::Vec};
+
+use fixed::types::I16F16;
+
+use crate::{actuator::FrequencyActuator, controller::FrequencyController};
+
+/// A single run of the recorded node.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceRecord {
+    pub buffer_levels: Vec<usize>,
+    /// Correction the node wrote to its actuator after this run, in ppm.
+    pub correction: Option<I16F16>,
+}
+
+/// Line numbers start at 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceError {
+    InvalidLine {
+        line: usize,
+    },
+    /// A line with neither `links` nor `links + 1` fields.
+    WrongFieldCount {
+        line: usize,
+    },
+    /// Some lines have a correction and others do not.
+    InconsistentCorrections {
+        line: usize,
+    },
+    EmptyTrace,
+}
+
+/// Parses a trace of a node with `links` links.
+pub fn parse_trace(text: &str, links: usize) -> Result<Vec<TraceRecord>, TraceError> {
+    let mut records = Vec::new();
+
+    for (index, line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|field| !field.is_empty())
+            .collect();
+
+        if fields.len() != links && fields.len() != links + 1 {
+            return Err(TraceError::WrongFieldCount { line: line_number });
+        }
+
+        let buffer_levels = fields[..links]
+            .iter()
+            .map(|field| field.parse::<usize>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| TraceError::InvalidLine { line: line_number })?;
+        let correction = fields
+            .get(links)
+            .map(|field| field.parse::<I16F16>())
+            .transpose()
+            .map_err(|_| TraceError::InvalidLine { line: line_number })?;
+
+        if records
+            .first()
+            .is_some_and(|first: &TraceRecord| first.correction.is_some() != correction.is_some())
+        {
+            return Err(TraceError::InconsistentCorrections { line: line_number });
+        }
+
+        records.push(TraceRecord {
+            buffer_levels,
+            correction,
+        });
+    }
+
+    if records.is_empty() {
+        return Err(TraceError::EmptyTrace);
+    }
+
+    Ok(records)
+}
+
+/// Stands in for the actuator of a replayed controller, and keeps the offset it was last set to for
+/// its [`ActuatorOutput`].
+pub struct ReplayActuator {
+    range: RangeInclusive<I16F16>,
+    resolution: I16F16,
+    offset: Rc<Cell<I16F16>>,
+}
+
+/// The offset last written to a [`ReplayActuator`].
+#[derive(Clone)]
+pub struct ActuatorOutput {
+    offset: Rc<Cell<I16F16>>,
+}
+
+impl ActuatorOutput {
+    pub fn get(&self) -> I16F16 {
+        self.offset.get()
+    }
+}
+
+impl ReplayActuator {
+    /// Creates an actuator with the range and resolution of `actuator`, so the replayed controller
+    /// clamps like the one on the node.
+    pub fn like<A: FrequencyActuator>(actuator: &A) -> (Self, ActuatorOutput) {
+        let offset = Rc::new(Cell::new(I16F16::ZERO));
+
+        (
+            Self {
+                range: actuator.range(),
+                resolution: actuator.resolution(),
+                offset: offset.clone(),
+            },
+            ActuatorOutput { offset },
+        )
+    }
+}
+
+impl FrequencyActuator for ReplayActuator {
+    type Error = ();
+
+    fn range(&self) -> RangeInclusive<I16F16> {
+        self.range.clone()
+    }
+
+    fn resolution(&self) -> I16F16 {
+        self.resolution
+    }
+
+    fn apply_latency_us(&self) -> u32 {
+        0
+    }
+
+    fn set_offset_ppm(&mut self, offset: I16F16) -> Result<(), Self::Error> {
+        self.offset
+            .set(offset.clamp(*self.range.start(), *self.range.end()));
+        Ok(())
+    }
+
+    fn setting(&self) -> u32 {
+        self.offset.get().to_bits() as u32
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ReplaySettings {
+    /// Difference in ppm between the corrections above which the candidate counts as diverged.
+    pub divergence_tolerance: f64,
+    /// Buffer error in words within which a predicted link counts as settled.
+    pub settling_tolerance: f64,
+    /// Fraction at the end of the trace over which the steady-state error is averaged.
+    pub steady_state_fraction: f64,
+}
+
+impl Default for ReplaySettings {
+    fn default() -> Self {
+        Self {
+            divergence_tolerance: 1.,
+            settling_tolerance: 2.,
+            steady_state_fraction: 0.1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplayError {
+    MissingCorrections,
+    /// A predicted buffer level left the buffer.
+    BufferOverflow {
+        run: usize,
+        link: usize,
+    },
+    ControllerError {
+        run: usize,
+    },
+}
+
+/// Comparison of a candidate with the recorded node. Buffer errors are the distance of the predicted
+/// buffer levels from their midpoint in words.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReplayReport {
+    pub runs: usize,
+    /// Root mean square of the difference between the corrections, in ppm.
+    pub rms_divergence: f64,
+    pub max_divergence: f64,
+    /// First run at which the corrections differ by more than the tolerance.
+    pub first_divergence: Option<usize>,
+    /// Run after which every predicted link stayed within the settling tolerance, `None` if some link
+    /// was outside of it at the end of the trace.
+    pub predicted_settling_run: Option<usize>,
+    /// Largest predicted absolute buffer error averaged over the end of the trace.
+    pub predicted_steady_state_error: f64,
+    /// Largest predicted absolute buffer error of the whole trace.
+    pub predicted_max_error: f64,
+}
+
+/// Fills in the corrections of a trace by replaying the controller the node ran with.
+pub fn record_corrections<C, const B: usize>(
+    trace: &mut [TraceRecord],
+    controller: &mut C,
+    output: &ActuatorOutput,
+) -> Result<(), ReplayError>
+where
+    C: FrequencyController<B>,
+{
+    for (run, record) in trace.iter_mut().enumerate() {
+        controller
+            .run(&record.buffer_levels)
+            .map_err(|_| ReplayError::ControllerError { run })?;
+        record.correction = Some(output.get());
+    }
+
+    Ok(())
+}
+
+/// Runs `candidate` against a trace with corrections, with buffers of size `B`.
+pub fn replay<C, const B: usize>(
+    trace: &[TraceRecord],
+    candidate: &mut C,
+    output: &ActuatorOutput,
+    settings: ReplaySettings,
+) -> Result<ReplayReport, ReplayError>
+where
+    C: FrequencyController<B>,
+{
+    let links = trace.first().map_or(0, |record| record.buffer_levels.len());
+    let steady_state_start =
+        trace.len() - (trace.len() as f64 * settings.steady_state_fraction) as usize;
+    let midpoint = (B / 2) as f64;
+
+    // Words the candidate drained from every buffer more than the node did.
+    let mut drained = 0.;
+    let mut levels = vec![0; links];
+    let mut squared_divergence = 0.;
+    let mut report = ReplayReport {
+        runs: trace.len(),
+        ..Default::default()
+    };
+    let mut last_unsettled = None;
+    let mut steady_state_sums = vec![0.; links];
+
+    for (run, record) in trace.iter().enumerate() {
+        let recorded = record
+            .correction
+            .ok_or(ReplayError::MissingCorrections)?
+            .to_num::<f64>();
+
+        for (link, (level, &recorded_level)) in
+            levels.iter_mut().zip(&record.buffer_levels).enumerate()
+        {
+            let predicted = (recorded_level as f64 - drained).round();
+
+            if !(0. ..=B as f64).contains(&predicted) {
+                return Err(ReplayError::BufferOverflow { run, link });
+            }
+
+            *level = predicted as usize;
+
+            let error = predicted - midpoint;
+            report.predicted_max_error = report.predicted_max_error.max(error.abs());
+
+            if error.abs() > settings.settling_tolerance {
+                last_unsettled = Some(run);
+            }
+
+            if run >= steady_state_start {
+                steady_state_sums[link] += error;
+            }
+        }
+
+        candidate
+            .run(&levels)
+            .map_err(|_| ReplayError::ControllerError { run })?;
+
+        let divergence = output.get().to_num::<f64>() - recorded;
+        drained += divergence * 1e-6;
+        squared_divergence += divergence * divergence;
+        report.max_divergence = report.max_divergence.max(divergence.abs());
+
+        if divergence.abs() > settings.divergence_tolerance && report.first_divergence.is_none() {
+            report.first_divergence = Some(run);
+        }
+    }
+
+    let steady_state_runs = (trace.len() - steady_state_start).max(1) as f64;
+
+    report.rms_divergence = (squared_divergence / trace.len().max(1) as f64).sqrt();
+    report.predicted_settling_run = match last_unsettled {
+        None => Some(0),
+        Some(run) if run + 1 < trace.len() => Some(run + 1),
+        Some(_) => None,
+    };
+    report.predicted_steady_state_error = steady_state_sums
+        .iter()
+        .map(|sum| (sum / steady_state_runs).abs())
+        .fold(0., f64::max);
+
+    Ok(report)
+}
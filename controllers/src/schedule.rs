@@ -0,0 +1,106 @@
+use fixed::types::I16F16;
+
+use crate::pid::PidSettings;
+
+/// The regimes of a [`GainSchedule`], each with its own gains.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Regime {
+    /// Far from the setpoint, e.g. right after start up: aggressive gains to pull the frequencies in.
+    Acquire,
+    /// Locked: gentle gains that do not oscillate around the setpoint. A law without a schedule is
+    /// always tracking.
+    #[default]
+    Track,
+    /// No link gives a buffer level, so there is nothing to steer on.
+    Holdover,
+}
+
+/// Gains per regime and when to switch between them, based on the error of the total buffer level.
+/// The error has to stay within `lock_threshold` for `lock_runs` runs in a row before acquisition
+/// turns into tracking, and tracking only goes back to acquisition beyond `unlock_threshold`, so the
+/// regimes do not flip back and forth around a single threshold.
+#[derive(Debug, Clone, Copy)]
+pub struct GainSchedule {
+    pub acquire: PidSettings,
+    pub track: PidSettings,
+    pub holdover: PidSettings,
+    /// Error in words within which the buffers count as locked.
+    pub lock_threshold: I16F16,
+    /// Error in words beyond which a locked controller acquires again.
+    pub unlock_threshold: I16F16,
+    pub lock_runs: u32,
+}
+
+impl GainSchedule {
+    pub fn settings(&self, regime: Regime) -> PidSettings {
+        match regime {
+            Regime::Acquire => self.acquire,
+            Regime::Track => self.track,
+            Regime::Holdover => self.holdover,
+        }
+    }
+}
+
+/// Follows the regime of a [`GainSchedule`] from run to run.
+#[derive(Debug, Clone, Copy)]
+pub struct Scheduler {
+    schedule: GainSchedule,
+    regime: Regime,
+    /// The regime to go back to after a holdover.
+    resume: Regime,
+    locked_runs: u32,
+}
+
+impl Scheduler {
+    /// Starts out acquiring.
+    pub fn new(schedule: GainSchedule) -> Self {
+        Self {
+            schedule,
+            regime: Regime::Acquire,
+            resume: Regime::Acquire,
+            locked_runs: 0,
+        }
+    }
+
+    pub fn schedule(&self) -> &GainSchedule {
+        &self.schedule
+    }
+
+    /// Changed gains take effect at the next change of regime.
+    pub fn schedule_mut(&mut self) -> &mut GainSchedule {
+        &mut self.schedule
+    }
+
+    pub fn regime(&self) -> Regime {
+        self.regime
+    }
+
+    /// Moves to the regime for the error of this run, `None` when no link gave a buffer level. Returns
+    /// the new regime if it changed.
+    pub fn update(&mut self, error: Option<I16F16>) -> Option<Regime> {
+        let previous = self.regime;
+        let error = error.map(|error| error.saturating_abs());
+
+        if error.is_some_and(|error| error <= self.schedule.lock_threshold) {
+            self.locked_runs = self.locked_runs.saturating_add(1);
+        } else {
+            self.locked_runs = 0;
+        }
+
+        if previous != Regime::Holdover {
+            self.resume = previous;
+        }
+
+        self.regime = match error {
+            None => Regime::Holdover,
+            Some(error) if error > self.schedule.unlock_threshold => Regime::Acquire,
+            // The frequency was held, so links that come back within the unlock threshold have not
+            // drifted far enough for another acquisition.
+            Some(_) if previous == Regime::Holdover => self.resume,
+            Some(_) if self.locked_runs >= self.schedule.lock_runs => Regime::Track,
+            Some(_) => previous,
+        };
+
+        (self.regime != previous).then_some(self.regime)
+    }
+}
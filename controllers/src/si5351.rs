@@ -1,19 +1,32 @@
+use core::ops::RangeInclusive;
+
 use embedded_hal::blocking::i2c;
 use fixed::types::I16F16;
-use rp_pico::pac::{self};
-use si5351::{Si5351Device, PLL};
+use si5351::{Multisynth, OutputDivider, Si5351Device, PLL};
 
 use crate::{
-    controller::FrequencyController,
-    pid::{PidControl, PidSettings},
+    actuator::FrequencyActuator,
+    calibration::{Calibrated, CalibrationTable},
+    controller::{Controller, ControllerDebug},
+    law::Integrating,
+    pid::{AntiWindup, PidControl, PidDebug, PidLaw, PidSettings},
+    schedule::GainSchedule,
 };
 
 // Put all hardware specific things to set a frac in an impl for this so we can mock the whole thing.
 pub trait Si5351 {
     type Error;
     fn set_pll_frac(&mut self, frac: u32) -> Result<(), Self::Error>;
+
+    /// Whether the last frac passed to `set_pll_frac` has reached the chip. Drivers that write before
+    /// returning always have.
+    fn frac_applied(&self) -> bool {
+        true
+    }
 }
 
+/// Blocks until the registers are written, which takes too long for the control interrupt on a slow bus.
+/// Use [`crate::si5351_i2c::Si5351I2c`] there instead.
 impl<I2C, E> Si5351 for Si5351Device<I2C>
 where
     I2C: i2c::WriteRead<Error = E> + i2c::Write<Error = E>,
@@ -21,98 +34,444 @@ where
     type Error = si5351::Error;
 
     fn set_pll_frac(&mut self, frac: u32) -> Result<(), Self::Error> {
-        // unsafe and a hack, can we do the ownership in a better way? only works if the SI is connected on I2C1 now...
-        unsafe {
-            let pac = pac::Peripherals::steal();
-            let fill_level = pac.I2C1.ic_txflr().read().txflr().bits();
-
-            // Cannot run I2C non-blocking
-            if fill_level > 0 {
-                return Ok(());
-            }
-        }
-
         let frac = frac & 0xfffff; // TODO: verify. error?
 
         si5351::Si5351::setup_pll(self, PLL::A, 35, frac, 0xfffff)
     }
 }
 
-pub struct Si5351Controller<SI> {
-    degree: usize,
-    si: SI,
-    pid: PidControl,
-    divider: i32,
-    debug: Si5351Debug,
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Pll {
+    A,
+    B,
+}
+
+/// A synthesizer of an Si5351 with a fractional ratio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Synth {
+    /// A PLL, which multiplies the crystal frequency.
+    Pll(Pll),
+    /// The multisynth of one of the outputs 0 to 5, which divides the frequency of the PLL it is fed from.
+    Multisynth { output: u8, pll: Pll },
+}
+
+/// The ratio `integer + numerator / denominator` a PLL multiplies or a multisynth divides by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct SynthRatio {
+    pub integer: u32,
+    pub numerator: u32,
+    pub denominator: u32,
 }
 
-impl<SI> Si5351Controller<SI>
+/// The largest denominator the chip takes, which gives the finest steps.
+pub const SYNTH_DENOMINATOR_MAX: u32 = 0xf_ffff;
+
+impl SynthRatio {
+    /// The PLL A ratio that [`Si5351::set_pll_frac`] sets for `frac`.
+    pub const fn pll_frac(frac: u32) -> Self {
+        Self {
+            integer: 35,
+            numerator: frac & SYNTH_DENOMINATOR_MAX,
+            denominator: SYNTH_DENOMINATOR_MAX,
+        }
+    }
+
+    /// The ratio in steps of one over [`SYNTH_DENOMINATOR_MAX`].
+    fn steps(&self) -> u64 {
+        let denominator = self.denominator.max(1) as u64;
+
+        self.integer as u64 * SYNTH_DENOMINATOR_MAX as u64
+            + (self.numerator as u64 * SYNTH_DENOMINATOR_MAX as u64 + denominator / 2) / denominator
+    }
+
+    fn from_steps(steps: u64) -> Self {
+        Self {
+            integer: (steps / SYNTH_DENOMINATOR_MAX as u64) as u32,
+            numerator: (steps % SYNTH_DENOMINATOR_MAX as u64) as u32,
+            denominator: SYNTH_DENOMINATOR_MAX,
+        }
+    }
+}
+
+/// Drivers that can set the ratio of any synthesizer, not just the PLL A frac.
+pub trait Si5351Synth: Si5351 {
+    fn set_synth(&mut self, synth: Synth, ratio: SynthRatio) -> Result<(), Self::Error>;
+
+    /// Sets the synthesizer `tuning` adjusts to run `offset` ppm away from its nominal output, and
+    /// returns the ratio it picked.
+    fn set_offset_ppm(
+        &mut self,
+        tuning: &Si5351Tuning,
+        offset: I16F16,
+    ) -> Result<SynthRatio, Self::Error> {
+        let ratio = tuning.ratio(offset);
+        self.set_synth(tuning.synth(), ratio)?;
+
+        Ok(ratio)
+    }
+}
+
+impl<I2C, E> Si5351Synth for Si5351Device<I2C>
 where
-    SI: Si5351,
+    I2C: i2c::WriteRead<Error = E> + i2c::Write<Error = E>,
 {
-    pub fn new(si: SI, degree: usize, settings: PidSettings) -> Self {
+    fn set_synth(&mut self, synth: Synth, ratio: SynthRatio) -> Result<(), Self::Error> {
+        let pll = |pll| match pll {
+            Pll::A => PLL::A,
+            Pll::B => PLL::B,
+        };
+
+        match synth {
+            Synth::Pll(p) => si5351::Si5351::setup_pll(
+                self,
+                pll(p),
+                ratio.integer as u8,
+                ratio.numerator,
+                ratio.denominator,
+            ),
+            Synth::Multisynth { output, .. } => {
+                let multisynth = match output {
+                    0 => Multisynth::MS0,
+                    1 => Multisynth::MS1,
+                    2 => Multisynth::MS2,
+                    3 => Multisynth::MS3,
+                    4 => Multisynth::MS4,
+                    5 => Multisynth::MS5,
+                    _ => return Err(si5351::Error::InvalidParameter),
+                };
+
+                si5351::Si5351::setup_multisynth(
+                    self,
+                    multisynth,
+                    ratio.integer as u16,
+                    ratio.numerator,
+                    ratio.denominator,
+                    OutputDivider::Div1,
+                )
+            }
+        }
+    }
+}
+
+/// Which synthesizer moves the output frequency.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Adjust {
+    Pll,
+    /// Leaves the PLL alone, so other outputs on the same PLL keep their frequency.
+    Multisynth,
+}
+
+/// The nominal configuration of one output of an Si5351, and how offsets from it are made. The output
+/// divider is 1, offsets are applied with the largest denominator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Si5351Tuning {
+    pub crystal_hz: u32,
+    pub pll: Pll,
+    pub pll_ratio: SynthRatio,
+    pub output: u8,
+    pub multisynth_ratio: SynthRatio,
+    pub adjust: Adjust,
+}
+
+const PLL_RATIO_RANGE: RangeInclusive<u32> = 15..=90;
+const MULTISYNTH_RATIO_RANGE: RangeInclusive<u32> = 8..=2048;
+const PLL_MAX_HZ: u64 = 900_000_000;
+
+/// Offsets beyond this are outside of what fits in the I16F16 of the controllers.
+const TUNING_PPM_LIMIT: i64 = 30_000;
+
+impl Si5351Tuning {
+    /// Output 0 from PLL A at the given ratios, adjusting the PLL.
+    pub fn new(crystal_hz: u32, pll_ratio: SynthRatio, multisynth_ratio: SynthRatio) -> Self {
+        Self {
+            crystal_hz,
+            pll: Pll::A,
+            pll_ratio,
+            output: 0,
+            multisynth_ratio,
+            adjust: Adjust::Pll,
+        }
+    }
+
+    /// Picks an even integer multisynth with the PLL as fast as it goes, and the PLL ratio that gets
+    /// closest to `output_hz`.
+    pub fn for_frequency(crystal_hz: u32, output_hz: u32) -> Self {
+        let multisynth = (PLL_MAX_HZ / output_hz.max(1) as u64).clamp(
+            *MULTISYNTH_RATIO_RANGE.start() as u64,
+            *MULTISYNTH_RATIO_RANGE.end() as u64,
+        ) & !1;
+        let pll_hz = output_hz as u64 * multisynth;
+        let steps = (pll_hz * SYNTH_DENOMINATOR_MAX as u64 + crystal_hz as u64 / 2)
+            / crystal_hz.max(1) as u64;
+
+        Self::new(
+            crystal_hz,
+            SynthRatio::from_steps(steps),
+            SynthRatio {
+                integer: multisynth as u32,
+                numerator: 0,
+                denominator: 1,
+            },
+        )
+    }
+
+    /// The configuration of the minsync board: PLL A at the center frac and the 12 MHz multisynth.
+    pub fn minsync() -> Self {
+        Self::new(
+            25_000_000,
+            SynthRatio::pll_frac(PLL_FRAC_MAX as u32 / 2),
+            SynthRatio {
+                integer: 75,
+                numerator: 0,
+                denominator: 1,
+            },
+        )
+    }
+
+    pub fn with_output(mut self, output: u8, pll: Pll) -> Self {
+        self.output = output;
+        self.pll = pll;
+        self
+    }
+
+    pub fn adjusting(mut self, adjust: Adjust) -> Self {
+        self.adjust = adjust;
+        self
+    }
+
+    pub fn synth(&self) -> Synth {
+        match self.adjust {
+            Adjust::Pll => Synth::Pll(self.pll),
+            Adjust::Multisynth => Synth::Multisynth {
+                output: self.output,
+                pll: self.pll,
+            },
+        }
+    }
+
+    /// The frequency of the output without offset.
+    pub fn nominal_hz(&self) -> u32 {
+        (self.crystal_hz as u64 * self.pll_ratio.steps() / self.multisynth_ratio.steps().max(1))
+            as u32
+    }
+
+    /// Nominal ratio of the adjusted synthesizer, in steps, and the steps it can take.
+    fn steps(&self) -> (u64, RangeInclusive<u64>) {
+        let (ratio, range) = match self.adjust {
+            Adjust::Pll => (self.pll_ratio, PLL_RATIO_RANGE),
+            Adjust::Multisynth => (self.multisynth_ratio, MULTISYNTH_RATIO_RANGE),
+        };
+        let steps = |ratio: u32| ratio as u64 * SYNTH_DENOMINATOR_MAX as u64;
+
+        (ratio.steps(), steps(*range.start())..=steps(*range.end()))
+    }
+
+    /// The ratio of the adjusted synthesizer that gets the output closest to `offset` ppm from nominal.
+    pub fn ratio(&self, offset: I16F16) -> SynthRatio {
+        let (nominal, range) = self.steps();
+        // An offset in ppm as I16F16 bits is a fraction of this.
+        let scale = 1_000_000i128 << 16;
+        let offset = (offset.to_bits() as i128).clamp(
+            -(TUNING_PPM_LIMIT as i128) << 16,
+            (TUNING_PPM_LIMIT as i128) << 16,
+        );
+        let nominal = nominal as i128;
+
+        // A PLL multiplies, so its ratio scales with the frequency. A multisynth divides.
+        let steps = match self.adjust {
+            Adjust::Pll => (nominal * (scale + offset) + scale / 2) / scale,
+            Adjust::Multisynth => (nominal * scale + (scale + offset) / 2) / (scale + offset),
+        };
+
+        SynthRatio::from_steps((steps as u64).clamp(*range.start(), *range.end()))
+    }
+
+    /// The change in ppm of a single step of the adjusted synthesizer.
+    pub fn resolution(&self) -> I16F16 {
+        let (nominal, _) = self.steps();
+
+        I16F16::from_bits(((1_000_000u64 << 16) / nominal.max(1)) as i32)
+    }
+
+    /// The offsets the adjusted synthesizer can reach within its valid ratios.
+    pub fn range(&self) -> RangeInclusive<I16F16> {
+        let (nominal, range) = self.steps();
+        let ppm = |steps: u64| {
+            let ppm = (steps as i64 - nominal as i64) * 1_000_000 / nominal.max(1) as i64;
+            let ppm = match self.adjust {
+                Adjust::Pll => ppm,
+                Adjust::Multisynth => -ppm,
+            };
+
+            I16F16::from_num(ppm.clamp(-TUNING_PPM_LIMIT, TUNING_PPM_LIMIT))
+        };
+
+        let (low, high) = (ppm(*range.start()), ppm(*range.end()));
+        low.min(high)..=low.max(high)
+    }
+}
+
+/// Drives one output of an Si5351 around the nominal frequency of a [`Si5351Tuning`].
+pub struct Si5351SynthActuator<SI> {
+    si: SI,
+    tuning: Si5351Tuning,
+    ratio: SynthRatio,
+}
+
+impl<SI: Si5351Synth> Si5351SynthActuator<SI> {
+    pub fn new(si: SI, tuning: Si5351Tuning) -> Self {
         Self {
-            degree,
             si,
-            pid: PidControl::new(settings),
-            divider: PLL_FRAC_MAX / 2,
-            debug: Default::default(),
+            ratio: tuning.ratio(I16F16::ZERO),
+            tuning,
         }
     }
+
+    pub fn tuning(&self) -> &Si5351Tuning {
+        &self.tuning
+    }
+
+    /// Whether the ratio of the last run has reached the chip.
+    pub fn ratio_applied(&self) -> bool {
+        self.si.frac_applied()
+    }
 }
 
-const PLL_FRAC_MAX: i32 = 0xf_ffff;
-const PLL_FRAC_MIN: i32 = 0x0_0000;
+impl<SI: Si5351Synth> FrequencyActuator for Si5351SynthActuator<SI> {
+    type Error = SI::Error;
+
+    fn range(&self) -> RangeInclusive<I16F16> {
+        self.tuning.range()
+    }
+
+    fn resolution(&self) -> I16F16 {
+        self.tuning.resolution()
+    }
+
+    /// Writing the registers over I2C takes about a millisecond.
+    fn apply_latency_us(&self) -> u32 {
+        1000
+    }
+
+    fn set_offset_ppm(&mut self, offset: I16F16) -> Result<(), Self::Error> {
+        self.ratio = self.si.set_offset_ppm(&self.tuning, offset)?;
 
-pub enum Si5351Error {
-    SetupPllI2cError,
-    PidError,
+        Ok(())
+    }
+
+    /// The numerator of the adjusted synthesizer.
+    fn setting(&self) -> u32 {
+        self.ratio.numerator
+    }
 }
 
-#[derive(Debug, Default, Clone, Copy)]
-pub struct Si5351Debug {
-    pub frac: u32,
-    pub adjust: i32,
+/// Drives the PLL A frac of an Si5351, with the center frac as the nominal frequency.
+pub struct Si5351FracActuator<SI> {
+    si: SI,
+    frac: u32,
 }
 
-impl<SI: Si5351, const B: usize> FrequencyController<B> for Si5351Controller<SI> {
-    type Error = Si5351Error;
-    type Debug = Si5351Debug;
+impl<SI: Si5351> Si5351FracActuator<SI> {
+    pub fn new(si: SI) -> Self {
+        Self {
+            si,
+            frac: PLL_FRAC_MAX as u32 / 2,
+        }
+    }
+
+    /// Whether the frac of the last run has reached the chip.
+    pub fn frac_applied(&self) -> bool {
+        self.si.frac_applied()
+    }
+}
 
-    fn run(&mut self, buffer_levels: &[usize]) -> Result<(), Self::Error> {
-        //TODO: remove some common code between controllers
-        assert!(buffer_levels.len() >= self.degree); // TODO: return Err? type level thing?
-        let half_full = (self.degree * B) / 2;
-        let total_level: usize = buffer_levels.iter().sum();
+/// One frac step in ppm: the PLL runs at 35.5 times the crystal around the center frac.
+const PPM_PER_FRAC: I16F16 = I16F16::lit("0.026864");
+const PPM_RANGE: I16F16 = I16F16::lit("14084.5");
 
-        let adjust = self
-            .pid
-            .run(I16F16::from_num(half_full), I16F16::from_num(total_level))
-            .ok_or(Si5351Error::PidError)?
-            .to_bits();
+impl<SI: Si5351> FrequencyActuator for Si5351FracActuator<SI> {
+    type Error = SI::Error;
 
-        self.debug.adjust = adjust;
+    fn range(&self) -> RangeInclusive<I16F16> {
+        -PPM_RANGE..=PPM_RANGE
+    }
+
+    fn resolution(&self) -> I16F16 {
+        PPM_PER_FRAC
+    }
 
-        self.divider = self.divider.saturating_sub(adjust);
+    /// Writing the PLL registers over I2C takes about a millisecond.
+    fn apply_latency_us(&self) -> u32 {
+        1000
+    }
 
-        let frac_offset_from_center = self.divider >> 12; // Keep 20 msb's
+    fn set_offset_ppm(&mut self, offset: I16F16) -> Result<(), Self::Error> {
+        let frac_offset =
+            (offset.to_bits() as i64 * PLL_FRAC_MAX as i64 * 71 / 2 / 1_000_000) >> 16;
         let frac =
-            (PLL_FRAC_MAX / 2 + frac_offset_from_center).clamp(PLL_FRAC_MIN, PLL_FRAC_MAX) as u32;
+            (PLL_FRAC_MAX as i64 / 2 + frac_offset).clamp(PLL_FRAC_MIN as i64, PLL_FRAC_MAX as i64);
 
-        // TODO: takes ~1ms to apply (depending on I2C speed), might be too long?
-        self.si
-            .set_pll_frac(frac)
-            .map_err(|_| Si5351Error::SetupPllI2cError)?; // TODO: way more safety in these type conversions?
+        self.si.set_pll_frac(frac as u32)?;
+        self.frac = frac as u32;
 
         Ok(())
     }
 
-    // TODO: obsolete after link masks
-    fn set_degree(&mut self, new_degree: usize) {
-        self.degree = new_degree
+    fn setting(&self) -> u32 {
+        self.frac
     }
+}
+
+/// The original Si5351 controller: a PID on the total buffer deviation, whose output is added to
+/// the frac in steps of 16. The integral stops while the frac is at the end of its range. Offsets go
+/// through a calibration table once one is set.
+pub type Si5351Controller<SI, const DEGREE: usize = 4> =
+    Controller<Integrating<PidLaw>, Calibrated<Si5351FracActuator<SI>>, DEGREE>;
+
+pub type Si5351Debug = ControllerDebug<PidDebug>;
+
+/// The law of [`Si5351Controller::with_pid`], to run it against another actuator, e.g. when replaying
+/// a trace.
+pub fn pid_law(settings: PidSettings) -> Integrating<PidLaw> {
+    Integrating::new(
+        PidLaw::from_control(PidControl::new(settings).with_anti_windup(AntiWindup::Clamping)),
+        PPM_PER_FRAC * 16,
+    )
+}
+
+/// The law of [`Si5351Controller::with_schedule`], to run it against another actuator.
+pub fn schedule_law(schedule: GainSchedule) -> Integrating<PidLaw> {
+    Integrating::new(
+        PidLaw::from_control(
+            PidControl::new(schedule.acquire).with_anti_windup(AntiWindup::Clamping),
+        )
+        .with_schedule(schedule),
+        PPM_PER_FRAC * 16,
+    )
+}
 
-    fn debug(&self) -> Self::Debug {
-        self.debug
+impl<SI: Si5351, const DEGREE: usize> Si5351Controller<SI, DEGREE> {
+    pub fn with_pid(si: SI, settings: PidSettings) -> Self {
+        Controller::new(
+            pid_law(settings),
+            Calibrated::new(Si5351FracActuator::new(si), None),
+        )
+    }
+
+    /// Starts with the acquisition gains of `schedule` and switches gains as the buffers lock.
+    pub fn with_schedule(si: SI, schedule: GainSchedule) -> Self {
+        Controller::new(
+            schedule_law(schedule),
+            Calibrated::new(Si5351FracActuator::new(si), None),
+        )
+    }
+
+    /// Linearizes the offsets with a table measured by [`crate::calibration::calibrate`].
+    pub fn with_calibration(mut self, table: Option<CalibrationTable>) -> Self {
+        self.actuator_mut().set_table(table);
+        self
     }
 }
+
+const PLL_FRAC_MAX: i32 = 0xf_ffff;
+const PLL_FRAC_MIN: i32 = 0x0_0000;
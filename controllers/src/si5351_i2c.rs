@@ -0,0 +1,646 @@
+//! Writes the ratios of an Si5351 without blocking the control interrupt. Only the registers that
+//! changed since the last acknowledged write are sent, and the whole write fits in the transmit FIFO of
+//! the I2C block, so the hardware clocks it out while the CPU continues. The I2C interrupt starts the
+//! next write as soon as the bus is free again.
+
+use core::{
+    cell::RefCell,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+
+use critical_section::Mutex;
+use heapless::Vec;
+use rp_pico::{
+    hal::I2C,
+    pac::{self, i2c0::RegisterBlock},
+};
+
+use crate::si5351::{Pll, Si5351, Si5351Synth, Synth, SynthRatio};
+
+/// I2C address of an Si5351 with its address pin low.
+pub const SI5351_ADDRESS: u8 = 0x60;
+
+const TX_FIFO_DEPTH: usize = 16;
+
+/// Computes the eight registers of a PLL or multisynth that runs at `integer + numerator / denominator`
+/// times its input, as laid out in AN619. The R divider and divide by 4 bits that share a register with
+/// a multisynth are left at zero, so an output divides by 1.
+pub fn synth_registers(integer: u32, numerator: u32, denominator: u32) -> [u8; 8] {
+    let fraction = 128 * numerator as u64 / denominator as u64;
+    let p1 = (128 * integer as u64 + fraction - 512) as u32;
+    let p2 = (128 * numerator as u64 - denominator as u64 * fraction) as u32;
+    let p3 = denominator;
+
+    [
+        (p3 >> 8) as u8,
+        p3 as u8,
+        ((p1 >> 16) & 0x03) as u8,
+        (p1 >> 8) as u8,
+        p1 as u8,
+        (((p3 >> 12) & 0xf0) | ((p2 >> 16) & 0x0f)) as u8,
+        (p2 >> 8) as u8,
+        p2 as u8,
+    ]
+}
+
+/// How the last write on the bus ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum I2cEvent {
+    /// The device acknowledged every byte and the stop condition was sent.
+    Completed,
+    /// The device did not acknowledge, or the bus was lost.
+    Aborted,
+}
+
+/// The transmit side of an I2C controller at register level, so the engine can run against a mock.
+pub trait NonBlockingI2c {
+    /// Points all following writes at the device at `address`.
+    fn set_target(&mut self, address: u8);
+
+    /// Queues `bytes` as a single write if the bus is idle and they fit in the transmit FIFO. Returns
+    /// whether they were queued.
+    fn start_write(&mut self, bytes: &[u8]) -> bool;
+
+    /// Returns and clears how the last write ended, `None` while it is still running.
+    fn take_event(&mut self) -> Option<I2cEvent>;
+
+    /// Reads the registers from `first` on into `buffer`, blocking until they arrived. Only for setup,
+    /// before the interrupt takes over the bus. Returns whether the device answered.
+    fn read_registers(&mut self, first: u8, buffer: &mut [u8]) -> bool;
+
+    /// Makes the interrupt of the block run, so it picks up a ratio requested from another context.
+    fn pend_interrupt();
+}
+
+fn set_target(i2c: &RegisterBlock, address: u8) {
+    // The target address can only be changed while the block is disabled.
+    i2c.ic_enable().write(|w| w.enable().disabled());
+    i2c.ic_tar()
+        .write(|w| unsafe { w.ic_tar().bits(address as u16) });
+    // Named the other way around in the PAC: disabled means the interrupt is not masked.
+    i2c.ic_intr_mask().write(|w| {
+        w.m_stop_det().disabled();
+        w.m_tx_abrt().disabled()
+    });
+    i2c.ic_enable().write(|w| w.enable().enabled());
+}
+
+fn start_write(i2c: &RegisterBlock, bytes: &[u8]) -> bool {
+    let level = i2c.ic_txflr().read().txflr().bits() as usize;
+
+    if i2c.ic_status().read().mst_activity().bit_is_set() || level + bytes.len() > TX_FIFO_DEPTH {
+        return false;
+    }
+
+    // Events of an earlier write may still be set, e.g. the stop after an abort.
+    i2c.ic_clr_tx_abrt().read();
+    i2c.ic_clr_stop_det().read();
+
+    for (i, &byte) in bytes.iter().enumerate() {
+        i2c.ic_data_cmd().write(|w| unsafe {
+            w.cmd().write();
+            w.stop().bit(i == bytes.len() - 1);
+            w.dat().bits(byte)
+        });
+    }
+
+    true
+}
+
+fn read_registers(i2c: &RegisterBlock, first: u8, buffer: &mut [u8]) -> bool {
+    if buffer.is_empty() || 1 + buffer.len() > TX_FIFO_DEPTH {
+        return false;
+    }
+
+    while i2c.ic_status().read().mst_activity().bit_is_set() {}
+    i2c.ic_clr_tx_abrt().read();
+    i2c.ic_clr_stop_det().read();
+
+    i2c.ic_data_cmd().write(|w| unsafe {
+        w.cmd().write();
+        w.dat().bits(first)
+    });
+    for i in 0..buffer.len() {
+        i2c.ic_data_cmd().write(|w| {
+            w.cmd().read();
+            w.restart().bit(i == 0);
+            w.stop().bit(i == buffer.len() - 1)
+        });
+    }
+
+    for byte in buffer.iter_mut() {
+        while i2c.ic_rxflr().read().rxflr().bits() == 0 {
+            if take_event(i2c) == Some(I2cEvent::Aborted) {
+                return false;
+            }
+        }
+
+        *byte = i2c.ic_data_cmd().read().dat().bits();
+    }
+
+    // The stop of the read should not look like the end of the first write to the engine.
+    while take_event(i2c).is_none() {}
+
+    true
+}
+
+fn take_event(i2c: &RegisterBlock) -> Option<I2cEvent> {
+    let raw = i2c.ic_raw_intr_stat().read();
+
+    if raw.tx_abrt().bit_is_set() {
+        i2c.ic_clr_tx_abrt().read();
+        i2c.ic_clr_stop_det().read();
+        Some(I2cEvent::Aborted)
+    } else if raw.stop_det().bit_is_set() {
+        i2c.ic_clr_stop_det().read();
+        Some(I2cEvent::Completed)
+    } else {
+        None
+    }
+}
+
+macro_rules! impl_non_blocking_i2c {
+    ($block:ty, $interrupt:ident) => {
+        impl<P> NonBlockingI2c for I2C<$block, P> {
+            fn set_target(&mut self, address: u8) {
+                // Safe because this owns the only handle to the block.
+                set_target(unsafe { &*<$block>::ptr() }, address)
+            }
+
+            fn start_write(&mut self, bytes: &[u8]) -> bool {
+                start_write(unsafe { &*<$block>::ptr() }, bytes)
+            }
+
+            fn take_event(&mut self) -> Option<I2cEvent> {
+                take_event(unsafe { &*<$block>::ptr() })
+            }
+
+            fn read_registers(&mut self, first: u8, buffer: &mut [u8]) -> bool {
+                read_registers(unsafe { &*<$block>::ptr() }, first, buffer)
+            }
+
+            fn pend_interrupt() {
+                pac::NVIC::pend(pac::Interrupt::$interrupt);
+            }
+        }
+    };
+}
+
+impl_non_blocking_i2c!(pac::I2C0, I2C0_IRQ);
+impl_non_blocking_i2c!(pac::I2C1, I2C1_IRQ);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Si5351I2cError {
+    /// A write was not acknowledged since the last ratio was requested. It is retried.
+    WriteFailed,
+    /// The engine has not been given to the shared handle yet.
+    Missing,
+    /// The chip has no multisynth with a fractional ratio for this output.
+    InvalidSynth,
+}
+
+/// Number of synthesizers with a fractional ratio: the two PLLs and the multisynths of outputs 0 to 5.
+const SYNTHS: usize = 8;
+/// Outputs with a multisynth of their own.
+const OUTPUTS: usize = SYNTHS - 2;
+
+fn synth_index(synth: Synth) -> usize {
+    match synth {
+        Synth::Pll(Pll::A) => 0,
+        Synth::Pll(Pll::B) => 1,
+        Synth::Multisynth { output, .. } => 2 + output as usize,
+    }
+}
+
+/// The synthesizers take eight registers each, starting with PLL A at 26.
+fn first_register(synth: Synth) -> u8 {
+    26 + 8 * synth_index(synth) as u8
+}
+
+const CLOCK_CONTROL_REGISTERS: u8 = 16;
+
+/// Clock control bit that puts a multisynth in integer mode.
+const MS_INT: u8 = 1 << 6;
+/// Clock control bit that feeds a multisynth from PLL B instead of PLL A.
+const MS_SRC: u8 = 1 << 5;
+/// Clock control of an output fed by its own multisynth, powered up with 8 mA drive strength. Used when
+/// the clock controls could not be read.
+const DEFAULT_CLOCK_CONTROL: u8 = 0x0f;
+
+/// The clock control `control` with the multisynth in fractional mode and fed from `pll`. Power, drive
+/// strength, inversion and the clock source stay as the board set them up.
+fn clock_control(control: u8, pll: Pll) -> u8 {
+    let control = control & !(MS_INT | MS_SRC);
+
+    match pll {
+        Pll::A => control,
+        Pll::B => control | MS_SRC,
+    }
+}
+
+enum Write {
+    ClockControl {
+        output: u8,
+    },
+    Synth {
+        synth: Synth,
+        ratio: SynthRatio,
+        registers: [u8; 8],
+    },
+}
+
+/// Keeps a ratio of an Si5351 up to date over a bus it owns. A requested ratio is written as soon as the
+/// bus is free, a ratio requested while a write is running replaces any ratio still waiting. The PLL is
+/// not reset after a write, so the output follows the ratio without a glitch. A multisynth is switched
+/// to fractional mode before its first write, which only changes the mode and PLL bits of its clock
+/// control.
+pub struct Si5351I2c<I> {
+    i2c: I,
+    /// The registers as the chip acknowledged them, `None` until the first write and after a failed one.
+    written: [Option<[u8; 8]>; SYNTHS],
+    /// The clock control of every output as it was read when the engine was set up.
+    clock_controls: [u8; OUTPUTS],
+    /// Outputs whose multisynth has been switched to fractional mode, one bit each.
+    fractional: u8,
+    in_flight: Option<Write>,
+    pending: Option<(Synth, SynthRatio)>,
+    applied: Option<(Synth, SynthRatio)>,
+    failed: bool,
+    failures: u32,
+}
+
+impl<I: NonBlockingI2c> Si5351I2c<I> {
+    /// Takes over the bus, after reading the clock controls the board set up.
+    pub fn new(mut i2c: I, address: u8) -> Self {
+        i2c.set_target(address);
+
+        let mut clock_controls = [DEFAULT_CLOCK_CONTROL; OUTPUTS];
+        if !i2c.read_registers(CLOCK_CONTROL_REGISTERS, &mut clock_controls) {
+            defmt::warn!("Could not read the Si5351 clock controls, using the defaults");
+            clock_controls = [DEFAULT_CLOCK_CONTROL; OUTPUTS];
+        }
+
+        Self {
+            i2c,
+            written: [None; SYNTHS],
+            clock_controls,
+            fractional: 0,
+            in_flight: None,
+            pending: None,
+            applied: None,
+            failed: false,
+            failures: 0,
+        }
+    }
+
+    /// Queues a ratio to be written, replacing any ratio that has not been sent yet.
+    pub fn request(&mut self, synth: Synth, ratio: SynthRatio) -> Result<(), Si5351I2cError> {
+        if synth_index(synth) >= SYNTHS {
+            return Err(Si5351I2cError::InvalidSynth);
+        }
+
+        self.pending = Some((synth, ratio));
+        self.poll();
+
+        Ok(())
+    }
+
+    /// Handles the end of the running write and starts the next one. Call this from the I2C interrupt.
+    pub fn poll(&mut self) {
+        match (self.i2c.take_event(), self.in_flight.take()) {
+            (Some(I2cEvent::Completed), Some(Write::ClockControl { output })) => {
+                self.fractional |= 1 << output;
+            }
+            (
+                Some(I2cEvent::Completed),
+                Some(Write::Synth {
+                    synth,
+                    ratio,
+                    registers,
+                }),
+            ) => {
+                self.written[synth_index(synth)] = Some(registers);
+                self.applied = Some((synth, ratio));
+            }
+            (Some(I2cEvent::Aborted), Some(write)) => {
+                if let Write::Synth { synth, ratio, .. } = write {
+                    // Part of the registers may have been written, so all of them are sent next time.
+                    self.written[synth_index(synth)] = None;
+                    self.applied = None;
+                    self.pending.get_or_insert((synth, ratio));
+                }
+
+                self.failed = true;
+                self.failures = self.failures.wrapping_add(1);
+            }
+            (None, write) => self.in_flight = write,
+            // A stray event while nothing was being written.
+            (Some(_), None) => {}
+        }
+
+        if self.in_flight.is_none() {
+            if let Some((synth, ratio)) = self.pending {
+                self.start(synth, ratio);
+            }
+        }
+    }
+
+    fn start(&mut self, synth: Synth, ratio: SynthRatio) {
+        if let Synth::Multisynth { output, pll } = synth {
+            if self.fractional & (1 << output) == 0 {
+                let control = clock_control(self.clock_controls[output as usize], pll);
+
+                if self
+                    .i2c
+                    .start_write(&[CLOCK_CONTROL_REGISTERS + output, control])
+                {
+                    self.in_flight = Some(Write::ClockControl { output });
+                }
+
+                return;
+            }
+        }
+
+        let registers = synth_registers(ratio.integer, ratio.numerator, ratio.denominator);
+        let written = self.written[synth_index(synth)];
+        let changed = |i: &usize| written.map_or(true, |written| written[*i] != registers[*i]);
+
+        let (Some(first), Some(last)) = ((0..8).find(changed), (0..8).rfind(changed)) else {
+            self.pending = None;
+            self.applied = Some((synth, ratio));
+            return;
+        };
+
+        // The Si5351 increments the register address after every byte, so the changed registers
+        // and those in between go out in one write.
+        let mut bytes: Vec<u8, 9> = Vec::new();
+        bytes.push(first_register(synth) + first as u8).ok();
+        bytes.extend_from_slice(&registers[first..=last]).ok();
+
+        if self.i2c.start_write(&bytes) {
+            self.in_flight = Some(Write::Synth {
+                synth,
+                ratio,
+                registers,
+            });
+            self.pending = None;
+        }
+    }
+
+    /// The synthesizer last written and the ratio it runs at, `None` while unknown.
+    pub fn applied(&self) -> Option<(Synth, SynthRatio)> {
+        self.applied
+    }
+
+    /// Whether the last requested ratio has been acknowledged by the chip.
+    pub fn is_applied(&self) -> bool {
+        self.pending.is_none() && self.in_flight.is_none() && self.applied.is_some()
+    }
+
+    /// Number of writes the chip did not acknowledge.
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    pub fn free(self) -> I {
+        self.i2c
+    }
+}
+
+impl<I: NonBlockingI2c> Si5351 for Si5351I2c<I> {
+    type Error = Si5351I2cError;
+
+    fn set_pll_frac(&mut self, frac: u32) -> Result<(), Self::Error> {
+        self.set_synth(Synth::Pll(Pll::A), SynthRatio::pll_frac(frac))
+    }
+
+    fn frac_applied(&self) -> bool {
+        self.is_applied()
+    }
+}
+
+impl<I: NonBlockingI2c> Si5351Synth for Si5351I2c<I> {
+    fn set_synth(&mut self, synth: Synth, ratio: SynthRatio) -> Result<(), Self::Error> {
+        self.request(synth, ratio)?;
+
+        if core::mem::take(&mut self.failed) {
+            Err(Si5351I2cError::WriteFailed)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Packs a synthesizer into a word: whether it is a multisynth, whether it runs from PLL B, and the
+/// output.
+fn pack_synth(synth: Synth) -> u32 {
+    let (multisynth, output, pll) = match synth {
+        Synth::Pll(pll) => (false, 0, pll),
+        Synth::Multisynth { output, pll } => (true, output, pll),
+    };
+
+    multisynth as u32 | ((pll == Pll::B) as u32) << 1 | (output as u32) << 8
+}
+
+fn unpack_synth(packed: u32) -> Synth {
+    let pll = if packed & 0b10 == 0 { Pll::A } else { Pll::B };
+
+    if packed & 0b01 == 0 {
+        Synth::Pll(pll)
+    } else {
+        Synth::Multisynth {
+            output: (packed >> 8) as u8,
+            pll,
+        }
+    }
+}
+
+/// The last requested ratio, handed to the I2C interrupt without a critical section. The RP2040 has no
+/// atomic read-modify-write instructions, so this is a sequence lock of plain loads and stores: the
+/// sequence number is odd while a request is written, and a reader that sees it change leaves the
+/// request for the interrupt the writer pends afterwards. Requests should come from one context at a
+/// time, requests that race may mix into a ratio nobody asked for.
+struct Mailbox {
+    sequence: AtomicU32,
+    synth: AtomicU32,
+    integer: AtomicU32,
+    numerator: AtomicU32,
+    denominator: AtomicU32,
+}
+
+impl Mailbox {
+    const fn new() -> Self {
+        Self {
+            sequence: AtomicU32::new(0),
+            synth: AtomicU32::new(0),
+            integer: AtomicU32::new(0),
+            numerator: AtomicU32::new(0),
+            denominator: AtomicU32::new(0),
+        }
+    }
+
+    fn write(&self, synth: Synth, ratio: SynthRatio) {
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        self.sequence
+            .store(sequence.wrapping_add(1), Ordering::Release);
+
+        self.synth.store(pack_synth(synth), Ordering::Release);
+        self.integer.store(ratio.integer, Ordering::Release);
+        self.numerator.store(ratio.numerator, Ordering::Release);
+        self.denominator.store(ratio.denominator, Ordering::Release);
+
+        // Skip zero on wrap around, it marks an empty mailbox.
+        self.sequence
+            .store(sequence.wrapping_add(2).max(2), Ordering::Release);
+    }
+
+    /// The sequence number of the last complete request, zero while there is none.
+    fn sequence(&self) -> u32 {
+        self.sequence.load(Ordering::Acquire)
+    }
+
+    /// The last request and its sequence number, `None` while it is being written or there is none.
+    fn read(&self) -> Option<(u32, Synth, SynthRatio)> {
+        let before = self.sequence();
+
+        if before == 0 || before % 2 == 1 {
+            return None;
+        }
+
+        let synth = unpack_synth(self.synth.load(Ordering::Acquire));
+        let ratio = SynthRatio {
+            integer: self.integer.load(Ordering::Acquire),
+            numerator: self.numerator.load(Ordering::Acquire),
+            denominator: self.denominator.load(Ordering::Acquire),
+        };
+
+        (self.sequence() == before).then_some((before, synth, ratio))
+    }
+}
+
+/// An [`Si5351I2c`] shared between the control interrupt, which requests ratios through a reference to
+/// this, and the I2C interrupt, which calls [`SharedSi5351I2c::on_interrupt`]. Requests and the state
+/// of the engine pass through atomics, so the control interrupt never takes a critical section, only
+/// the I2C interrupt does.
+/// ```ignore
+/// static SI5351: SharedSi5351I2c<SiI2C> = SharedSi5351I2c::new();
+///
+/// #[interrupt]
+/// fn I2C1_IRQ() {
+///     SI5351.on_interrupt();
+/// }
+/// ```
+pub struct SharedSi5351I2c<I> {
+    engine: Mutex<RefCell<Option<Si5351I2c<I>>>>,
+    given: AtomicBool,
+    requests: Mailbox,
+    /// Sequence number of the request the engine works on, only written by the I2C interrupt.
+    taken: AtomicU32,
+    /// Sequence number of the last request the chip acknowledged, only written by the I2C interrupt.
+    acknowledged: AtomicU32,
+    /// Writes the chip did not acknowledge, only written by the I2C interrupt.
+    failures: AtomicU32,
+    /// Failures already returned to the requester, only written by the requester.
+    reported_failures: AtomicU32,
+}
+
+impl<I> SharedSi5351I2c<I> {
+    pub const fn new() -> Self {
+        Self {
+            engine: Mutex::new(RefCell::new(None)),
+            given: AtomicBool::new(false),
+            requests: Mailbox::new(),
+            taken: AtomicU32::new(0),
+            acknowledged: AtomicU32::new(0),
+            failures: AtomicU32::new(0),
+            reported_failures: AtomicU32::new(0),
+        }
+    }
+
+    pub fn give(&self, engine: Si5351I2c<I>) {
+        critical_section::with(|cs| self.engine.borrow(cs).replace(Some(engine)));
+        self.given.store(true, Ordering::Release);
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut Si5351I2c<I>) -> R) -> Option<R> {
+        critical_section::with(|cs| self.engine.borrow(cs).borrow_mut().as_mut().map(f))
+    }
+}
+
+impl<I> Default for SharedSi5351I2c<I> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<I: NonBlockingI2c> SharedSi5351I2c<I> {
+    /// Passes a new request to the engine, or else handles the end of the running write.
+    pub fn on_interrupt(&self) {
+        let request = self
+            .requests
+            .read()
+            .filter(|&(sequence, ..)| sequence != self.taken.load(Ordering::Relaxed));
+
+        self.with(|engine| {
+            match request {
+                Some((sequence, synth, ratio)) => {
+                    // The synthesizer was checked when it was requested.
+                    engine.request(synth, ratio).ok();
+                    self.taken.store(sequence, Ordering::Relaxed);
+                }
+                None => engine.poll(),
+            }
+
+            if engine.is_applied() {
+                self.acknowledged
+                    .store(self.taken.load(Ordering::Relaxed), Ordering::Release);
+            }
+            self.failures.store(engine.failures(), Ordering::Release);
+        });
+    }
+
+    pub fn applied(&self) -> Option<(Synth, SynthRatio)> {
+        self.with(|engine| engine.applied()).flatten()
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures.load(Ordering::Acquire)
+    }
+}
+
+impl<I: NonBlockingI2c> Si5351 for &SharedSi5351I2c<I> {
+    type Error = Si5351I2cError;
+
+    fn set_pll_frac(&mut self, frac: u32) -> Result<(), Self::Error> {
+        self.set_synth(Synth::Pll(Pll::A), SynthRatio::pll_frac(frac))
+    }
+
+    fn frac_applied(&self) -> bool {
+        let sequence = self.requests.sequence();
+
+        sequence != 0 && self.acknowledged.load(Ordering::Acquire) == sequence
+    }
+}
+
+impl<I: NonBlockingI2c> Si5351Synth for &SharedSi5351I2c<I> {
+    fn set_synth(&mut self, synth: Synth, ratio: SynthRatio) -> Result<(), Self::Error> {
+        if !self.given.load(Ordering::Acquire) {
+            return Err(Si5351I2cError::Missing);
+        }
+        if synth_index(synth) >= SYNTHS {
+            return Err(Si5351I2cError::InvalidSynth);
+        }
+
+        self.requests.write(synth, ratio);
+        I::pend_interrupt();
+
+        // Like the engine, a failure is reported once, by the next request.
+        let failures = self.failures.load(Ordering::Acquire);
+        if failures != self.reported_failures.load(Ordering::Relaxed) {
+            self.reported_failures.store(failures, Ordering::Relaxed);
+            return Err(Si5351I2cError::WriteFailed);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,528 @@
+//! Closed-loop simulation of a network of nodes running frequency controllers, to be run on the host.
+//! Every node has a clock whose frequency follows from what its controller writes to the (simulated)
+//! hardware. Nodes send one word per control tick to every neighbor, words spend some time in flight
+//! on the links, and the controllers see the resulting elastic buffer levels.
+
+use std::{cell::Cell, collections::VecDeque, rc::Rc, vec::Vec};
+
+use fixed::types::U32F32;
+
+use crate::{
+    calibration::FrequencyCounter, controller::FrequencyController, fbdiv::PllFbdiv, si5351::Si5351,
+};
+
+/// A clock whose frequency can be changed by a controller during the simulation.
+pub trait SimulatedClockGenerator {
+    fn frequency(&mut self) -> f64;
+}
+
+/// Maps the PLL A numerator written by `Si5351FracActuator` to the system clock frequency.
+#[derive(Debug, Clone, Copy)]
+pub struct Si5351Model {
+    pub crystal_hz: f64,
+    pub pll_integer: u32,
+    pub pll_denominator: u32,
+    /// Divider from PLL A to the clock output driving the RP2040.
+    pub multisynth_divider: f64,
+    /// Multiplication of the Si5351 output to the system clock by the RP2040 PLL.
+    pub sys_multiplier: f64,
+}
+
+impl Si5351Model {
+    /// The minsync board: a 25MHz crystal, the multisynth set to 75 by setting up a 12MHz output on a 900MHz
+    /// PLL, and the RP2040 PLL multiplying that to a 200MHz system clock.
+    pub fn minsync() -> Self {
+        Self {
+            crystal_hz: 25e6,
+            pll_integer: 35,
+            pll_denominator: 0xfffff,
+            multisynth_divider: 75.,
+            sys_multiplier: 200. / 12.,
+        }
+    }
+
+    pub fn frequency(&self, frac: u32) -> f64 {
+        let pll_multiplier = self.pll_integer as f64 + frac as f64 / self.pll_denominator as f64;
+
+        self.crystal_hz * pll_multiplier / self.multisynth_divider * self.sys_multiplier
+    }
+}
+
+/// Maps the feedback divider written by `FbdivActuator` to the system clock frequency.
+#[derive(Debug, Clone, Copy)]
+pub struct FbdivModel {
+    pub reference_hz: f64,
+    pub refdiv: u32,
+    pub post_div1: u32,
+    pub post_div2: u32,
+}
+
+impl FbdivModel {
+    /// The PLL configuration of the pico on breadboard installation.
+    pub fn pico_on_breadboard() -> Self {
+        Self {
+            reference_hz: 12e6,
+            refdiv: 1,
+            post_div1: 5,
+            post_div2: 2,
+        }
+    }
+
+    pub fn frequency(&self, fbdiv: u16) -> f64 {
+        self.reference_hz / self.refdiv as f64 * fbdiv as f64
+            / (self.post_div1 * self.post_div2) as f64
+    }
+}
+
+/// The Si5351 as seen by the controller, stores the last written frac for its `Si5351Clock`.
+pub struct SimulatedSi5351 {
+    frac: Rc<Cell<u32>>,
+}
+
+impl Si5351 for SimulatedSi5351 {
+    type Error = ();
+
+    fn set_pll_frac(&mut self, frac: u32) -> Result<(), Self::Error> {
+        log::trace!("set pll frac {}", frac);
+        self.frac.set(frac);
+        Ok(())
+    }
+}
+
+/// The clock produced by a `SimulatedSi5351`, with a fixed crystal error in ppm.
+pub struct Si5351Clock {
+    model: Si5351Model,
+    frac: Rc<Cell<u32>>,
+    ppm_offset: f64,
+}
+
+impl SimulatedClockGenerator for Si5351Clock {
+    fn frequency(&mut self) -> f64 {
+        self.model.frequency(self.frac.get()) * (1. + self.ppm_offset * 1e-6)
+    }
+}
+
+/// Measures the clock exactly, the crystal error included.
+impl FrequencyCounter for Si5351Clock {
+    fn measure_hz(&mut self) -> Option<U32F32> {
+        U32F32::checked_from_num(self.frequency())
+    }
+
+    fn interval_us(&self) -> u32 {
+        0
+    }
+}
+
+/// Creates a simulated Si5351 for a controller and the clock it drives.
+pub fn simulated_si5351(
+    model: Si5351Model,
+    initial_frac: u32,
+    ppm_offset: f64,
+) -> (SimulatedSi5351, Si5351Clock) {
+    let frac = Rc::new(Cell::new(initial_frac));
+
+    (
+        SimulatedSi5351 { frac: frac.clone() },
+        Si5351Clock {
+            model,
+            frac,
+            ppm_offset,
+        },
+    )
+}
+
+/// The system PLL as seen by the controller, stores the last written fbdiv for its `FbdivClock`.
+pub struct SimulatedPll {
+    fbdiv: Rc<Cell<u16>>,
+}
+
+impl PllFbdiv for SimulatedPll {
+    fn read_fbdiv(&self) -> u16 {
+        self.fbdiv.get()
+    }
+
+    fn write_fbdiv(&mut self, fbdiv: u16) {
+        self.fbdiv.set(fbdiv);
+    }
+}
+
+/// The clock produced by a `SimulatedPll`, with a fixed crystal error in ppm.
+pub struct FbdivClock {
+    model: FbdivModel,
+    fbdiv: Rc<Cell<u16>>,
+    ppm_offset: f64,
+}
+
+impl SimulatedClockGenerator for FbdivClock {
+    fn frequency(&mut self) -> f64 {
+        self.model.frequency(self.fbdiv.get()) * (1. + self.ppm_offset * 1e-6)
+    }
+}
+
+/// Measures the clock exactly, the crystal error included.
+impl FrequencyCounter for FbdivClock {
+    fn measure_hz(&mut self) -> Option<U32F32> {
+        U32F32::checked_from_num(self.frequency())
+    }
+
+    fn interval_us(&self) -> u32 {
+        0
+    }
+}
+
+/// Creates a simulated system PLL for a controller and the clock it drives.
+pub fn simulated_pll(
+    model: FbdivModel,
+    initial_fbdiv: u16,
+    ppm_offset: f64,
+) -> (SimulatedPll, FbdivClock) {
+    let fbdiv = Rc::new(Cell::new(initial_fbdiv));
+
+    (
+        SimulatedPll {
+            fbdiv: fbdiv.clone(),
+        },
+        FbdivClock {
+            model,
+            fbdiv,
+            ppm_offset,
+        },
+    )
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SimulationSettings {
+    /// System clock cycles between two runs of the controller, `CLOCKS_PER_SYNC_WORD` on hardware.
+    pub clocks_per_sync_word: u32,
+    /// Seconds a word spends in flight on a link.
+    pub link_latency: f64,
+    /// Buffer error in words within which a link counts as settled.
+    pub settling_tolerance: f64,
+    /// Fraction at the end of a run over which the steady-state error is averaged.
+    pub steady_state_fraction: f64,
+}
+
+impl Default for SimulationSettings {
+    fn default() -> Self {
+        Self {
+            clocks_per_sync_word: 4096,
+            link_latency: 1e-6,
+            settling_tolerance: 2.,
+            steady_state_fraction: 0.1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SimulationError {
+    BufferFull { node: usize, link: usize, time: f64 },
+    BufferEmpty { node: usize, link: usize, time: f64 },
+    ControllerError { node: usize, time: f64 },
+}
+
+/// Results of a run, buffer errors are the distance of a buffer level from its midpoint in words.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SimulationReport {
+    /// Time from the start of the run after which every link stayed within the settling tolerance,
+    /// `None` if some link was outside of it at the end of the run.
+    pub settling_time: Option<f64>,
+    /// Largest buffer error past the midpoint, in the direction opposite of the first error on that link.
+    pub overshoot: f64,
+    /// Largest absolute buffer error averaged over the end of the run.
+    pub steady_state_error: f64,
+    /// Largest relative frequency difference between two nodes at the end of the run, in ppm.
+    pub frequency_spread_ppm: f64,
+    /// Largest relative difference between the average frequencies of two nodes over the end of the
+    /// run, in ppm. Differs from `frequency_spread_ppm` for actuators that dither between settings.
+    pub average_frequency_spread_ppm: f64,
+}
+
+struct SimulatedNode<C, K> {
+    controller: C,
+    clock: K,
+    next_tick: f64,
+    buffer_levels: Vec<usize>,
+    /// Per port, the link on which words from that neighbor arrive, and the link to send words on.
+    incoming: Vec<usize>,
+    outgoing: Vec<usize>,
+}
+
+/// Words in flight on a directed link, as their arrival times. Nothing is sent or received while the
+/// link is down.
+#[derive(Default)]
+struct SimulatedLink {
+    in_flight: VecDeque<f64>,
+    down: bool,
+}
+
+/// Metrics of a single link, gathered while running.
+#[derive(Default, Clone, Copy)]
+struct LinkMetrics {
+    initial_sign: f64,
+    overshoot: f64,
+    last_unsettled: Option<f64>,
+    steady_state_sum: f64,
+    steady_state_samples: u64,
+}
+
+/// A network of nodes that all run a controller of type `C` with elastic buffers of size `B`.
+pub struct Simulation<C, K, const B: usize> {
+    nodes: Vec<SimulatedNode<C, K>>,
+    links: Vec<SimulatedLink>,
+    settings: SimulationSettings,
+    time: f64,
+}
+
+impl<C, K, const B: usize> Simulation<C, K, B>
+where
+    C: FrequencyController<B>,
+    K: SimulatedClockGenerator,
+{
+    /// Connects the nodes by the undirected edges in `topology`. The ports of a node, and so the order of its
+    /// buffer levels, follow the order of the edges it is part of.
+    pub fn new(
+        nodes: Vec<(C, K)>,
+        topology: &[(usize, usize)],
+        settings: SimulationSettings,
+    ) -> Self {
+        let mut nodes: Vec<SimulatedNode<C, K>> = nodes
+            .into_iter()
+            .map(|(controller, clock)| SimulatedNode {
+                controller,
+                clock,
+                next_tick: 0.,
+                buffer_levels: Vec::new(),
+                incoming: Vec::new(),
+                outgoing: Vec::new(),
+            })
+            .collect();
+        let mut links = Vec::new();
+
+        for &(a, b) in topology {
+            for (from, to) in [(a, b), (b, a)] {
+                let link = links.len();
+                links.push(SimulatedLink::default());
+                nodes[from].outgoing.push(link);
+                nodes[to].incoming.push(link);
+                nodes[to].buffer_levels.push(B / 2);
+            }
+        }
+
+        let mut simulation = Self {
+            nodes,
+            links,
+            settings,
+            time: 0.,
+        };
+
+        simulation.fill_links();
+        simulation
+    }
+
+    /// Puts the words on the links that would be in flight if every node had always run at its initial frequency.
+    fn fill_links(&mut self) {
+        for node in self.nodes.iter_mut() {
+            let period = self.settings.clocks_per_sync_word as f64 / node.clock.frequency();
+
+            for &link in node.outgoing.iter() {
+                let mut arrival = self.settings.link_latency % period;
+                while arrival < self.settings.link_latency {
+                    self.links[link].in_flight.push_back(arrival);
+                    arrival += period;
+                }
+            }
+        }
+    }
+
+    /// Runs the network for `duration` seconds of simulated time.
+    pub fn run(&mut self, duration: f64) -> Result<SimulationReport, SimulationError> {
+        let start = self.time;
+        let end = start + duration;
+        let steady_state_start = end - duration * self.settings.steady_state_fraction;
+        let mut metrics = vec![LinkMetrics::default(); self.links.len()];
+        // Per node the first and last tick in the steady-state window, and the ticks in between.
+        let mut steady_state_ticks: Vec<Option<(f64, f64, u64)>> = vec![None; self.nodes.len()];
+
+        loop {
+            let (id, time) = self
+                .nodes
+                .iter()
+                .enumerate()
+                .map(|(id, node)| (id, node.next_tick))
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+                .expect("simulation without nodes");
+
+            if time > end {
+                break;
+            }
+
+            self.time = time;
+            self.tick(id)?;
+
+            if time >= steady_state_start {
+                let ticks = steady_state_ticks[id].get_or_insert((time, time, 0));
+                ticks.1 = time;
+                ticks.2 += 1;
+            }
+
+            let node = &self.nodes[id];
+            for (&link, &level) in node.incoming.iter().zip(node.buffer_levels.iter()) {
+                let error = level as f64 - (B / 2) as f64;
+                let metrics = &mut metrics[link];
+
+                if metrics.initial_sign == 0. {
+                    metrics.initial_sign = error.signum() * (error != 0.) as u8 as f64;
+                }
+
+                metrics.overshoot = metrics.overshoot.max(-metrics.initial_sign * error);
+
+                if error.abs() > self.settings.settling_tolerance {
+                    metrics.last_unsettled = Some(time - start);
+                }
+
+                if time >= steady_state_start {
+                    metrics.steady_state_sum += error;
+                    metrics.steady_state_samples += 1;
+                }
+            }
+        }
+
+        let frequencies: Vec<f64> = self
+            .nodes
+            .iter_mut()
+            .map(|node| node.clock.frequency())
+            .collect();
+        let max_frequency = frequencies.iter().copied().fold(f64::MIN, f64::max);
+        let min_frequency = frequencies.iter().copied().fold(f64::MAX, f64::min);
+
+        let average_frequencies: Vec<f64> = steady_state_ticks
+            .iter()
+            .flatten()
+            .filter(|(first, last, _)| last > first)
+            .map(|(first, last, ticks)| {
+                (ticks - 1) as f64 * self.settings.clocks_per_sync_word as f64 / (last - first)
+            })
+            .collect();
+        let max_average = average_frequencies.iter().copied().fold(f64::MIN, f64::max);
+        let min_average = average_frequencies.iter().copied().fold(f64::MAX, f64::min);
+
+        let unsettled_at_end = self.nodes.iter().any(|node| {
+            node.buffer_levels.iter().any(|&level| {
+                (level as f64 - (B / 2) as f64).abs() > self.settings.settling_tolerance
+            })
+        });
+
+        Ok(SimulationReport {
+            settling_time: if unsettled_at_end {
+                None
+            } else {
+                Some(
+                    metrics
+                        .iter()
+                        .filter_map(|m| m.last_unsettled)
+                        .fold(0., f64::max),
+                )
+            },
+            overshoot: metrics.iter().map(|m| m.overshoot).fold(0., f64::max),
+            steady_state_error: metrics
+                .iter()
+                .map(|m| (m.steady_state_sum / m.steady_state_samples.max(1) as f64).abs())
+                .fold(0., f64::max),
+            frequency_spread_ppm: (max_frequency - min_frequency) / min_frequency * 1e6,
+            average_frequency_spread_ppm: if average_frequencies.is_empty() {
+                0.
+            } else {
+                (max_average - min_average) / min_average * 1e6
+            },
+        })
+    }
+
+    /// A single run of the control interrupt of a node, in the same order as `BittideChannelControl`:
+    /// send a word to every neighbor, receive words, take one word out of every buffer and run the controller.
+    fn tick(&mut self, id: usize) -> Result<(), SimulationError> {
+        let time = self.time;
+        let node = &mut self.nodes[id];
+
+        for &link in node.outgoing.iter() {
+            let link = &mut self.links[link];
+
+            if !link.down {
+                link.in_flight.push_back(time + self.settings.link_latency);
+            }
+        }
+
+        let active: Vec<bool> = node
+            .incoming
+            .iter()
+            .map(|&link| !self.links[link].down)
+            .collect();
+
+        for (port, &link) in node.incoming.iter().enumerate() {
+            // The buffer of a link that is down neither fills nor drains.
+            if !active[port] {
+                continue;
+            }
+
+            let in_flight = &mut self.links[link].in_flight;
+
+            while in_flight.front().is_some_and(|&arrival| arrival <= time) {
+                in_flight.pop_front();
+                node.buffer_levels[port] += 1;
+            }
+
+            if node.buffer_levels[port] > B {
+                return Err(SimulationError::BufferFull {
+                    node: id,
+                    link: port,
+                    time,
+                });
+            }
+
+            if node.buffer_levels[port] == 0 {
+                return Err(SimulationError::BufferEmpty {
+                    node: id,
+                    link: port,
+                    time,
+                });
+            }
+
+            node.buffer_levels[port] -= 1;
+        }
+
+        node.controller.set_active_links(&active);
+        node.controller
+            .run(&node.buffer_levels)
+            .map_err(|_| SimulationError::ControllerError { node: id, time })?;
+
+        node.next_tick = time + self.settings.clocks_per_sync_word as f64 / node.clock.frequency();
+
+        Ok(())
+    }
+
+    /// Takes both directions of the undirected edge at index `edge` of the topology down or back up.
+    pub fn set_edge_down(&mut self, edge: usize, down: bool) {
+        self.links[2 * edge].down = down;
+        self.links[2 * edge + 1].down = down;
+    }
+
+    pub fn buffer_levels(&self, node: usize) -> &[usize] {
+        &self.nodes[node].buffer_levels
+    }
+
+    pub fn frequency(&mut self, node: usize) -> f64 {
+        self.nodes[node].clock.frequency()
+    }
+
+    pub fn controller(&self, node: usize) -> &C {
+        &self.nodes[node].controller
+    }
+
+    pub fn controller_mut(&mut self, node: usize) -> &mut C {
+        &mut self.nodes[node].controller
+    }
+
+    /// Simulated time in seconds.
+    pub fn time(&self) -> f64 {
+        self.time
+    }
+}
@@ -0,0 +1,132 @@
+mod common;
+
+use common::{minsync_nodes, minsync_si5351, pid, schedule, BUFFER_SIZE};
+use controllers::{
+    autotune::{RelaySettings, RelayStep, RelayTuner},
+    controller::FrequencyController,
+    schedule::{GainSchedule, Regime},
+    si5351::Si5351Controller,
+    sim::{Simulation, SimulationSettings},
+};
+use fixed::types::I16F16;
+
+#[test]
+fn test_relay_on_integrator() {
+    // Buffers that move a thousandth of a word per run and ppm, next to a neighbor 30ppm faster.
+    let mut tuner = RelayTuner::new(RelaySettings {
+        amplitude: I16F16::from_num(50),
+        hysteresis: I16F16::from_num(2),
+        cycles: 4,
+        timeout_runs: 100_000,
+    });
+    let mut level: f64 = 0.;
+    let mut output = 0.;
+
+    let result = loop {
+        level += (30. - output) * 1e-3;
+
+        match tuner.update(I16F16::from_num(level.round())) {
+            RelayStep::Output(correction) => output = correction.to_num(),
+            RelayStep::Done(result) => break result,
+            RelayStep::Failed(error) => panic!("{error:?}"),
+        }
+    };
+
+    // The relay switches once the level rounds to a word beyond the hysteresis, so the level peaks at
+    // three words and travels five words per half cycle at the amplitude.
+    assert!((result.bias.to_num::<f64>() - 30.).abs() < 1.);
+    assert!(
+        (result.ultimate_gain.to_num::<f64>() - 200. / (std::f64::consts::PI * 3.)).abs() < 0.1
+    );
+    assert!((result.ultimate_period as f64 - 2. * 5. / 50e-3).abs() < 5.);
+}
+
+#[test]
+fn test_autotune_simulated_si5351() {
+    // Node 1 does not control its frequency, so node 0 tunes against a fixed neighbor.
+    let mut nodes = minsync_nodes(&[-40., 30.], |si| {
+        Si5351Controller::<_, 2>::with_pid(si, pid(0., 0., 0.))
+    });
+    nodes[0].0.start_autotune(RelaySettings {
+        amplitude: I16F16::from_num(100),
+        hysteresis: I16F16::from_num(1),
+        cycles: 4,
+        timeout_runs: 1_000_000,
+    });
+
+    let mut simulation =
+        Simulation::<_, _, BUFFER_SIZE>::new(nodes, &[(0, 1)], SimulationSettings::default());
+
+    while simulation.controller(0).autotuning() {
+        simulation.run(0.1).unwrap();
+    }
+
+    let result = *simulation.controller(0).tuning().unwrap();
+    assert!((result.bias.to_num::<f64>() - 70.).abs() < 1.);
+
+    // The tuned gains take over and lock node 0 to its neighbor.
+    let report = simulation.run(10.).unwrap();
+    assert!(report.settling_time.is_some());
+    assert!(report.frequency_spread_ppm < 0.5);
+}
+
+#[test]
+fn test_autotune_with_schedule() {
+    // Node 1 runs a schedule without gains, so it does not control its frequency either.
+    let idle = GainSchedule {
+        acquire: pid(0., 0., 0.),
+        track: pid(0., 0., 0.),
+        holdover: pid(0., 0., 0.),
+        ..schedule()
+    };
+    let nodes = [(-40., schedule()), (30., idle)]
+        .into_iter()
+        .map(|(ppm_offset, schedule)| {
+            let (si, clock) = minsync_si5351(ppm_offset);
+
+            (Si5351Controller::<_, 2>::with_schedule(si, schedule), clock)
+        })
+        .collect();
+
+    let mut simulation =
+        Simulation::<_, _, BUFFER_SIZE>::new(nodes, &[(0, 1)], SimulationSettings::default());
+    simulation.controller_mut(0).start_autotune(RelaySettings {
+        amplitude: I16F16::from_num(100),
+        hysteresis: I16F16::from_num(1),
+        cycles: 4,
+        timeout_runs: 1_000_000,
+    });
+
+    while simulation.controller(0).autotuning() {
+        simulation.run(0.1).unwrap();
+    }
+
+    // The tuned gains become the tracking gains, which take over once acquisition has locked.
+    let report = simulation.run(20.).unwrap();
+    let law = FrequencyController::<BUFFER_SIZE>::debug(simulation.controller(0)).law;
+    assert_eq!(law.regime, Regime::Track);
+    assert!(report.settling_time.is_some());
+    assert!(report.frequency_spread_ppm < 0.5);
+}
+
+#[test]
+fn test_ending_autotune_is_no_holdover() {
+    let (si, _) = minsync_si5351(0.);
+    let mut controller = Si5351Controller::<_, 1>::with_schedule(si, schedule());
+    controller.start_autotune(RelaySettings {
+        amplitude: I16F16::from_num(100),
+        hysteresis: I16F16::from_num(1),
+        cycles: 4,
+        timeout_runs: 10,
+    });
+
+    // The buffer never moves, so the relay gives up. The links were up all along, so the schedule
+    // stays where it was.
+    while controller.autotuning() {
+        FrequencyController::<BUFFER_SIZE>::run(&mut controller, &[BUFFER_SIZE / 2]).unwrap();
+    }
+
+    let debug = FrequencyController::<BUFFER_SIZE>::debug(&controller);
+    assert!(!debug.holdover);
+    assert_eq!(debug.law.regime, Regime::Acquire);
+}
@@ -0,0 +1,226 @@
+mod common;
+
+use std::{
+    cell::{Cell, RefCell},
+    ops::RangeInclusive,
+    rc::Rc,
+};
+
+use common::minsync_si5351;
+use controllers::{
+    actuator::FrequencyActuator,
+    calibration::{
+        calibrate, Calibrated, CalibrationError, CalibrationTable, FrequencyCounter,
+        CALIBRATION_BYTES,
+    },
+    fbdiv::FbdivActuator,
+    si5351::Si5351FracActuator,
+    sim::{simulated_pll, FbdivModel},
+};
+use fixed::types::{I16F16, U32F32};
+
+/// An actuator whose frequency moves 10% less than asked, and bends away further from the center.
+struct BentActuator(Rc<Cell<f64>>);
+
+impl BentActuator {
+    fn frequency(offset: f64) -> f64 {
+        200e6 * (1. + (0.9 * offset + 2e-6 * offset * offset) * 1e-6)
+    }
+}
+
+impl FrequencyActuator for BentActuator {
+    type Error = ();
+
+    fn range(&self) -> RangeInclusive<I16F16> {
+        I16F16::from_num(-10_000)..=I16F16::from_num(10_000)
+    }
+
+    fn resolution(&self) -> I16F16 {
+        I16F16::from_num(0.1)
+    }
+
+    fn apply_latency_us(&self) -> u32 {
+        0
+    }
+
+    fn set_offset_ppm(&mut self, offset: I16F16) -> Result<(), Self::Error> {
+        self.0.set(offset.to_num());
+        Ok(())
+    }
+
+    fn setting(&self) -> u32 {
+        0
+    }
+}
+
+struct BentCounter(Rc<Cell<f64>>);
+
+impl FrequencyCounter for BentCounter {
+    fn measure_hz(&mut self) -> Option<U32F32> {
+        Some(U32F32::from_num(BentActuator::frequency(self.0.get())))
+    }
+
+    fn interval_us(&self) -> u32 {
+        0
+    }
+}
+
+fn bent() -> (BentActuator, BentCounter) {
+    let offset = Rc::new(Cell::new(0.));
+
+    (BentActuator(offset.clone()), BentCounter(offset))
+}
+
+/// Time on the bench, with an offset that only reaches the frequency some time after it was set.
+#[derive(Default)]
+struct Bench {
+    now_us: u32,
+    offset: f64,
+    pending: Option<(u32, f64)>,
+}
+
+const LATENCY_US: u32 = 2500;
+const INTERVAL_US: u32 = 1000;
+
+/// The bent actuator behind a slow bus.
+struct SlowActuator(Rc<RefCell<Bench>>);
+
+impl FrequencyActuator for SlowActuator {
+    type Error = ();
+
+    fn range(&self) -> RangeInclusive<I16F16> {
+        I16F16::from_num(-10_000)..=I16F16::from_num(10_000)
+    }
+
+    fn resolution(&self) -> I16F16 {
+        I16F16::from_num(0.1)
+    }
+
+    fn apply_latency_us(&self) -> u32 {
+        LATENCY_US
+    }
+
+    fn set_offset_ppm(&mut self, offset: I16F16) -> Result<(), Self::Error> {
+        let mut bench = self.0.borrow_mut();
+        bench.pending = Some((bench.now_us + LATENCY_US, offset.to_num()));
+        Ok(())
+    }
+
+    fn setting(&self) -> u32 {
+        0
+    }
+}
+
+/// Counts the clock of the bent actuator over an interval, averaging when the offset changes in it.
+struct GatedCounter(Rc<RefCell<Bench>>);
+
+impl FrequencyCounter for GatedCounter {
+    fn measure_hz(&mut self) -> Option<U32F32> {
+        let mut bench = self.0.borrow_mut();
+        let end = bench.now_us + INTERVAL_US;
+        let mut hz = BentActuator::frequency(bench.offset);
+
+        if let Some((due, offset)) = bench.pending.filter(|(due, _)| *due < end) {
+            let before = due.saturating_sub(bench.now_us) as f64 / INTERVAL_US as f64;
+            hz = before * hz + (1. - before) * BentActuator::frequency(offset);
+            bench.offset = offset;
+            bench.pending = None;
+        }
+
+        bench.now_us = end;
+        Some(U32F32::from_num(hz))
+    }
+
+    fn interval_us(&self) -> u32 {
+        INTERVAL_US
+    }
+}
+
+#[test]
+fn test_calibration_waits_for_the_actuator() {
+    let (mut actuator, mut counter) = bent();
+    let instant = calibrate(&mut actuator, &mut counter, 2).unwrap();
+
+    let bench = Rc::new(RefCell::new(Bench::default()));
+    let table = calibrate(
+        &mut SlowActuator(bench.clone()),
+        &mut GatedCounter(bench.clone()),
+        2,
+    )
+    .unwrap();
+
+    // Every point is measured at its own offset, not partly at the one before.
+    for (slow, instant) in table.points().iter().zip(instant.points()) {
+        assert!((slow.measured - instant.measured).abs() < 0.01);
+    }
+    assert_eq!(bench.borrow().pending.map(|(_, offset)| offset), Some(0.));
+}
+
+#[test]
+fn test_calibration_linearizes() {
+    let (mut actuator, mut counter) = bent();
+    let table = calibrate(&mut actuator, &mut counter, 4).unwrap();
+
+    assert_eq!(table.nominal_hz(), 200_000_000);
+
+    let mut calibrated = Calibrated::new(actuator, Some(table));
+    for offset in [-9000., -1234.5, -3., 0., 0.5, 42., 7777.] {
+        calibrated.set_offset_ppm(I16F16::from_num(offset)).unwrap();
+
+        let measured = (BentActuator::frequency(counter.0.get()) / 200e6 - 1.) * 1e6;
+        assert!(
+            (measured - offset).abs() < 1.,
+            "{offset} ppm measured as {measured} ppm"
+        );
+    }
+
+    // The range is what the frequency can actually reach.
+    let range = calibrated.range();
+    assert!((range.end().to_num::<f64>() - 9200.).abs() < 1.);
+    assert!((range.start().to_num::<f64>() + 8800.).abs() < 1.);
+}
+
+#[test]
+fn test_calibration_bytes() {
+    let (mut actuator, mut counter) = bent();
+    let table = calibrate(&mut actuator, &mut counter, 1).unwrap();
+    let bytes = table.to_bytes();
+
+    assert_eq!(CalibrationTable::from_bytes(&bytes), Some(table));
+
+    // Erased flash.
+    assert_eq!(
+        CalibrationTable::from_bytes(&[0xff; CALIBRATION_BYTES]),
+        None
+    );
+
+    let mut corrupted = bytes;
+    corrupted[20] ^= 1;
+    assert_eq!(CalibrationTable::from_bytes(&corrupted), None);
+    assert_eq!(CalibrationTable::from_bytes(&bytes[..100]), None);
+}
+
+#[test]
+fn test_calibrate_simulated_si5351() {
+    // The crystal error shifts the nominal frequency, not the offsets around it.
+    let (si, mut clock) = minsync_si5351(30.);
+    let mut actuator = Si5351FracActuator::new(si);
+    let table = calibrate(&mut actuator, &mut clock, 1).unwrap();
+
+    assert_eq!(actuator.setting(), 0x7ffff);
+    for point in table.points() {
+        assert!((point.measured - point.offset).abs() < 0.1);
+    }
+}
+
+#[test]
+fn test_calibrate_rounding_actuator() {
+    // Without dithering many of the points round to the same fbdiv.
+    let (pll, mut clock) = simulated_pll(FbdivModel::pico_on_breadboard(), 100, 0.);
+    let mut actuator = FbdivActuator::new(pll);
+
+    assert_eq!(
+        calibrate(&mut actuator, &mut clock, 1),
+        Err(CalibrationError::NotMonotonic)
+    );
+}
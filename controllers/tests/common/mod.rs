@@ -0,0 +1,57 @@
+//! Fixtures shared by the tests: buffer size, gains and the simulated minsync boards.
+
+#![allow(dead_code)]
+
+use controllers::{
+    pid::PidSettings,
+    schedule::GainSchedule,
+    sim::{simulated_si5351, Si5351Clock, Si5351Model, SimulatedSi5351},
+};
+use fixed::types::I16F16;
+
+pub const BUFFER_SIZE: usize = 256;
+
+/// The frac at the center of the range of PLL A, which the minsync board starts out at.
+const CENTER_FRAC: u32 = 0x7ffff;
+
+pub fn pid(kp: f64, ki: f64, kd: f64) -> PidSettings {
+    PidSettings {
+        kp: I16F16::from_num(kp),
+        ki: I16F16::from_num(ki),
+        kd: I16F16::from_num(kd),
+    }
+}
+
+/// Aggressive gains until the buffers are within a few words, and calm ones from there.
+pub fn schedule() -> GainSchedule {
+    GainSchedule {
+        acquire: pid(0.01, 0., 200.),
+        track: pid(0.001, 0., 40.),
+        holdover: pid(0., 0., 0.),
+        lock_threshold: I16F16::from_num(4),
+        unlock_threshold: I16F16::from_num(20),
+        lock_runs: 100,
+    }
+}
+
+/// The Si5351 of a minsync board at the center frac and the clock it drives, with a crystal error of
+/// `ppm_offset`.
+pub fn minsync_si5351(ppm_offset: f64) -> (SimulatedSi5351, Si5351Clock) {
+    simulated_si5351(Si5351Model::minsync(), CENTER_FRAC, ppm_offset)
+}
+
+/// A minsync node for every crystal error in `ppm_offsets`, running the controller `controller` builds
+/// around its Si5351.
+pub fn minsync_nodes<C>(
+    ppm_offsets: &[f64],
+    controller: impl Fn(SimulatedSi5351) -> C,
+) -> Vec<(C, Si5351Clock)> {
+    ppm_offsets
+        .iter()
+        .map(|&ppm_offset| {
+            let (si, clock) = minsync_si5351(ppm_offset);
+
+            (controller(si), clock)
+        })
+        .collect()
+}
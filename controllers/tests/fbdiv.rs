@@ -0,0 +1,82 @@
+mod common;
+
+use common::BUFFER_SIZE;
+use controllers::{
+    controller::{Controller, FrequencyController},
+    fbdiv::{Dithering, FbdivActuator, FbdivController},
+    pid::PidSettings,
+    proportional::ProportionalLaw,
+    sim::{
+        simulated_pll, FbdivModel, SimulatedPll, Simulation, SimulationReport, SimulationSettings,
+    },
+};
+use fixed::types::I16F16;
+
+fn simulate<C: FrequencyController<BUFFER_SIZE>>(
+    controller: impl Fn(SimulatedPll) -> C,
+    duration: f64,
+) -> SimulationReport {
+    let nodes = [-20., 30., 10.]
+        .into_iter()
+        .map(|ppm_offset| {
+            let (pll, clock) = simulated_pll(FbdivModel::pico_on_breadboard(), 100, ppm_offset);
+
+            (controller(pll), clock)
+        })
+        .collect();
+
+    let mut simulation = Simulation::<_, _, BUFFER_SIZE>::new(
+        nodes,
+        &[(0, 1), (1, 2), (2, 0)],
+        SimulationSettings::default(),
+    );
+
+    simulation.run(duration).unwrap()
+}
+
+#[test]
+fn test_fbdiv_controller() {
+    let report = simulate(
+        |pll| {
+            FbdivController::<_, 2>::with_pid(
+                pll,
+                PidSettings {
+                    kp: I16F16::from_num(0.01),
+                    ki: I16F16::from_num(0.00000001),
+                    kd: I16F16::from_num(0.01),
+                },
+            )
+        },
+        10.,
+    );
+
+    // A single fbdiv step changes the frequency by 1%, so the frequencies never match, but the
+    // controller keeps switching between steps fast enough to keep the buffers around their midpoint.
+    assert!(report.overshoot < 8.);
+    assert!(report.steady_state_error < 2.);
+}
+
+#[test]
+fn test_fbdiv_dithering() {
+    let proportional = |dithering| {
+        move |pll| {
+            Controller::<_, _, 2>::new(
+                ProportionalLaw::new(I16F16::from_num(50)),
+                FbdivActuator::new(pll).with_dithering(dithering),
+            )
+        }
+    };
+
+    // Without dithering a correction below half an fbdiv step has no effect, so the buffers drift
+    // until their deviation asks for a whole step.
+    let rounded = simulate(proportional(Dithering::None), 20.);
+    assert!(rounded.steady_state_error > 10.);
+    assert!(rounded.average_frequency_spread_ppm > 10.);
+
+    for dithering in [Dithering::FirstOrder, Dithering::SecondOrder] {
+        let dithered = simulate(proportional(dithering), 20.);
+
+        assert!(dithered.steady_state_error < 2.);
+        assert!(dithered.average_frequency_spread_ppm < 1.);
+    }
+}
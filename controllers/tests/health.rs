@@ -0,0 +1,119 @@
+mod common;
+
+use core::ops::RangeInclusive;
+
+use common::BUFFER_SIZE;
+use controllers::{
+    actuator::FrequencyActuator,
+    controller::{Controller, ControllerError, FaultReason, FrequencyController},
+    law::{Integrating, Saturation},
+    proportional::ProportionalLaw,
+};
+use fixed::types::I16F16;
+
+/// An actuator with a range of 10 ppm either way, whose writes fail on request.
+#[derive(Default)]
+struct FaultyActuator {
+    offset: I16F16,
+    fail: bool,
+}
+
+impl FrequencyActuator for &mut FaultyActuator {
+    type Error = ();
+
+    fn range(&self) -> RangeInclusive<I16F16> {
+        I16F16::from_num(-10)..=I16F16::from_num(10)
+    }
+
+    fn resolution(&self) -> I16F16 {
+        I16F16::DELTA
+    }
+
+    fn apply_latency_us(&self) -> u32 {
+        0
+    }
+
+    fn set_offset_ppm(&mut self, offset: I16F16) -> Result<(), Self::Error> {
+        if self.fail {
+            return Err(());
+        }
+
+        self.offset = offset;
+        Ok(())
+    }
+
+    fn setting(&self) -> u32 {
+        self.offset.to_bits() as u32
+    }
+}
+
+#[test]
+fn test_saturation_and_failures() {
+    let mut actuator = FaultyActuator::default();
+    let mut controller =
+        Controller::<_, _, 1>::new(ProportionalLaw::new(I16F16::ONE), &mut actuator);
+    let run = |controller: &mut Controller<_, _, 1>, level: usize| {
+        FrequencyController::<BUFFER_SIZE>::run(controller, &[level])
+    };
+
+    for _ in 0..5 {
+        run(&mut controller, 148).unwrap();
+    }
+    assert_eq!(controller.health().saturation, Some(Saturation::High));
+    assert_eq!(
+        controller.health().reason(),
+        Some(FaultReason::SaturatedHigh)
+    );
+
+    for _ in 0..3 {
+        run(&mut controller, 100).unwrap();
+    }
+    run(&mut controller, 130).unwrap();
+
+    let health = *controller.health();
+    assert_eq!(health.saturation, None);
+    assert_eq!(health.saturated_high_runs, 5);
+    assert_eq!(health.saturated_low_runs, 3);
+    assert_eq!(health.clamp_events, 2);
+
+    controller.actuator_mut().fail = true;
+
+    let error = run(&mut controller, 130).unwrap_err();
+    assert!(matches!(error, ControllerError::ActuatorError(())));
+    assert_eq!(FaultReason::from(error), FaultReason::ActuatorWrite);
+    assert_eq!(controller.health().actuator_failures, 1);
+}
+
+#[test]
+fn test_recentering_is_a_clamp_event() {
+    let mut actuator = FaultyActuator::default();
+    let mut controller = Controller::<_, _, 1>::new(
+        Integrating::new(ProportionalLaw::new(I16F16::ONE), I16F16::ONE).with_recentering(),
+        &mut actuator,
+    );
+
+    // A buffer 4 words below its midpoint steps the correction down by 4 ppm per run, so it reaches
+    // the bottom of the range and restarts from nominal every third run.
+    for _ in 0..9 {
+        FrequencyController::<BUFFER_SIZE>::run(&mut controller, &[124]).unwrap();
+    }
+
+    let health = controller.health();
+    assert_eq!(health.saturated_low_runs, 3);
+    assert_eq!(health.clamp_events, 3);
+    assert_eq!(health.saturated_high_runs, 0);
+}
+
+#[test]
+fn test_fault_reason_codes() {
+    for reason in [
+        FaultReason::LawOverflow,
+        FaultReason::ActuatorWrite,
+        FaultReason::SaturatedHigh,
+        FaultReason::SaturatedLow,
+    ] {
+        assert_eq!(FaultReason::from_code(reason.code()), Some(reason));
+    }
+
+    assert_eq!(FaultReason::from_code(0), None);
+}
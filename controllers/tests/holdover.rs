@@ -0,0 +1,48 @@
+mod common;
+
+use common::{minsync_nodes, schedule, BUFFER_SIZE};
+use controllers::{
+    controller::{ControllerDebug, FrequencyController},
+    schedule::Regime,
+    si5351::Si5351Controller,
+    sim::{Simulation, SimulationSettings},
+};
+
+#[test]
+fn test_holdover_keeps_frequency() {
+    let schedule = schedule();
+
+    let nodes = minsync_nodes(&[-40., 30.], |si| {
+        Si5351Controller::<_, 2>::with_schedule(si, schedule)
+    });
+
+    let mut simulation =
+        Simulation::<_, _, BUFFER_SIZE>::new(nodes, &[(0, 1)], SimulationSettings::default());
+
+    simulation.run(20.).unwrap();
+    let locked = simulation.frequency(0);
+
+    // Both nodes lose their only link.
+    simulation.set_edge_down(0, true);
+    simulation.run(10.).unwrap();
+
+    let ControllerDebug { holdover, law, .. } =
+        FrequencyController::<BUFFER_SIZE>::debug(simulation.controller(0));
+    assert!(holdover);
+    assert_eq!(law.regime, Regime::Holdover);
+    // The held frequency lags the drift of the pair by the averaging, but both nodes hold the same.
+    assert!((simulation.frequency(0) / locked - 1.).abs() < 1e-6);
+    assert!((simulation.frequency(0) / simulation.frequency(1) - 1.).abs() < 0.1e-6);
+
+    // The buffers did not move while the links were down, so they come back within the unlock
+    // threshold and resume tracking without acquiring again.
+    simulation.set_edge_down(0, false);
+    let report = simulation.run(10.).unwrap();
+
+    let ControllerDebug { holdover, law, .. } =
+        FrequencyController::<BUFFER_SIZE>::debug(simulation.controller(0));
+    assert!(!holdover);
+    assert_eq!(law.regime, Regime::Track);
+    assert!(report.overshoot < 2.);
+    assert!(report.frequency_spread_ppm < 0.5);
+}
@@ -0,0 +1,73 @@
+mod common;
+
+use common::{minsync_nodes, BUFFER_SIZE};
+use controllers::{
+    controller::Controller,
+    kalman::{KalmanSettings, NeighborEstimator, RateLaw},
+    law::ControlLaw,
+    proportional::ProportionalLaw,
+    si5351::Si5351FracActuator,
+    sim::{Simulation, SimulationReport, SimulationSettings},
+};
+use fixed::types::I16F16;
+
+#[test]
+fn test_estimator_follows_drift() {
+    let mut estimator = NeighborEstimator::<2>::new(KalmanSettings::default());
+
+    // The first neighbor runs 12 ppm fast, the second 5 ppm slow. Levels only show whole words.
+    for run in 0..2_000_000u32 {
+        let level = |ppm: f64| Some(I16F16::from_num((run as f64 * ppm * 1e-6).floor()));
+        estimator.update(&[level(12.), level(-5.)]);
+    }
+
+    let [fast, slow] = estimator.estimates().map(Option::unwrap);
+
+    assert!((fast.offset_ppm.to_num::<f64>() - 12.).abs() < 1.);
+    assert!((slow.offset_ppm.to_num::<f64>() + 5.).abs() < 1.);
+    assert!((fast.level.to_num::<f64>() - 24.).abs() < 1.);
+}
+
+#[test]
+fn test_long_intervals_are_limited() {
+    let settings = KalmanSettings {
+        interval_shift: 20,
+        ..KalmanSettings::default()
+    };
+    let mut estimator = NeighborEstimator::<1>::new(settings);
+
+    // Steps every 2^16 runs instead of every 2^20.
+    for run in 0..3 << 16 {
+        estimator.update(&[Some(I16F16::from_num(run >> 15))]);
+    }
+
+    let [estimate] = estimator.estimates();
+    assert!(estimate.unwrap().offset_ppm > 0);
+}
+
+fn simulate<L: ControlLaw<2>>(law: impl Fn() -> L) -> SimulationReport {
+    let nodes = minsync_nodes(&[-40., 30., 10.], |si| {
+        Controller::<_, _, 2>::new(law(), Si5351FracActuator::new(si))
+    });
+
+    let mut simulation = Simulation::<_, _, BUFFER_SIZE>::new(
+        nodes,
+        &[(0, 1), (1, 2), (2, 0)],
+        SimulationSettings::default(),
+    );
+
+    simulation.run(40.).unwrap()
+}
+
+#[test]
+fn test_rate_law_against_proportional() {
+    let kp = I16F16::from_num(4);
+    let proportional = simulate(|| ProportionalLaw::new(kp));
+    let rate = simulate(|| RateLaw::new(kp, I16F16::from_num(0.5)));
+
+    // Both keep the buffer offset of a proportional controller. The proportional law keeps switching
+    // between two whole-word levels, while the estimated level and rate move the frequency in smaller
+    // steps once the estimates have settled.
+    assert!(rate.steady_state_error < 40. / 4.);
+    assert!(rate.frequency_spread_ppm < proportional.frequency_spread_ppm);
+}
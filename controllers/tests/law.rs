@@ -0,0 +1,52 @@
+use controllers::law::{BangBangLaw, ControlLaw, Integrating};
+use fixed::types::I16F16;
+
+fn deviations(values: [i32; 2]) -> [Option<I16F16>; 2] {
+    values.map(|value| Some(I16F16::from_num(value)))
+}
+
+#[test]
+fn test_bang_bang_law() {
+    let range = I16F16::from_num(-100)..=I16F16::from_num(100);
+    let mut law = BangBangLaw::new(I16F16::from_num(5), I16F16::from_num(2));
+
+    assert_eq!(
+        law.correction(&deviations([2, 1]), &range),
+        Some(I16F16::from_num(5))
+    );
+    assert_eq!(
+        law.correction(&deviations([-2, 1]), &range),
+        Some(I16F16::ZERO)
+    );
+    assert_eq!(
+        law.correction(&[Some(I16F16::from_num(-3)), None], &range),
+        Some(I16F16::from_num(-5))
+    );
+}
+
+#[test]
+fn test_integrating_law() {
+    let range = I16F16::from_num(-12)..=I16F16::from_num(12);
+    let mut law = Integrating::new(
+        BangBangLaw::new(I16F16::from_num(5), I16F16::ZERO),
+        I16F16::ONE,
+    );
+
+    let corrections: Vec<_> = (0..4)
+        .map(|_| law.correction(&deviations([1, 0]), &range).unwrap())
+        .collect();
+
+    assert_eq!(corrections, [5, 10, 12, 12].map(I16F16::from_num));
+
+    let mut law = Integrating::new(
+        BangBangLaw::new(I16F16::from_num(5), I16F16::ZERO),
+        I16F16::ONE,
+    )
+    .with_recentering();
+
+    let corrections: Vec<_> = (0..4)
+        .map(|_| law.correction(&deviations([-1, 0]), &range).unwrap())
+        .collect();
+
+    assert_eq!(corrections, [-5, -10, 0, -5].map(I16F16::from_num));
+}
@@ -0,0 +1,136 @@
+mod common;
+
+use common::{minsync_nodes, minsync_si5351, pid, schedule, BUFFER_SIZE};
+use controllers::{
+    parameters::{ControllerParameters, ParameterConsole, ParameterStore},
+    si5351::Si5351Controller,
+    sim::{Simulation, SimulationSettings},
+};
+use fixed::types::I16F16;
+
+fn parameters() -> ControllerParameters {
+    ControllerParameters {
+        gains: pid(0.001, 0., 40.),
+        setpoint: I16F16::ZERO,
+        clamp_min: I16F16::MIN,
+        clamp_max: I16F16::MAX,
+        no_message_limit: 3,
+    }
+}
+
+#[test]
+fn test_parameter_console() {
+    let store = ParameterStore::new();
+    let mut console = ParameterConsole::new();
+
+    assert_eq!(
+        console.receive(b"set kp 1\n", &store).as_str(),
+        "error: Uninitialized\n"
+    );
+
+    store.init(parameters());
+
+    // Lines may arrive in pieces.
+    assert_eq!(console.receive(b"set kd 2", &store).as_str(), "");
+    assert_eq!(
+        console
+            .receive(b"0\r\nset no_message_limit 5.7\n", &store)
+            .as_str(),
+        "staged kd=20\nstaged no_message_limit=5\n"
+    );
+    assert_eq!(
+        console
+            .receive(b"set ki x\nfoo\nset bar 1\n", &store)
+            .as_str(),
+        "error: InvalidValue\nerror: UnknownCommand\nerror: UnknownParameter\n"
+    );
+
+    // Nothing changes until the staged values are applied, and then all at once between two runs.
+    // Reads and further changes already see the applied values.
+    assert_eq!(console.receive(b"get kd\n", &store).as_str(), "kd=40\n");
+    assert_eq!(console.receive(b"apply\n", &store).as_str(), "applied\n");
+    assert_eq!(console.receive(b"get kd\n", &store).as_str(), "kd=20\n");
+
+    let mut applied = None;
+    store.apply_pending(None, |parameters| applied = Some(*parameters));
+    let applied = applied.unwrap();
+    assert_eq!(applied.gains.kd, 20);
+    assert_eq!(applied.no_message_limit, 5);
+    store.apply_pending(None, |_| panic!("applied twice"));
+
+    assert_eq!(
+        console.receive(b"get\n", &store).as_str(),
+        "kp=0.001\nki=0\nkd=20\nsetpoint=0\nclamp_min=-32768\nclamp_max=32767.99998\nno_message_limit=5\n"
+    );
+
+    assert_eq!(
+        console
+            .receive(b"set clamp_min 10\nset clamp_max -10\napply\n", &store)
+            .as_str(),
+        "staged clamp_min=10\nstaged clamp_max=-10\nerror: EmptyClampRange\n"
+    );
+    assert_eq!(
+        console.receive(b"discard\napply\n", &store).as_str(),
+        "discarded\nerror: NothingStaged\n"
+    );
+}
+
+#[test]
+fn test_get_reads_the_running_gains() {
+    let store = ParameterStore::new();
+    let mut console = ParameterConsole::new();
+    store.init(parameters());
+
+    // The schedule starts out acquiring, the gains of the parameters are those for tracking.
+    let (si, _) = minsync_si5351(0.);
+    let mut controller = Si5351Controller::<_, 2>::with_schedule(si, schedule());
+    assert_eq!(controller.gains(), Some(schedule().track));
+
+    controller.set_parameters(&parameters());
+    assert_eq!(controller.gains(), Some(parameters().gains));
+
+    // Gains that changed without the console, like tuned ones, show up from the next run on.
+    let tuned = pid(0.5, 0.25, 0.);
+    store.apply_pending(Some(tuned), |_| panic!("nothing was published"));
+
+    assert_eq!(
+        console.receive(b"get kp\nget ki\n", &store).as_str(),
+        "kp=0.5\nki=0.25\n"
+    );
+    assert_eq!(
+        console.receive(b"set kd 1\n", &store).as_str(),
+        "staged kd=1\n"
+    );
+    console.receive(b"apply\n", &store);
+
+    let mut applied = None;
+    store.apply_pending(Some(tuned), |parameters| applied = Some(*parameters));
+    assert_eq!(applied.unwrap().gains, pid(0.5, 0.25, 1.));
+}
+
+#[test]
+fn test_setpoint_at_runtime() {
+    let nodes = minsync_nodes(&[-40., 30.], |si| {
+        Si5351Controller::<_, 2>::with_pid(si, parameters().gains)
+    });
+
+    let mut simulation =
+        Simulation::<_, _, BUFFER_SIZE>::new(nodes, &[(0, 1)], SimulationSettings::default());
+
+    simulation.run(10.).unwrap();
+    let difference = |simulation: &Simulation<_, _, BUFFER_SIZE>| {
+        simulation.buffer_levels(0)[0] as i32 - simulation.buffer_levels(1)[0] as i32
+    };
+    assert_eq!(difference(&simulation), 0);
+
+    simulation
+        .controller_mut(0)
+        .set_parameters(&ControllerParameters {
+            setpoint: I16F16::from_num(20),
+            ..parameters()
+        });
+    simulation.run(20.).unwrap();
+
+    // Node 1 still steers towards the midpoint, so the two meet halfway.
+    assert!((difference(&simulation) - 20).abs() <= 1);
+}
@@ -0,0 +1,90 @@
+use controllers::pid::{AntiWindup, PidControl, PidSettings};
+use fixed::types::I16F16;
+
+/// Runs a PI controller against a first-order plant whose input is limited to -1..=1, with a setpoint
+/// close to what the plant can reach. Returns the largest overshoot past the setpoint.
+fn overshoot_on_saturating_plant(mut pid: PidControl) -> f64 {
+    let limits = I16F16::from_num(-1)..=I16F16::ONE;
+    let setpoint = I16F16::from_num(0.9);
+    let mut output = I16F16::ZERO;
+    let mut overshoot: f64 = 0.;
+
+    for _ in 0..2000 {
+        let input = pid.run_limited(setpoint, output, &limits).unwrap();
+        output += (input - output) / 32;
+
+        overshoot = overshoot.max((output - setpoint).to_num());
+    }
+
+    // Every controller has to reach the setpoint eventually.
+    assert!((output - setpoint).abs() < 0.01);
+
+    overshoot
+}
+
+fn settings() -> PidSettings {
+    PidSettings {
+        kp: I16F16::from_num(1),
+        ki: I16F16::from_num(0.2),
+        kd: I16F16::ZERO,
+    }
+}
+
+#[test]
+fn test_pid_anti_windup() {
+    let windup = overshoot_on_saturating_plant(PidControl::new(settings()));
+    let clamping = overshoot_on_saturating_plant(
+        PidControl::new(settings()).with_anti_windup(AntiWindup::Clamping),
+    );
+    let back_calculation = overshoot_on_saturating_plant(
+        PidControl::new(settings()).with_anti_windup(AntiWindup::BackCalculation {
+            gain: I16F16::from_num(10),
+        }),
+    );
+
+    assert!(clamping < windup / 2.);
+    assert!(back_calculation < windup / 2.);
+}
+
+#[test]
+fn test_pid_reset() {
+    let mut pid = PidControl::new(settings());
+
+    for _ in 0..10 {
+        pid.run(I16F16::ONE, I16F16::ZERO).unwrap();
+    }
+
+    pid.reset();
+
+    let mut fresh = PidControl::new(settings());
+
+    assert_eq!(
+        pid.run(I16F16::ONE, I16F16::ZERO),
+        fresh.run(I16F16::ONE, I16F16::ZERO)
+    );
+}
+
+#[test]
+fn test_pid_derivative_filter() {
+    let kd_only = || PidSettings {
+        kp: I16F16::ZERO,
+        ki: I16F16::ZERO,
+        kd: I16F16::ONE,
+    };
+    let mut raw = PidControl::new(kd_only());
+    let mut filtered = PidControl::new(kd_only()).with_derivative_filter(I16F16::from_num(0.25));
+
+    // A single step in the measurement gives one derivative kick, which the filter spreads out.
+    let step = |pid: &mut PidControl| {
+        [0, 1, 1, 1].map(|measurement| {
+            pid.run(I16F16::ZERO, I16F16::from_num(measurement))
+                .unwrap()
+        })
+    };
+
+    assert_eq!(step(&mut raw), [0, -1, 0, 0].map(I16F16::from_num));
+    assert_eq!(
+        step(&mut filtered),
+        [0., -0.25, -0.1875, -0.140625].map(I16F16::from_num)
+    );
+}
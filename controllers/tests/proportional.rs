@@ -0,0 +1,84 @@
+mod common;
+
+use core::ops::RangeInclusive;
+
+use common::{minsync_nodes, BUFFER_SIZE};
+use controllers::{
+    actuator::FrequencyActuator,
+    controller::{Controller, FrequencyController},
+    proportional::ProportionalLaw,
+    si5351::Si5351FracActuator,
+    sim::{Simulation, SimulationSettings},
+};
+use fixed::types::I16F16;
+
+#[test]
+fn test_proportional_controller() {
+    let nodes = minsync_nodes(&[-40., 30., 10.], |si| {
+        Controller::<_, _, 2>::new(
+            ProportionalLaw::new(I16F16::from_num(4)),
+            Si5351FracActuator::new(si),
+        )
+    });
+
+    let mut simulation = Simulation::<_, _, BUFFER_SIZE>::new(
+        nodes,
+        &[(0, 1), (1, 2), (2, 0)],
+        SimulationSettings::default(),
+    );
+
+    let report = simulation.run(20.).unwrap();
+
+    // A proportional controller keeps a buffer offset of the crystal error divided by the gain.
+    assert!(report.steady_state_error < 40. / 4.);
+    // Buffer levels are whole words, so the correction of every node moves in steps of the gain.
+    assert!(report.frequency_spread_ppm < 2. * 4.);
+}
+
+#[derive(Default)]
+struct RecordingActuator {
+    offset: I16F16,
+}
+
+impl FrequencyActuator for &mut RecordingActuator {
+    type Error = ();
+
+    fn range(&self) -> RangeInclusive<I16F16> {
+        I16F16::MIN..=I16F16::MAX
+    }
+
+    fn resolution(&self) -> I16F16 {
+        I16F16::DELTA
+    }
+
+    fn apply_latency_us(&self) -> u32 {
+        0
+    }
+
+    fn set_offset_ppm(&mut self, offset: I16F16) -> Result<(), Self::Error> {
+        self.offset = offset;
+        Ok(())
+    }
+
+    fn setting(&self) -> u32 {
+        self.offset.to_bits() as u32
+    }
+}
+
+#[test]
+fn test_proportional_controller_link_mask() {
+    let mut actuator = RecordingActuator::default();
+    let mut controller = Controller::<_, _, 3>::new(
+        ProportionalLaw::new(I16F16::from_num(0.5)).with_weights([
+            I16F16::ONE,
+            I16F16::ONE,
+            I16F16::from_num(2),
+        ]),
+        &mut actuator,
+    );
+
+    FrequencyController::<BUFFER_SIZE>::set_link_mask(&mut controller, &[true, false, true]);
+    FrequencyController::<BUFFER_SIZE>::run(&mut controller, &[138, 0, 120]).unwrap();
+
+    assert_eq!(actuator.offset, I16F16::from_num(-3));
+}
@@ -0,0 +1,108 @@
+mod common;
+
+use common::{minsync_si5351, BUFFER_SIZE};
+use controllers::{
+    controller::{Controller, FrequencyController},
+    proportional::ProportionalLaw,
+    replay::{
+        parse_trace, record_corrections, replay, ActuatorOutput, ReplayActuator, ReplayError,
+        ReplaySettings, TraceError, TraceRecord,
+    },
+    si5351::Si5351FracActuator,
+};
+use fixed::types::I16F16;
+
+/// Correction in ppm per word of a proportional node, large enough that the buffers move within a
+/// short trace.
+const GAIN: i32 = 400;
+/// Frequency of the neighbor relative to the node, in ppm.
+const NEIGHBOR_PPM: f64 = 5000.;
+
+fn proportional(
+    gain: i32,
+) -> (
+    Controller<ProportionalLaw<1>, ReplayActuator, 1>,
+    ActuatorOutput,
+) {
+    let (si, _) = minsync_si5351(0.);
+    let (actuator, output) = ReplayActuator::like(&Si5351FracActuator::new(si));
+
+    (
+        Controller::new(ProportionalLaw::new(I16F16::from_num(gain)), actuator),
+        output,
+    )
+}
+
+/// Records a node with a single link to a faster neighbor, without the corrections.
+fn record(runs: usize) -> Vec<TraceRecord> {
+    let (mut controller, output) = proportional(GAIN);
+    let mut level = (BUFFER_SIZE / 2) as f64;
+    let mut trace = Vec::new();
+
+    for _ in 0..runs {
+        let record = TraceRecord {
+            buffer_levels: vec![level.round() as usize],
+            correction: None,
+        };
+
+        FrequencyController::<BUFFER_SIZE>::run(&mut controller, &record.buffer_levels).unwrap();
+        level += (NEIGHBOR_PPM - output.get().to_num::<f64>()) * 1e-6;
+        trace.push(record);
+    }
+
+    trace
+}
+
+#[test]
+fn test_parse_trace() {
+    let trace = parse_trace("# N E\n128, 130 1.5\n\n127 131 -0.25\n", 2).unwrap();
+
+    assert_eq!(trace.len(), 2);
+    assert_eq!(trace[1].buffer_levels, vec![127, 131]);
+    assert_eq!(trace[1].correction, Some(I16F16::from_num(-0.25)));
+
+    assert_eq!(
+        parse_trace("128 130\n128\n", 2),
+        Err(TraceError::WrongFieldCount { line: 2 })
+    );
+    assert_eq!(
+        parse_trace("128 130 1\n128 130\n", 2),
+        Err(TraceError::InconsistentCorrections { line: 2 })
+    );
+    assert_eq!(
+        parse_trace("128 x\n", 2),
+        Err(TraceError::InvalidLine { line: 1 })
+    );
+    assert_eq!(parse_trace("# empty\n", 2), Err(TraceError::EmptyTrace));
+}
+
+#[test]
+fn test_replay() {
+    let mut trace = record(30_000);
+    let settings = ReplaySettings::default();
+
+    let (mut candidate, output) = proportional(GAIN);
+    assert_eq!(
+        replay::<_, BUFFER_SIZE>(&trace, &mut candidate, &output, settings).unwrap_err(),
+        ReplayError::MissingCorrections
+    );
+
+    let (mut recorder, output) = proportional(GAIN);
+    record_corrections::<_, BUFFER_SIZE>(&mut trace, &mut recorder, &output).unwrap();
+
+    // The node itself follows the trace exactly, and keeps the buffer at the offset of the
+    // neighbor divided by the gain.
+    let (mut candidate, output) = proportional(GAIN);
+    let same = replay::<_, BUFFER_SIZE>(&trace, &mut candidate, &output, settings).unwrap();
+    assert_eq!(same.max_divergence, 0.);
+    assert_eq!(same.first_divergence, None);
+    assert!((same.predicted_steady_state_error - NEIGHBOR_PPM / GAIN as f64).abs() < 1.);
+    assert_eq!(same.predicted_settling_run, None);
+
+    // Twice the gain halves the buffer offset.
+    let (mut candidate, output) = proportional(2 * GAIN);
+    let double = replay::<_, BUFFER_SIZE>(&trace, &mut candidate, &output, settings).unwrap();
+    assert!(double.first_divergence.is_some());
+    assert!(double.rms_divergence > 100.);
+    assert!((double.predicted_steady_state_error - NEIGHBOR_PPM / (2 * GAIN) as f64).abs() < 1.);
+}
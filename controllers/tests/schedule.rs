@@ -0,0 +1,89 @@
+mod common;
+
+use common::{minsync_nodes, pid, schedule, BUFFER_SIZE};
+use controllers::{
+    controller::FrequencyController,
+    pid::PidControl,
+    schedule::{Regime, Scheduler},
+    si5351::Si5351Controller,
+    sim::{Simulation, SimulationReport, SimulationSettings},
+};
+use fixed::types::I16F16;
+
+#[test]
+fn test_scheduler_regimes() {
+    let mut scheduler = Scheduler::new(schedule());
+    let error = |words: i32| Some(I16F16::from_num(words));
+
+    assert_eq!(scheduler.regime(), Regime::Acquire);
+
+    // Locking takes a hundred runs in a row within the threshold.
+    for _ in 0..99 {
+        assert_eq!(scheduler.update(error(-3)), None);
+    }
+    assert_eq!(scheduler.update(error(10)), None);
+    for _ in 0..99 {
+        scheduler.update(error(4));
+    }
+    assert_eq!(scheduler.update(error(0)), Some(Regime::Track));
+
+    // Between the thresholds a locked controller keeps tracking.
+    assert_eq!(scheduler.update(error(-20)), None);
+    assert_eq!(scheduler.update(error(21)), Some(Regime::Acquire));
+
+    assert_eq!(scheduler.update(None), Some(Regime::Holdover));
+    assert_eq!(scheduler.update(None), None);
+    assert_eq!(scheduler.update(error(0)), Some(Regime::Acquire));
+
+    // After a holdover it goes back to the regime it was in before.
+    for _ in 0..98 {
+        scheduler.update(error(0));
+    }
+    assert_eq!(scheduler.update(error(0)), Some(Regime::Track));
+    assert_eq!(scheduler.update(None), Some(Regime::Holdover));
+    assert_eq!(scheduler.update(error(10)), Some(Regime::Track));
+}
+
+#[test]
+fn test_pid_bumpless_transfer() {
+    let mut pid = PidControl::new(pid(1., 0.1, 0.5));
+
+    let mut output = I16F16::ZERO;
+    for _ in 0..20 {
+        output = pid.run(I16F16::ONE, I16F16::ZERO).unwrap();
+    }
+
+    pid.set_settings(common::pid(4., 0.2, 2.));
+    let switched = pid.run(I16F16::ONE, I16F16::ZERO).unwrap();
+
+    // Only the integration of this run changes the output, not the new proportional gain.
+    assert!((switched - output - I16F16::from_num(0.2)).abs() < 0.01);
+}
+
+fn simulate<C: FrequencyController<BUFFER_SIZE>>(
+    controller: impl Fn(controllers::sim::SimulatedSi5351) -> C,
+) -> SimulationReport {
+    // Far enough apart that the buffers move tens of words before the tracking gains catch up.
+    let nodes = minsync_nodes(&[-4000., 3000., 1000.], controller);
+
+    let mut simulation = Simulation::<_, _, BUFFER_SIZE>::new(
+        nodes,
+        &[(0, 1), (1, 2), (2, 0)],
+        SimulationSettings::default(),
+    );
+
+    simulation.run(20.).unwrap()
+}
+
+#[test]
+fn test_schedule_against_fixed_gains() {
+    let schedule = schedule();
+    let track = simulate(|si| Si5351Controller::<_, 2>::with_pid(si, schedule.track));
+    let acquire = simulate(|si| Si5351Controller::<_, 2>::with_pid(si, schedule.acquire));
+    let scheduled = simulate(|si| Si5351Controller::<_, 2>::with_schedule(si, schedule));
+
+    // Acquires about as fast as the aggressive gains, and then stays as calm as the tracking gains.
+    assert!(scheduled.settling_time.unwrap() < track.settling_time.unwrap() / 2.);
+    assert!(scheduled.overshoot < track.overshoot / 2.);
+    assert!(scheduled.frequency_spread_ppm < acquire.frequency_spread_ppm / 2.);
+}
@@ -0,0 +1,367 @@
+use std::{cell::RefCell, rc::Rc};
+
+use controllers::{
+    si5351::{Adjust, Pll, Si5351, Si5351Synth, Si5351Tuning, Synth, SynthRatio},
+    si5351_i2c::{
+        synth_registers, I2cEvent, NonBlockingI2c, SharedSi5351I2c, Si5351I2c, Si5351I2cError,
+    },
+};
+use fixed::types::I16F16;
+
+/// The registers of an Si5351 behind a bus that completes a write when the test says so. It starts out
+/// like the minsync board: output 0 at a multisynth of 75 in integer mode from PLL A at the center frac.
+#[derive(Default)]
+struct MockSi5351 {
+    registers: Vec<u8>,
+    write: Option<Vec<u8>>,
+    event: Option<I2cEvent>,
+    nack: bool,
+    /// Whether reads fail, like when nothing answers at the address.
+    unreadable: bool,
+    /// Register bytes of every completed write.
+    written: Vec<usize>,
+}
+
+impl MockSi5351 {
+    fn new() -> Rc<RefCell<Self>> {
+        let mut registers = vec![0; 256];
+        registers[16] = 0x4f;
+        registers[26..34].copy_from_slice(&synth_registers(35, 0x7ffff, 0xfffff));
+        registers[42..50].copy_from_slice(&synth_registers(75, 0, 1));
+
+        Rc::new(RefCell::new(Self {
+            registers,
+            ..Default::default()
+        }))
+    }
+
+    fn finish(&mut self) {
+        let Some(bytes) = self.write.take() else {
+            return;
+        };
+
+        if self.nack {
+            self.event = Some(I2cEvent::Aborted);
+            return;
+        }
+
+        let start = bytes[0] as usize;
+        self.registers[start..start + bytes.len() - 1].copy_from_slice(&bytes[1..]);
+        self.written.push(bytes.len() - 1);
+        self.event = Some(I2cEvent::Completed);
+    }
+
+    fn pll_a(&self) -> &[u8] {
+        &self.registers[26..34]
+    }
+
+    /// The ratio the synthesizer with registers from `first` runs at, following AN619.
+    fn ratio(&self, first: usize) -> f64 {
+        let r = |i: usize| self.registers[first + i] as u32;
+        let p1 = (r(2) & 0x03) << 16 | r(3) << 8 | r(4);
+        let p2 = (r(5) & 0x0f) << 16 | r(6) << 8 | r(7);
+        let p3 = (r(5) & 0xf0) << 12 | r(0) << 8 | r(1);
+
+        (p1 as f64 + 512. + p2 as f64 / p3 as f64) / 128.
+    }
+
+    /// Frequency of output 0 with a 25 MHz crystal.
+    fn output_0_hz(&self) -> f64 {
+        let control = self.registers[16];
+        let pll = if control & 0x20 == 0 { 26 } else { 34 };
+        let multisynth = self.ratio(42);
+        // In integer mode the fractional part of the multisynth is ignored.
+        let multisynth = if control & 0x40 == 0 {
+            multisynth
+        } else {
+            multisynth.floor()
+        };
+
+        25e6 * self.ratio(pll) / multisynth
+    }
+}
+
+struct MockBus(Rc<RefCell<MockSi5351>>);
+
+impl NonBlockingI2c for MockBus {
+    fn set_target(&mut self, address: u8) {
+        assert_eq!(address, 0x60);
+    }
+
+    fn start_write(&mut self, bytes: &[u8]) -> bool {
+        let mut chip = self.0.borrow_mut();
+
+        if chip.write.is_some() || bytes.len() > 16 {
+            return false;
+        }
+
+        chip.write = Some(bytes.to_vec());
+        true
+    }
+
+    fn take_event(&mut self) -> Option<I2cEvent> {
+        self.0.borrow_mut().event.take()
+    }
+
+    fn read_registers(&mut self, first: u8, buffer: &mut [u8]) -> bool {
+        let chip = self.0.borrow();
+        let first = first as usize;
+
+        buffer.copy_from_slice(&chip.registers[first..first + buffer.len()]);
+        !chip.unreadable
+    }
+
+    // The tests run the interrupt themselves.
+    fn pend_interrupt() {}
+}
+
+fn engine() -> (Si5351I2c<MockBus>, Rc<RefCell<MockSi5351>>) {
+    let chip = MockSi5351::new();
+
+    (Si5351I2c::new(MockBus(chip.clone()), 0x60), chip)
+}
+
+/// Switches output 0 to fractional mode with the clock control the chip starts out with.
+fn fractional_clock_control(control: u8, unreadable: bool) -> u8 {
+    let chip = MockSi5351::new();
+    chip.borrow_mut().registers[16] = control;
+    chip.borrow_mut().unreadable = unreadable;
+    let mut si = Si5351I2c::new(MockBus(chip.clone()), 0x60);
+
+    si.set_offset_ppm(
+        &Si5351Tuning::minsync().adjusting(Adjust::Multisynth),
+        I16F16::ZERO,
+    )
+    .unwrap();
+    settle(&mut si, &chip);
+
+    let control = chip.borrow().registers[16];
+    control
+}
+
+/// Lets the bus finish every write the engine starts.
+fn settle(si: &mut Si5351I2c<MockBus>, chip: &Rc<RefCell<MockSi5351>>) {
+    while chip.borrow().write.is_some() {
+        chip.borrow_mut().finish();
+        si.poll();
+    }
+}
+
+fn applied_frac(si: &Si5351I2c<MockBus>) -> Option<u32> {
+    si.applied().map(|(synth, ratio)| {
+        assert_eq!(synth, Synth::Pll(Pll::A));
+        ratio.numerator
+    })
+}
+
+#[test]
+fn test_synth_registers() {
+    // Integer multiplier 35 with the frac at zero: P1 = 128 * 35 - 512, P2 = 0, P3 = 0xfffff.
+    assert_eq!(
+        synth_registers(35, 0, 0xfffff),
+        [0xff, 0xff, 0x00, 0x0f, 0x80, 0xf0, 0x00, 0x00]
+    );
+}
+
+#[test]
+fn test_only_changed_registers_are_written() {
+    let (mut si, chip) = engine();
+
+    si.set_pll_frac(0x8_0000).unwrap();
+    assert!(!si.frac_applied());
+    chip.borrow_mut().finish();
+    si.poll();
+
+    assert!(si.frac_applied());
+    assert_eq!(applied_frac(&si), Some(0x8_0000));
+    assert_eq!(
+        chip.borrow().pll_a(),
+        synth_registers(35, 0x8_0000, 0xfffff)
+    );
+
+    si.set_pll_frac(0x8_0001).unwrap();
+    chip.borrow_mut().finish();
+    si.poll();
+
+    assert_eq!(
+        chip.borrow().pll_a(),
+        synth_registers(35, 0x8_0001, 0xfffff)
+    );
+    // The first write has no idea what the chip holds, the second only touches P2.
+    assert_eq!(chip.borrow().written[0], 8);
+    assert!(chip.borrow().written[1] <= 3);
+
+    // Nothing changes, so nothing is written.
+    si.set_pll_frac(0x8_0001).unwrap();
+    assert!(si.frac_applied());
+    assert_eq!(chip.borrow().written.len(), 2);
+}
+
+#[test]
+fn test_requests_while_busy_keep_the_latest() {
+    let (mut si, chip) = engine();
+
+    si.set_pll_frac(100).unwrap();
+    si.set_pll_frac(200).unwrap();
+    si.set_pll_frac(300).unwrap();
+
+    chip.borrow_mut().finish();
+    si.poll();
+    assert_eq!(applied_frac(&si), Some(100));
+    assert!(!si.frac_applied());
+
+    chip.borrow_mut().finish();
+    si.poll();
+    assert_eq!(applied_frac(&si), Some(300));
+    assert!(si.frac_applied());
+    assert_eq!(chip.borrow().written.len(), 2);
+}
+
+#[test]
+fn test_failed_write_is_reported_and_retried() {
+    let (mut si, chip) = engine();
+
+    si.set_pll_frac(0x1234).unwrap();
+    chip.borrow_mut().finish();
+    si.poll();
+
+    chip.borrow_mut().nack = true;
+    si.set_pll_frac(0x1235).unwrap();
+    chip.borrow_mut().finish();
+    si.poll();
+
+    assert_eq!(si.failures(), 1);
+    assert_eq!(applied_frac(&si), None);
+
+    chip.borrow_mut().nack = false;
+    assert_eq!(si.set_pll_frac(0x1235), Err(Si5351I2cError::WriteFailed));
+    chip.borrow_mut().finish();
+    si.poll();
+
+    // After a failure the engine cannot trust the chip to hold anything it wrote before.
+    assert!(si.frac_applied());
+    assert_eq!(chip.borrow().written.last(), Some(&8));
+    assert_eq!(chip.borrow().pll_a(), synth_registers(35, 0x1235, 0xfffff));
+}
+
+#[test]
+fn test_shared_requests_pass_to_the_interrupt() {
+    let shared = SharedSi5351I2c::new();
+    let (engine, chip) = engine();
+    let mut si = &shared;
+
+    assert_eq!(si.set_pll_frac(100), Err(Si5351I2cError::Missing));
+    shared.give(engine);
+
+    // A request only reaches the bus once the I2C interrupt runs.
+    si.set_pll_frac(100).unwrap();
+    assert!(chip.borrow().write.is_none());
+    shared.on_interrupt();
+    assert!(chip.borrow().write.is_some());
+    assert!(!si.frac_applied());
+
+    // While the write runs, later requests replace each other.
+    si.set_pll_frac(200).unwrap();
+    shared.on_interrupt();
+    si.set_pll_frac(300).unwrap();
+    shared.on_interrupt();
+
+    chip.borrow_mut().finish();
+    shared.on_interrupt();
+    assert!(!si.frac_applied());
+    chip.borrow_mut().finish();
+    shared.on_interrupt();
+
+    assert!(si.frac_applied());
+    assert_eq!(chip.borrow().pll_a(), synth_registers(35, 300, 0xfffff));
+    assert_eq!(chip.borrow().written.len(), 2);
+
+    // A failure is reported once, by the next request, and the write is retried.
+    chip.borrow_mut().nack = true;
+    si.set_pll_frac(400).unwrap();
+    shared.on_interrupt();
+    chip.borrow_mut().finish();
+    shared.on_interrupt();
+    assert_eq!(shared.failures(), 1);
+    assert!(!si.frac_applied());
+
+    chip.borrow_mut().nack = false;
+    assert_eq!(si.set_pll_frac(400), Err(Si5351I2cError::WriteFailed));
+    shared.on_interrupt();
+    chip.borrow_mut().finish();
+    shared.on_interrupt();
+    assert_eq!(si.set_pll_frac(400), Ok(()));
+    shared.on_interrupt();
+
+    assert!(si.frac_applied());
+    assert_eq!(chip.borrow().pll_a(), synth_registers(35, 400, 0xfffff));
+}
+
+/// Sets a range of offsets through the tuning and measures them on the output of the mock.
+fn offsets_reach_the_output(tuning: Si5351Tuning) -> Rc<RefCell<MockSi5351>> {
+    let (mut si, chip) = engine();
+
+    si.set_offset_ppm(&tuning, I16F16::ZERO).unwrap();
+    settle(&mut si, &chip);
+    let nominal = chip.borrow().output_0_hz();
+    assert!((nominal - tuning.nominal_hz() as f64).abs() < 1.);
+
+    for offset in [-2000., -12.5, 0.3, 1., 250., 9000.] {
+        si.set_offset_ppm(&tuning, I16F16::from_num(offset))
+            .unwrap();
+        settle(&mut si, &chip);
+
+        let measured = (chip.borrow().output_0_hz() / nominal - 1.) * 1e6;
+        assert!(
+            (measured - offset).abs() <= tuning.resolution().to_num::<f64>(),
+            "{offset} ppm measured as {measured} ppm"
+        );
+    }
+
+    chip
+}
+
+#[test]
+fn test_tuning_pll() {
+    let tuning = Si5351Tuning::minsync();
+
+    // The old frac actuator has the same center and steps.
+    assert_eq!(tuning.ratio(I16F16::ZERO), SynthRatio::pll_frac(0x7ffff));
+    assert!(tuning.resolution() < 0.03);
+
+    offsets_reach_the_output(tuning);
+}
+
+#[test]
+fn test_tuning_multisynth() {
+    let tuning = Si5351Tuning::minsync().adjusting(Adjust::Multisynth);
+
+    // The multisynth divides by more than the PLL multiplies, so its steps are finer.
+    assert!(tuning.resolution() < Si5351Tuning::minsync().resolution());
+
+    let chip = offsets_reach_the_output(tuning);
+
+    // The PLL is never touched and the multisynth was switched to fractional mode.
+    assert_eq!(chip.borrow().pll_a(), synth_registers(35, 0x7ffff, 0xfffff));
+    assert_eq!(chip.borrow().registers[16] & 0x40, 0);
+}
+
+#[test]
+fn test_tuning_for_frequency() {
+    let tuning = Si5351Tuning::for_frequency(25_000_000, 12_000_000);
+
+    assert_eq!(tuning.nominal_hz(), 12_000_000);
+    assert!(tuning.range().contains(&I16F16::from_num(-10_000)));
+    assert!(tuning.range().contains(&I16F16::from_num(10_000)));
+}
+
+#[test]
+fn test_fractional_mode_keeps_the_clock_control() {
+    // Inverted, at 2 mA: only the integer mode bit changes.
+    assert_eq!(fractional_clock_control(0x5c, false), 0x1c);
+    // Powered down stays powered down.
+    assert_eq!(fractional_clock_control(0xcf, false), 0x8f);
+
+    // Without knowing what the board set up, the output is powered up at 8 mA.
+    assert_eq!(fractional_clock_control(0x5c, true), 0x0f);
+}
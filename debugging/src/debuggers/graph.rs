@@ -16,10 +16,10 @@ use minsync::display::{DEFAULT_TEXT_STYLE, ON_STYLE};
 
 use crate::BittideControlDebugger;
 
-#[derive(Debug, Default)]
-pub struct GraphDebugger {
+#[derive(Debug)]
+pub struct GraphDebugger<const DEGREE: usize> {
     settings: GraphDebuggerSettings,
-    buffer_levels: [AtomicU32; 4],
+    buffer_levels: [AtomicU32; DEGREE],
     error: AtomicU32,
     rx_sync_message_counter: AtomicU32,
     rx_comm_message_counter: AtomicU32,
@@ -32,16 +32,11 @@ pub struct GraphDebuggerSettings {
     pub buffer_size: usize,
 }
 
-impl GraphDebugger {
+impl<const DEGREE: usize> GraphDebugger<DEGREE> {
     pub const fn new(settings: GraphDebuggerSettings) -> Self {
         Self {
             settings,
-            buffer_levels: [
-                AtomicU32::new(0),
-                AtomicU32::new(1),
-                AtomicU32::new(2),
-                AtomicU32::new(3),
-            ],
+            buffer_levels: [const { AtomicU32::new(0) }; DEGREE],
             error: AtomicU32::new(0),
             rx_sync_message_counter: AtomicU32::new(0),
             rx_comm_message_counter: AtomicU32::new(0),
@@ -51,10 +46,10 @@ impl GraphDebugger {
     }
 }
 
-impl BittideControlDebugger<Si5351Debug> for GraphDebugger {
+impl<const DEGREE: usize> BittideControlDebugger<Si5351Debug, DEGREE> for GraphDebugger<DEGREE> {
     fn update(
         &self,
-        debug_info: &BittideChannelControlDebugInfo<Si5351Debug>,
+        debug_info: &BittideChannelControlDebugInfo<Si5351Debug, DEGREE>,
         result: Result<(), BittideChannelControlError>,
     ) {
         for (level, atomic) in debug_info
@@ -81,12 +76,12 @@ impl BittideControlDebugger<Si5351Debug> for GraphDebugger {
         );
 
         self.pll_frac.store(
-            debug_info.frequency_controller_debug.frac,
+            debug_info.frequency_controller_debug.setting,
             atomic::Ordering::Relaxed,
         );
 
         self.pid_adjust.store(
-            debug_info.frequency_controller_debug.adjust,
+            debug_info.frequency_controller_debug.law.output.to_bits(),
             atomic::Ordering::Relaxed,
         );
     }
@@ -2,7 +2,7 @@ use core::fmt::Write;
 use core::sync::atomic::{self, AtomicI32, AtomicU32};
 
 use bittide::bittide::{BittideChannelControlDebugInfo, BittideChannelControlError};
-use controllers::si5351::Si5351Debug;
+use controllers::{controller::FaultReason, si5351::Si5351Debug};
 use embedded_graphics::{
     pixelcolor::BinaryColor,
     prelude::{DrawTarget, Point, Size},
@@ -15,26 +15,30 @@ use minsync::display::DEFAULT_TEXT_STYLE;
 
 use crate::BittideControlDebugger;
 
-#[derive(Debug, Default)]
-pub struct TextDebugger {
-    buffer_levels_a: [AtomicU32; 4],
+/// Labels for the links of a node with four neighbors, in link order.
+pub const CARDINALS: [&str; 4] = ["N", "E", "S", "W"];
+
+#[derive(Debug)]
+pub struct TextDebugger<const DEGREE: usize> {
+    labels: [&'static str; DEGREE],
+    buffer_levels_a: [AtomicU32; DEGREE],
     error: AtomicU32,
+    /// Code of the saturation of the controller, 0 while it is not saturated.
+    saturation: AtomicU32,
     rx_sync_message_counter: AtomicU32,
     rx_comm_message_counter: AtomicU32,
     pll_frac: AtomicU32,
     pid_adjust: AtomicI32,
 }
 
-impl TextDebugger {
-    pub const fn new() -> Self {
+impl<const DEGREE: usize> TextDebugger<DEGREE> {
+    /// `labels` are drawn in front of the buffer level of each link, e.g. [`CARDINALS`].
+    pub const fn new(labels: [&'static str; DEGREE]) -> Self {
         Self {
-            buffer_levels_a: [
-                AtomicU32::new(0),
-                AtomicU32::new(1),
-                AtomicU32::new(2),
-                AtomicU32::new(3),
-            ],
+            labels,
+            buffer_levels_a: [const { AtomicU32::new(0) }; DEGREE],
             error: AtomicU32::new(0),
+            saturation: AtomicU32::new(0),
             rx_sync_message_counter: AtomicU32::new(0),
             rx_comm_message_counter: AtomicU32::new(0),
             pll_frac: AtomicU32::new(0),
@@ -43,10 +47,10 @@ impl TextDebugger {
     }
 }
 
-impl BittideControlDebugger<Si5351Debug> for TextDebugger {
+impl<const DEGREE: usize> BittideControlDebugger<Si5351Debug, DEGREE> for TextDebugger<DEGREE> {
     fn update(
         &self,
-        debug_info: &BittideChannelControlDebugInfo<Si5351Debug>,
+        debug_info: &BittideChannelControlDebugInfo<Si5351Debug, DEGREE>,
         result: Result<(), BittideChannelControlError>,
     ) {
         for (level, atomic) in debug_info
@@ -62,6 +66,15 @@ impl BittideControlDebugger<Si5351Debug> for TextDebugger {
             atomic::Ordering::Relaxed,
         );
 
+        self.saturation.store(
+            debug_info
+                .frequency_controller_debug
+                .health
+                .reason()
+                .map_or(0, |reason| reason.code() as u32),
+            atomic::Ordering::Relaxed,
+        );
+
         self.rx_comm_message_counter.store(
             debug_info.rx_comm_message_counter,
             atomic::Ordering::Relaxed,
@@ -73,12 +86,12 @@ impl BittideControlDebugger<Si5351Debug> for TextDebugger {
         );
 
         self.pll_frac.store(
-            debug_info.frequency_controller_debug.frac,
+            debug_info.frequency_controller_debug.setting,
             atomic::Ordering::Relaxed,
         );
 
         self.pid_adjust.store(
-            debug_info.frequency_controller_debug.adjust,
+            debug_info.frequency_controller_debug.law.output.to_bits(),
             atomic::Ordering::Relaxed,
         );
     }
@@ -94,12 +107,10 @@ impl BittideControlDebugger<Si5351Debug> for TextDebugger {
 
         let mut buffer_texts = String::<22>::new();
 
-        let cardinals = ["N", "E", "S", "W"];
-
-        for (buffer_level, cardinal) in self.buffer_levels_a.iter().zip(cardinals.iter()) {
+        for (buffer_level, label) in self.buffer_levels_a.iter().zip(self.labels.iter()) {
             let mut buffer = itoa::Buffer::new();
             let i_as_str = buffer.format(buffer_level.load(atomic::Ordering::Relaxed));
-            buffer_texts.push_str(cardinal).ok();
+            buffer_texts.push_str(label).ok();
             buffer_texts.push_str(i_as_str).ok();
             buffer_texts.push(' ').ok();
         }
@@ -124,7 +135,11 @@ impl BittideControlDebugger<Si5351Debug> for TextDebugger {
 
         match error {
             Ok(()) => {
-                line_two.push_str("Ok()").ok();
+                match FaultReason::from_code(self.saturation.load(atomic::Ordering::Relaxed) as u8)
+                {
+                    Some(reason) => write!(&mut line_two, "{:?}", reason).ok(),
+                    None => line_two.push_str("Ok()").ok(),
+                };
             }
             Err(err) => {
                 write!(&mut line_two, "{:?}", err).ok();
@@ -8,10 +8,10 @@ use embedded_graphics::{
 
 pub mod debuggers;
 
-pub trait BittideControlDebugger<F> {
+pub trait BittideControlDebugger<F, const DEGREE: usize> {
     fn update(
         &self,
-        debug_info: &BittideChannelControlDebugInfo<F>,
+        debug_info: &BittideChannelControlDebugInfo<F, DEGREE>,
         result: Result<(), BittideChannelControlError>,
     );
 
@@ -130,16 +130,16 @@ fn main() -> ! {
     let (tx_pio, tx_sm0, tx_sm1, tx_sm2, tx_sm3) = pac.PIO1.split(&mut pac.RESETS);
 
     let rx0_data = pins.gpio3.into_function::<FunctionPio0>().into_dyn_pin();
-    let rx0_clk = pins.gpio4.into_function::<FunctionPio0>().into_dyn_pin();
-    let rx0_word = pins.gpio5.into_function::<FunctionPio0>().into_dyn_pin();
+    let rx0_word = pins.gpio4.into_function::<FunctionPio0>().into_dyn_pin();
+    let rx0_clk = pins.gpio5.into_function::<FunctionPio0>().into_dyn_pin();
 
     let rx1_data = pins.gpio9.into_function::<FunctionPio0>().into_dyn_pin();
-    let rx1_clk = pins.gpio10.into_function::<FunctionPio0>().into_dyn_pin();
-    let rx1_word = pins.gpio11.into_function::<FunctionPio0>().into_dyn_pin();
+    let rx1_word = pins.gpio10.into_function::<FunctionPio0>().into_dyn_pin();
+    let rx1_clk = pins.gpio11.into_function::<FunctionPio0>().into_dyn_pin();
 
     let rx2_data = pins.gpio15.into_function::<FunctionPio0>().into_dyn_pin();
-    let rx2_clk = pins.gpio16.into_function::<FunctionPio0>().into_dyn_pin();
-    let rx2_word = pins.gpio17.into_function::<FunctionPio0>().into_dyn_pin();
+    let rx2_word = pins.gpio16.into_function::<FunctionPio0>().into_dyn_pin();
+    let rx2_clk = pins.gpio17.into_function::<FunctionPio0>().into_dyn_pin();
 
     let rx3_data = pins.gpio23.into_function::<FunctionPio0>().into_dyn_pin();
     let rx3_clk = pins.gpio24.into_function::<FunctionPio0>().into_dyn_pin();
@@ -231,9 +231,8 @@ fn main() -> ! {
     ];
 
     let tide_controller = bittide_impls::boards::pico1_and_si5351::Control::new(
-        Si5351Controller::new(
+        Si5351Controller::with_pid(
             si_clock,
-            4,
             PidSettings {
                 kp: I16F16::from_num(0.01),
                 ki: I16F16::from_num(0.00000001),
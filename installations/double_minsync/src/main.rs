@@ -12,8 +12,16 @@ use core::sync::atomic::{self, AtomicI32, AtomicU32};
 
 use bittide::bittide::{BittideChannelControlDebugInfo, BittideChannelControlError};
 use bittide_impls::boards::minsync_v02::{MinsyncPins, MinsyncV02};
+use bittide_impls::calibration::{load_calibration, store_calibration, Rp2040FrequencyCounter};
+use bittide_impls::chips::rp2040::NO_MESSAGE_LIMIT;
+use bittide_impls::interrupt::InterruptOwned;
+use controllers::autotune::RelaySettings;
+use controllers::calibration::calibrate;
+use controllers::parameters::{ControllerParameters, ParameterConsole, ParameterStore};
 use controllers::pid::PidSettings;
-use controllers::si5351::{Si5351Controller, Si5351Debug};
+use controllers::schedule::GainSchedule;
+use controllers::si5351::{Si5351Controller, Si5351Debug, Si5351FracActuator};
+use controllers::si5351_i2c::{SharedSi5351I2c, Si5351I2c, SI5351_ADDRESS};
 use cortex_m_rt::exception;
 use critical_section::Mutex;
 use debugging::debuggers::graph::{GraphDebugger, GraphDebuggerSettings};
@@ -21,7 +29,6 @@ use debugging::debuggers::text::TextDebugger;
 use debugging::BittideControlDebugger;
 #[allow(unused_imports)]
 use defmt::{error, info, warn};
-use defmt_rtt as _;
 use embedded_graphics::prelude::DrawTarget;
 use embedded_graphics::primitives::{PrimitiveStyle, StyledDrawable};
 use embedded_graphics::{
@@ -38,9 +45,14 @@ use heapless::String;
 use minsync::display::{draw_key_integral, draw_key_value, DEFAULT_TEXT_STYLE};
 use minsync::si_i2c;
 use panic_probe as _;
+use pitopi::bert::Prbs;
+use pitopi::rate::{cycles_per_bit, cycles_per_word};
+use rtt_target::rtt_init;
 
 use minsync::hal;
 use minsync::hal::pac;
+use minsync::hal::pac::interrupt;
+use minsync::hal::Clock;
 use minsync::{entry, hal::Watchdog};
 
 mod generated_constants;
@@ -51,8 +63,30 @@ pub const EXTERNAL_XTAL_FREQ_HZ: HertzU32 = HertzU32::from_raw(12_000_000u32);
 // pub const CLOCKS_PER_SYNC_WORD: u32 = 4096;
 pub const CLOCKS_PER_SYNC_WORD: u32 = 700_000;
 
+/// The reference clock runs from the ring oscillator, which is only roughly this fast.
+const REFERENCE_KHZ: u32 = 6_500;
+/// Measurements averaged for every point of the calibration sweep.
+const CALIBRATION_SAMPLES: u32 = 8;
+/// Far beyond the crystal tolerance, so the relay turns the buffers around quickly. A buffer moves
+/// by a millionth of a word per run and ppm, so at this amplitude a cycle takes about 30000 runs.
+const RELAY_AMPLITUDE_PPM: I16F16 = I16F16::lit("200");
+
 #[entry]
 fn main_pitopi_test() -> ! {
+    let channels = rtt_init! {
+        up: {
+            0: { size: 1024, name: "defmt" }
+            1: { size: 512, name: "params" }
+        }
+        down: {
+            0: { size: 64, name: "params" }
+        }
+    };
+    rtt_target::set_defmt_channel(channels.up.0);
+    let mut parameter_replies = channels.up.1;
+    let mut parameter_commands = channels.down.0;
+    let mut console = ParameterConsole::new();
+
     let mut pac = pac::Peripherals::take().unwrap();
     let mut core = pac::CorePeripherals::take().unwrap();
     let sio = hal::Sio::new(pac.SIO);
@@ -70,8 +104,8 @@ fn main_pitopi_test() -> ! {
 
     let mut clocks = minsync::clocks::minimal_clock_setup(pac.CLOCKS, pac.ROSC, pins.rest.gpout3)
         .expect("Failed to do minimal clock setup.");
-    let si_clock = minsync::clocks::setup_si_as_crystal(si_i2c!(pac, pins.rest, clocks, 1.kHz()))
-        .expect("Failed to setup Si5351");
+    let mut si_i2c = si_i2c!(pac, pins.rest, clocks, 1.kHz());
+    minsync::clocks::setup_si_as_crystal_on(&mut si_i2c).expect("Failed to setup Si5351");
     minsync::clocks::setup_pll_and_sysclk(&mut clocks, pac.PLL_SYS, &mut pac.XOSC, &mut pac.RESETS);
     // minsync::clocks::setup_pll(&mut clocks, pac.PLL_SYS, &mut pac.XOSC, &mut pac.RESETS).unwrap();
 
@@ -108,18 +142,89 @@ fn main_pitopi_test() -> ! {
         KI.to_bits()
     );
 
-    let frequency_controller = Si5351Controller::new(
-        si_clock,
-        4,
-        PidSettings {
-            kp: KP,
-            ki: KD,
-            kd: KI,
+    // From here on the frac is written without blocking, the I2C1 interrupt starts every write as soon
+    // as the previous one has finished.
+    SI5351.give(Si5351I2c::new(si_i2c, SI5351_ADDRESS));
+    unsafe { pac::NVIC::unmask(pac::Interrupt::I2C1_IRQ) };
+
+    if generated_constants::CALIBRATE {
+        draw_key_value(&mut display, 2, "calibrating", "...").unwrap();
+        display.flush().unwrap();
+
+        let mut counter = Rp2040FrequencyCounter::new(REFERENCE_KHZ);
+        match calibrate(
+            &mut Si5351FracActuator::new(&SI5351),
+            &mut counter,
+            CALIBRATION_SAMPLES,
+        ) {
+            Ok(table) => {
+                for point in table.points() {
+                    info!(
+                        "calibration {}ppm -> {}ppm",
+                        point.offset.to_num::<f32>(),
+                        point.measured.to_num::<f32>()
+                    );
+                }
+                store_calibration(&table);
+            }
+            Err(err) => error!("Calibration failed: {}", err),
+        }
+    }
+
+    let calibration = load_calibration();
+    match &calibration {
+        Some(table) => info!("Loaded calibration at {}Hz", table.nominal_hz()),
+        None => warn!("No calibration in flash, using the nominal frac steps"),
+    }
+
+    // The gains above track a locked network. Far from lock the buffers move faster than those gains
+    // follow, so acquisition runs ten times harder, and without links the frequency holds.
+    let track = PidSettings {
+        kp: KP,
+        ki: KD,
+        kd: KI,
+    };
+    let schedule = GainSchedule {
+        acquire: PidSettings {
+            kp: track.kp * 10,
+            ki: track.ki * 10,
+            kd: track.kd * 10,
         },
-    );
+        track,
+        holdover: PidSettings {
+            kp: I16F16::ZERO,
+            ki: I16F16::ZERO,
+            kd: I16F16::ZERO,
+        },
+        lock_threshold: I16F16::from_num(4),
+        unlock_threshold: I16F16::from_num(16),
+        lock_runs: 1000,
+    };
+
+    let mut frequency_controller =
+        Si5351Controller::with_schedule(&SI5351, schedule).with_calibration(calibration);
+
+    // What the controller starts with, which the host can read back and change over RTT.
+    PARAMETERS.init(ControllerParameters {
+        gains: track,
+        setpoint: I16F16::ZERO,
+        clamp_min: I16F16::MIN,
+        clamp_max: I16F16::MAX,
+        no_message_limit: NO_MESSAGE_LIMIT as u16,
+    });
+
+    if generated_constants::AUTOTUNE {
+        frequency_controller.start_autotune(RelaySettings {
+            amplitude: RELAY_AMPLITUDE_PPM,
+            hysteresis: I16F16::ONE,
+            cycles: 4,
+            timeout_runs: 1_000_000,
+        });
+    }
 
-    let bittide_controller = MinsyncV02::setup(
+    let mut bittide_controller = MinsyncV02::setup(
         link_mask,
+        generated_constants::NEGOTIATE_LINK_RATES,
         frequency_controller,
         pins.link,
         pac.PIO0,
@@ -128,51 +233,188 @@ fn main_pitopi_test() -> ! {
         sio.fifo,
     );
 
-    critical_section::with(|cs| {
-        GLOBAL_CONTROL.borrow(cs).replace(Some(bittide_controller));
-    });
+    let system_hz = clocks.system_clock.freq().to_Hz();
+    for (link, divisor) in bittide_controller
+        .links_mut()
+        .bit_clock_divisors()
+        .into_iter()
+        .enumerate()
+        .filter(|&(link, _)| link_mask[link])
+    {
+        info!(
+            "Link {} at {} bit/s, divisor {}",
+            link,
+            system_hz / cycles_per_bit(divisor),
+            divisor
+        );
+    }
+
+    if generated_constants::BERT {
+        let prbs = Prbs::from_order(generated_constants::BERT_PRBS as u32)
+            .expect("BERT_PRBS should be 7, 15 or 31");
+        let links = bittide_controller.links_mut();
+        let mut words = [0; 4];
+        for (link, divisor) in links.bit_clock_divisors().into_iter().enumerate() {
+            if link_mask[link] {
+                words[link] = (generated_constants::BERT_SECONDS as u64 * system_hz as u64
+                    / cycles_per_word(divisor) as u64) as u32;
+            }
+        }
+
+        draw_key_value(&mut display, 2, "bert", "...").unwrap();
+        display.flush().unwrap();
+
+        for (link, result) in links.run_bert(prbs, words).into_iter().enumerate() {
+            if link_mask[link] {
+                info!(
+                    "Link {} {}: {} bits, {} errors, BER {}, {} resyncs, {}",
+                    link,
+                    prbs,
+                    result.bits,
+                    result.errors,
+                    result.ber(),
+                    result.resyncs,
+                    result.rx_errors
+                );
+            }
+        }
+    }
+
+    CONTROL.give(bittide_controller);
 
     bittide_impls::chips::rp2040::setup_interrupt(CLOCKS_PER_SYNC_WORD, &mut core.SYST);
 
     #[allow(unused_variables, unused_mut)]
     let mut led = pins.rest.led_or_si_clk1.into_push_pull_output();
 
+    let mut iteration: u32 = 0;
+
     loop {
         // led.toggle().unwrap();
 
+        if let Some((debug_info, result)) = CONTROL.debug() {
+            DEBUG.update(&debug_info, result);
+        }
+
         DEBUG.draw(&mut display, Point::new(0, 9)).ok();
         display.flush().ok();
+
+        let mut command = [0; 64];
+        let received = parameter_commands.read(&mut command);
+        if received > 0 {
+            let reply = console.receive(&command[..received], &PARAMETERS);
+            parameter_replies.write(reply.as_bytes());
+        }
+
+        iteration = iteration.wrapping_add(1);
+        if iteration % 64 == 0 {
+            info!(
+                "SysTick overhead {} cycles, bittide algo {} cycles, handoff {} cycles, locked handoff {} cycles",
+                OVERHEAD_CYCLES.load(atomic::Ordering::Relaxed),
+                CONTROL_CYCLES.load(atomic::Ordering::Relaxed),
+                HANDOFF_CYCLES.load(atomic::Ordering::Relaxed),
+                LOCKED_HANDOFF_CYCLES.load(atomic::Ordering::Relaxed)
+            );
+        }
     }
 }
 
-static GLOBAL_CONTROL: Mutex<RefCell<Option<bittide_impls::boards::minsync_v02::Control>>> =
+type DebugSnapshot = (
+    BittideChannelControlDebugInfo<Si5351Debug, 4>,
+    Result<(), BittideChannelControlError>,
+);
+
+static SI5351: SharedSi5351I2c<minsync::clocks::SiI2C> = SharedSi5351I2c::new();
+
+static CONTROL: InterruptOwned<bittide_impls::boards::minsync_v02::Control, DebugSnapshot> =
+    InterruptOwned::new();
+
+/// Parameters published by the main loop, applied by the SysTick handler before a run.
+static PARAMETERS: ParameterStore = ParameterStore::new();
+
+/// Cycles spent in the SysTick handler outside of the control algorithm, measured on the last tick.
+static OVERHEAD_CYCLES: AtomicU32 = AtomicU32::new(0);
+/// Cycles spent in the control algorithm, measured on the last tick.
+static CONTROL_CYCLES: AtomicU32 = AtomicU32::new(0);
+/// Cycles `take_into` took to hand the control object to the handler, measured on the last tick.
+static HANDOFF_CYCLES: AtomicU32 = AtomicU32::new(0);
+/// Cycles the handoff took before `InterruptOwned`, with a critical section and a `RefCell` borrow on
+/// every tick. Measured every `LOCKED_HANDOFF_INTERVAL` ticks on `LOCKED_HANDOFF`.
+static LOCKED_HANDOFF_CYCLES: AtomicU32 = AtomicU32::new(0);
+/// Stands in for the static the control object used to live in. Never holds it, as borrowing the
+/// `RefCell` costs the same either way.
+static LOCKED_HANDOFF: Mutex<RefCell<Option<bittide_impls::boards::minsync_v02::Control>>> =
     Mutex::new(RefCell::new(None));
+/// Ticks between two measurements of the old handoff, so it costs next to nothing on average.
+const LOCKED_HANDOFF_INTERVAL: u32 = 1024;
 
-pub static DEBUG: GraphDebugger = GraphDebugger::new(GraphDebuggerSettings {
+pub static DEBUG: GraphDebugger<4> = GraphDebugger::new(GraphDebuggerSettings {
     buffer_size: bittide_impls::boards::minsync_v02::BUFFER_SIZE,
 });
 
+#[interrupt]
+#[allow(non_snake_case)]
+fn I2C1_IRQ() {
+    SI5351.on_interrupt();
+}
+
 #[exception]
 fn SysTick() {
-    critical_section::with(|cs| {
-        let mut refcell = GLOBAL_CONTROL
-            .borrow(cs)
-            .try_borrow_mut()
-            .expect("Control algorithm cannot keep up, already borrowed");
-        let mut control = refcell.take().expect("control not initialized.");
-
-        // safe because we're only going to be reading systick
-        let core = unsafe { pac::CorePeripherals::steal() };
-        let start = core.SYST.cvr.read();
-        let result = control.interrupt();
-        let end = core.SYST.cvr.read();
-
-        // info!("bittide algo took {} cycles", start - end);
+    static mut OWNED: Option<bittide_impls::boards::minsync_v02::Control> = None;
+    static mut TICKS: u32 = 0;
+
+    // safe because we're only going to be reading systick
+    let core = unsafe { pac::CorePeripherals::steal() };
+    let entry = core.SYST.cvr.read();
+
+    // safe because SysTick is the only caller, and cannot preempt itself
+    let Some(owner) = (unsafe { CONTROL.take_into(OWNED) }) else {
+        return;
+    };
+    let taken = core.SYST.cvr.read();
+
+    // The handoff as it was before, timed the same way and left out of the overhead.
+    *TICKS = TICKS.wrapping_add(1);
+    if *TICKS % LOCKED_HANDOFF_INTERVAL == 0 {
+        let before = core.SYST.cvr.read();
+        critical_section::with(|cs| {
+            let _control = LOCKED_HANDOFF
+                .borrow(cs)
+                .try_borrow_mut()
+                .expect("Control algorithm cannot keep up, already borrowed");
+        });
+        LOCKED_HANDOFF_CYCLES.store(
+            before.wrapping_sub(core.SYST.cvr.read()),
+            atomic::Ordering::Relaxed,
+        );
+    }
+    let locked = core.SYST.cvr.read();
+
+    let gains = owner.control.frequency_controller_mut().gains();
+    PARAMETERS.apply_pending(gains, |parameters| {
+        owner
+            .control
+            .frequency_controller_mut()
+            .set_parameters(parameters);
+        owner
+            .control
+            .links_mut()
+            .set_no_message_limit(parameters.no_message_limit as usize);
+    });
 
-        DEBUG.update(control.debug(), result);
+    let start = core.SYST.cvr.read();
+    let result = owner.control.interrupt();
+    let end = core.SYST.cvr.read();
 
-        // TODO: visualize freq stabilizer
+    let debug_info = *owner.control.debug();
+    owner.publish((debug_info, result));
 
-        *refcell = Some(control);
-    });
+    // SYST counts down from the reload value that triggered this handler, which is shorter than a tick.
+    let exit = core.SYST.cvr.read();
+    CONTROL_CYCLES.store(start.wrapping_sub(end), atomic::Ordering::Relaxed);
+    HANDOFF_CYCLES.store(entry.wrapping_sub(taken), atomic::Ordering::Relaxed);
+    OVERHEAD_CYCLES.store(
+        entry.wrapping_sub(taken) + locked.wrapping_sub(start) + end.wrapping_sub(exit),
+        atomic::Ordering::Relaxed,
+    );
 }
@@ -0,0 +1,137 @@
+#![no_std]
+#![no_main]
+
+use core::fmt::Write;
+
+use bittide_impls::boards::minsync_v02::{MinsyncLinks, MinsyncPins, MinsyncV02};
+use defmt::{error, info};
+use defmt_rtt as _;
+use fugit::RateExtU32;
+use heapless::String;
+use minsync::display::draw_key_value;
+use minsync::hal::pac;
+use minsync::{display_i2c, hal, si_i2c};
+use minsync::{entry, hal::Watchdog};
+use panic_probe as _;
+use pitopi::loopback::{poll_loopback, LoopbackResult, LoopbackTest, PATTERN_WORDS};
+
+/// Rounds of test patterns sent over every link.
+const TEST_ROUNDS: u32 = 64;
+
+const LINK_NAMES: [&str; 4] = ["N", "E", "S", "W"];
+
+fn verdict(passed: bool) -> &'static str {
+    if passed {
+        "pass"
+    } else {
+        "FAIL"
+    }
+}
+
+#[entry]
+fn main() -> ! {
+    let mut pac = pac::Peripherals::take().unwrap();
+    let sio = hal::Sio::new(pac.SIO);
+
+    let watchdog = Watchdog::new(pac.WATCHDOG);
+    watchdog.disable();
+
+    let pins: MinsyncPins = minsync::Pins::new(
+        pac.IO_BANK0,
+        pac.PADS_BANK0,
+        sio.gpio_bank0,
+        &mut pac.RESETS,
+    )
+    .into();
+
+    // Runs from the ring oscillator until the Si5351 is up, and stays there if it is not.
+    let mut clocks = minsync::clocks::minimal_clock_setup(pac.CLOCKS, pac.ROSC, pins.rest.gpout3)
+        .expect("Failed to do minimal clock setup.");
+    let mut si_i2c = si_i2c!(pac, pins.rest, clocks, 1.kHz());
+    let si5351_passed = match minsync::clocks::setup_si_as_crystal_on(&mut si_i2c) {
+        Ok(()) => {
+            minsync::clocks::setup_pll_and_sysclk(
+                &mut clocks,
+                pac.PLL_SYS,
+                &mut pac.XOSC,
+                &mut pac.RESETS,
+            );
+            true
+        }
+        Err(err) => {
+            error!("Si5351 failed: {}", defmt::Debug2Format(&err));
+            false
+        }
+    };
+    info!("Si5351 {}", verdict(si5351_passed));
+
+    let mut display = match minsync::display::setup(display_i2c!(pac, pins.rest, clocks, 100.kHz()))
+    {
+        Ok(display) => Some(display),
+        Err(err) => {
+            error!("OLED failed: {}", err);
+            None
+        }
+    };
+
+    if let Some(display) = &mut display {
+        draw_key_value(display, 0, "self-test", "links...").unwrap();
+        display.flush().unwrap();
+    }
+
+    let MinsyncLinks {
+        north: (_, mut rx0, _, mut tx0),
+        east: (_, mut rx1, _, mut tx1),
+        south: (_, mut rx2, _, mut tx2),
+        west: (_, mut rx3, _, mut tx3),
+    } = MinsyncV02::setup_links(
+        pitopi::DEFAULT_LINK_CONFIG,
+        pins.link,
+        pac.PIO0,
+        pac.PIO1,
+        &mut pac.RESETS,
+    );
+
+    let mut tests = [(); 4].map(|_| LoopbackTest::new(TEST_ROUNDS * PATTERN_WORDS));
+
+    while !tests.iter().all(LoopbackTest::is_done) {
+        let [north, east, south, west] = &mut tests;
+
+        poll_loopback(north, &mut rx0, &mut tx0);
+        poll_loopback(east, &mut rx1, &mut tx1);
+        poll_loopback(south, &mut rx2, &mut tx2);
+        poll_loopback(west, &mut rx3, &mut tx3);
+    }
+
+    let results = tests.map(|test| test.result());
+
+    for (name, result) in LINK_NAMES.iter().zip(&results) {
+        info!("Link {} {}: {}", name, verdict(result.passed()), result);
+    }
+
+    if let Some(display) = &mut display {
+        draw_key_value(display, 0, "si5351", verdict(si5351_passed)).unwrap();
+
+        for (line, pair) in results.chunks(2).enumerate() {
+            let mut text: String<22> = String::new();
+            let names = &LINK_NAMES[2 * line..];
+
+            for (name, result) in names.iter().zip(pair) {
+                write!(text, "{} {} ", name, verdict(result.passed())).ok();
+            }
+
+            draw_key_value(display, line as i32 + 1, "link", text.trim_end()).unwrap();
+        }
+
+        display.flush().unwrap();
+    }
+
+    info!(
+        "Self-test {}",
+        verdict(si5351_passed && display.is_some() && results.iter().all(LoopbackResult::passed))
+    );
+
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
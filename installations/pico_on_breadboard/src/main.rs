@@ -6,13 +6,10 @@
 #[used]
 pub static BOOT2_FIRMWARE: [u8; 256] = rp2040_boot2::BOOT_LOADER_W25Q080;
 
-use core::cell::RefCell;
-
-use controllers::fbdiv::FbdivController;
+use controllers::fbdiv::{Dithering, FbdivController};
 use controllers::pid::PidSettings;
 use cortex_m::asm;
 use cortex_m_rt::exception;
-use critical_section::Mutex;
 #[allow(unused_imports)]
 use defmt::{error, info, warn};
 use defmt_rtt as _;
@@ -42,7 +39,8 @@ use rp_pico::{
 };
 
 use bittide::bittide::BittideFifo;
-use bittide_impls::chips::rp2040::Rp2040Links;
+use bittide_impls::chips::rp2040::Rp2040DualLinks;
+use bittide_impls::interrupt::InterruptOwned;
 
 mod generated_constants;
 
@@ -116,24 +114,17 @@ fn main() -> ! {
         clocks.clk_gpout0_div().write(|w| w.int().variant(1000));
     };
 
-    let (rx_pio, rx_sm0, rx_sm1, rx_sm2, rx_sm3) = pac.PIO0.split(&mut pac.RESETS);
-    let (tx_pio, tx_sm0, tx_sm1, tx_sm2, tx_sm3) = pac.PIO1.split(&mut pac.RESETS);
+    // Every pico has at most two neighbors in a line or triangle, so only two links are set up.
+    let (rx_pio, rx_sm0, rx_sm1, _, _) = pac.PIO0.split(&mut pac.RESETS);
+    let (tx_pio, tx_sm0, tx_sm1, _, _) = pac.PIO1.split(&mut pac.RESETS);
 
     let rx0_data = pins.gpio3.into_function::<FunctionPio0>().into_dyn_pin();
-    let rx0_clk = pins.gpio4.into_function::<FunctionPio0>().into_dyn_pin();
-    let rx0_word = pins.gpio5.into_function::<FunctionPio0>().into_dyn_pin();
+    let rx0_word = pins.gpio4.into_function::<FunctionPio0>().into_dyn_pin();
+    let rx0_clk = pins.gpio5.into_function::<FunctionPio0>().into_dyn_pin();
 
     let rx1_data = pins.gpio9.into_function::<FunctionPio0>().into_dyn_pin();
-    let rx1_clk = pins.gpio10.into_function::<FunctionPio0>().into_dyn_pin();
-    let rx1_word = pins.gpio11.into_function::<FunctionPio0>().into_dyn_pin();
-
-    let rx2_data = pins.gpio15.into_function::<FunctionPio0>().into_dyn_pin();
-    let rx2_clk = pins.gpio16.into_function::<FunctionPio0>().into_dyn_pin();
-    let rx2_word = pins.gpio17.into_function::<FunctionPio0>().into_dyn_pin();
-
-    let rx3_data = pins.gpio23.into_function::<FunctionPio0>().into_dyn_pin();
-    let rx3_clk = pins.gpio24.into_function::<FunctionPio0>().into_dyn_pin();
-    let rx3_word = pins.gpio28.into_function::<FunctionPio0>().into_dyn_pin();
+    let rx1_word = pins.gpio10.into_function::<FunctionPio0>().into_dyn_pin();
+    let rx1_clk = pins.gpio11.into_function::<FunctionPio0>().into_dyn_pin();
 
     let tx0_data = pins.gpio0.into_function::<FunctionPio1>().into_dyn_pin();
     let tx0_clk = pins.gpio1.into_function::<FunctionPio1>().into_dyn_pin();
@@ -143,14 +134,6 @@ fn main() -> ! {
     let tx1_clk = pins.gpio7.into_function::<FunctionPio1>().into_dyn_pin();
     let tx1_word = pins.gpio8.into_function::<FunctionPio1>().into_dyn_pin();
 
-    let tx2_data = pins.gpio12.into_function::<FunctionPio1>().into_dyn_pin();
-    let tx2_clk = pins.gpio13.into_function::<FunctionPio1>().into_dyn_pin();
-    let tx2_word = pins.gpio14.into_function::<FunctionPio1>().into_dyn_pin();
-
-    let tx3_data = pins.gpio18.into_function::<FunctionPio1>().into_dyn_pin();
-    let tx3_clk = pins.gpio19.into_function::<FunctionPio1>().into_dyn_pin();
-    let tx3_word = pins.gpio22.into_function::<FunctionPio1>().into_dyn_pin();
-
     let mut pitopi = Pitopi::new(rx_pio, tx_pio);
 
     pitopi.install_programs();
@@ -183,62 +166,28 @@ fn main() -> ! {
         )
         .unwrap();
 
-    let (_, rx2, _, tx2) = pitopi
-        .setup_link(
-            pitopi::DEFAULT_LINK_CONFIG,
-            rx_sm2,
-            rx2_data,
-            rx2_clk,
-            rx2_word,
-            tx_sm2,
-            tx2_data,
-            tx2_clk,
-            tx2_word,
-        )
-        .unwrap();
-
-    let (_, rx3, _, tx3) = pitopi
-        .setup_link(
-            pitopi::DEFAULT_LINK_CONFIG,
-            rx_sm3,
-            rx3_data,
-            rx3_clk,
-            rx3_word,
-            tx_sm3,
-            tx3_data,
-            tx3_clk,
-            tx3_word,
-        )
-        .unwrap();
-
     let sio_fifo = sio.fifo;
 
-    let tide_fifos = [
-        BittideFifo::new(),
-        BittideFifo::new(),
-        BittideFifo::new(),
-        BittideFifo::new(),
-    ];
+    let tide_fifos = [BittideFifo::new(), BittideFifo::new()];
 
-    let tide_controller = bittide_impls::boards::rpi_pico::Control::new(
-        FbdivController::new(
-            2,
+    let tide_controller = bittide_impls::boards::rpi_pico::DualLinkControl::new(
+        FbdivController::with_pid(
             pll_sys,
             PidSettings {
                 kp: I16F16::from_num(0.01),
                 ki: I16F16::from_num(0.00000001),
                 kd: I16F16::from_num(0.01),
             },
-        ),
-        Rp2040Links::new(rx0, rx1, rx2, rx3, tx0, tx1, tx2, tx3),
-        [true, true, true, true],
+        )
+        // A single fbdiv step is 1% of the frequency, dithering makes the average follow the PID.
+        .with_dithering(Dithering::FirstOrder),
+        Rp2040DualLinks::new(rx0, rx1, tx0, tx1),
+        [true, true],
         bittide_impls::chips::rp2040::SioFifo(sio_fifo),
         tide_fifos,
     );
 
-    critical_section::with(|cs| {
-        GLOBAL_CONTROL.borrow(cs).replace(Some(tide_controller));
-    });
+    CONTROL.give(tide_controller);
 
     info!("Starting node {} {}", NODE_ID, NAME);
     bittide_impls::chips::rp2040::setup_interrupt(CLOCKS_PER_SYNC_WORD, &mut core.SYST);
@@ -253,23 +202,18 @@ fn main() -> ! {
     }
 }
 
-static GLOBAL_CONTROL: Mutex<RefCell<Option<bittide_impls::boards::rpi_pico::Control>>> =
-    Mutex::new(RefCell::new(None));
+static CONTROL: InterruptOwned<bittide_impls::boards::rpi_pico::DualLinkControl> =
+    InterruptOwned::new();
 
-/// Triggered every `CLOCKS_PER_SYNC_WORD` cycles. Tries to borrow the GLOBAL_CONTROL object which contains
-/// the BittideController. Since this is the only routine that borrows it, if the borrow fails that means
-/// that the exception triggered before the control algorithm finished, so the control algorithm is too slow.
+/// Triggered every `CLOCKS_PER_SYNC_WORD` cycles. On the first run it takes ownership of the
+/// BittideController, after which it runs without disabling interrupts. If the control algorithm is
+/// too slow the exception stays pending while it runs and the next tick is late.
 #[exception]
 fn SysTick() {
-    critical_section::with(|cs| {
-        let mut refcell = GLOBAL_CONTROL
-            .borrow(cs)
-            .try_borrow_mut()
-            .expect("Control algorithm cannot keep up, already borrowed");
-        let mut control = refcell.take().expect("control not initialized.");
+    static mut OWNED: Option<bittide_impls::boards::rpi_pico::DualLinkControl> = None;
 
-        control.interrupt().ok();
-
-        *refcell = Some(control);
-    });
+    // safe because SysTick is the only caller, and cannot preempt itself
+    if let Some(owner) = unsafe { CONTROL.take_into(OWNED) } {
+        owner.control.interrupt().ok();
+    }
 }
@@ -1,5 +1,6 @@
 //! Utility functions for clocking on the minsync PCB, both local to the rp2040 and with the Si5351
 
+use embedded_hal::blocking::i2c;
 use fugit::HertzU32;
 use hal::{
     clocks::{ClockError, ClockSource, ClocksManager},
@@ -81,11 +82,52 @@ pub type SiI2C = I2C<
 
 const CRYSTAL_FREQ: HertzU32 = HertzU32::from_raw(12_000_000u32);
 
+/// Lends a bus to a driver that takes ownership of it, such as [`Si5351Device`].
+pub struct BorrowedI2c<'a, I>(pub &'a mut I);
+
+impl<I: i2c::Write> i2c::Write for BorrowedI2c<'_, I> {
+    type Error = I::Error;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.0.write(address, bytes)
+    }
+}
+
+impl<I: i2c::WriteRead> i2c::WriteRead for BorrowedI2c<'_, I> {
+    type Error = I::Error;
+
+    fn write_read(
+        &mut self,
+        address: u8,
+        bytes: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        self.0.write_read(address, bytes, buffer)
+    }
+}
+
 /// Sets up SI as 12MHz to function as a default crystal for the rp2040
 /// ```
 /// minsync::clocks::setup_si_as_crystal(minsync::si_i2c!(pac, pins, clocks, 1.kHz()));
 /// ```
 pub fn setup_si_as_crystal(i2c: SiI2C) -> Result<Si5351Device<SiI2C>, si5351::Error> {
+    configure_si_as_crystal(i2c)
+}
+
+/// Sets up the SI like [`setup_si_as_crystal`], but leaves the bus with the caller, e.g. to hand it to
+/// the non-blocking Si5351 driver of the controllers afterwards.
+/// ```
+/// let mut i2c = minsync::si_i2c!(pac, pins, clocks, 1.kHz());
+/// minsync::clocks::setup_si_as_crystal_on(&mut i2c);
+/// ```
+pub fn setup_si_as_crystal_on(i2c: &mut SiI2C) -> Result<(), si5351::Error> {
+    configure_si_as_crystal(BorrowedI2c(i2c)).map(|_| ())
+}
+
+fn configure_si_as_crystal<I, E>(i2c: I) -> Result<Si5351Device<I>, si5351::Error>
+where
+    I: i2c::WriteRead<Error = E> + i2c::Write<Error = E>,
+{
     let mut si_clock = Si5351Device::new(i2c, false, SI5351_CRYSTAL_FREQ);
 
     let status = si_clock.read_device_status()?.bits();
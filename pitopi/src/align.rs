@@ -0,0 +1,177 @@
+//! Word alignment for two-wire links. Without a word line the receiver shifts in bits from wherever
+//! it started, so its words can start anywhere in the words that were sent. Both sides send
+//! [`ALIGNMENT_WORD`] until their receiver finds it in the bit stream, slip their receiver to its
+//! boundary, and then send [`ALIGNED_WORD`] until they have seen that of the neighbor.
+//!
+//! Neighbors align their links in any order, so every link of a node should be polled in turn:
+//!
+//! ```ignore
+//! let mut aligners = [WordAligner::new(), WordAligner::new()];
+//!
+//! while !aligners.iter().all(WordAligner::is_done) {
+//!     poll_alignment(&mut aligners[0], &mut rx_sm0, &mut rx0, &mut tx0);
+//!     poll_alignment(&mut aligners[1], &mut rx_sm1, &mut rx1, &mut tx1);
+//! }
+//! ```
+
+use pio::{InSource, Instruction, InstructionOperands};
+use rp_pico::hal::pio::{Running, Rx, StateMachine, Tx, ValidStateMachine};
+
+/// Sent while the receiver searches the word boundaries. No rotation of it equals itself or
+/// [`ALIGNED_WORD`], and neither does any window on the two sent back to back.
+pub const ALIGNMENT_WORD: u32 = 0x1b5f_0e4d;
+/// Sent once the receiver is aligned, tells the neighbor it can stop.
+pub const ALIGNED_WORD: u32 = !ALIGNMENT_WORD;
+
+/// Words that have to arrive whole in a row before the boundaries count as found.
+const LOCK_WORDS: u32 = 4;
+/// Aligned words sent after both sides are aligned, so the neighbor gets to see one even when it
+/// saw ours first.
+const LINGER_WORDS: u32 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum AlignmentState {
+    Searching,
+    /// Words arrive whole, the neighbor has not confirmed that its words do too.
+    Aligned,
+    Done,
+}
+
+pub struct WordAligner {
+    state: AlignmentState,
+    previous: Option<u32>,
+    /// The word after a slip holds bits from before it, and is skipped.
+    slipped: bool,
+    matches: u32,
+    neighbor_aligned: bool,
+    lingered: u32,
+}
+
+impl Default for WordAligner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WordAligner {
+    pub const fn new() -> Self {
+        Self {
+            state: AlignmentState::Searching,
+            previous: None,
+            slipped: false,
+            matches: 0,
+            neighbor_aligned: false,
+            lingered: 0,
+        }
+    }
+
+    pub fn state(&self) -> AlignmentState {
+        self.state
+    }
+
+    /// Whether both sides are aligned and nothing is left to send.
+    pub fn is_done(&self) -> bool {
+        self.state == AlignmentState::Done && self.lingered >= LINGER_WORDS
+    }
+
+    /// The word to send to the neighbor next, `None` once alignment is done.
+    pub fn next_word(&mut self) -> Option<u32> {
+        match self.state {
+            AlignmentState::Searching => Some(ALIGNMENT_WORD),
+            AlignmentState::Aligned => Some(ALIGNED_WORD),
+            AlignmentState::Done if self.lingered < LINGER_WORDS => {
+                self.lingered += 1;
+                Some(ALIGNED_WORD)
+            }
+            AlignmentState::Done => None,
+        }
+    }
+
+    /// Takes a received word, and returns the number of bits to shift into the receiver to move its
+    /// word boundary onto that of the sender, if it is off.
+    pub fn receive(&mut self, word: u32) -> Option<u8> {
+        if core::mem::take(&mut self.slipped) {
+            return None;
+        }
+
+        let previous = self.previous.replace(word);
+        let whole = word == ALIGNMENT_WORD || word == ALIGNED_WORD;
+        self.neighbor_aligned |= word == ALIGNED_WORD;
+
+        match self.state {
+            AlignmentState::Searching if whole => {
+                self.matches += 1;
+
+                if self.matches >= LOCK_WORDS {
+                    defmt::info!("Two-wire link aligned");
+                    self.state = AlignmentState::Aligned;
+                }
+            }
+            AlignmentState::Searching => {
+                self.matches = 0;
+
+                // The bits in the order they arrived, the words are shifted in MSB first.
+                let stream = (previous? as u64) << 32 | word as u64;
+                let offset = (1..32).find(|&offset| {
+                    let window = (stream >> (32 - offset)) as u32;
+                    window == ALIGNMENT_WORD || window == ALIGNED_WORD
+                })?;
+
+                // A sent word starts `offset` bits into a received one. Shifting in the rest of a
+                // word ends the received word where the sent word starts.
+                self.previous = None;
+                self.slipped = true;
+                return Some(32 - offset as u8);
+            }
+            AlignmentState::Aligned if !whole => {
+                defmt::warn!("Two-wire link lost alignment, searching again");
+                self.state = AlignmentState::Searching;
+                self.matches = 0;
+            }
+            AlignmentState::Aligned | AlignmentState::Done => {}
+        }
+
+        if self.state == AlignmentState::Aligned && self.neighbor_aligned {
+            self.state = AlignmentState::Done;
+        }
+
+        None
+    }
+}
+
+/// Shifts `bits` zeroes into the receiver, which ends the word it is receiving that much earlier. The
+/// shift is lost if the receiver holds a nearly full word, the aligner then finds the boundary again.
+pub fn slip_bits<SM: ValidStateMachine>(rx_sm: &mut StateMachine<SM, Running>, bits: u8) {
+    rx_sm.exec_instruction(Instruction {
+        operands: InstructionOperands::IN {
+            source: InSource::NULL,
+            bit_count: bits,
+        },
+        delay: 0,
+        side_set: None,
+    });
+}
+
+/// A single step of aligning a two-wire link: sends the next word if the TX FIFO has room, and passes
+/// the received words to `aligner`, slipping the receiver where it asks. Returns whether the link is
+/// done.
+pub fn poll_alignment<RX: ValidStateMachine, TX: ValidStateMachine>(
+    aligner: &mut WordAligner,
+    rx_sm: &mut StateMachine<RX, Running>,
+    rx: &mut Rx<RX>,
+    tx: &mut Tx<TX>,
+) -> bool {
+    if !tx.is_full() {
+        if let Some(word) = aligner.next_word() {
+            tx.write(word);
+        }
+    }
+
+    while let Some(word) = rx.read() {
+        if let Some(bits) = aligner.receive(word) {
+            slip_bits(rx_sm, bits);
+        }
+    }
+
+    aligner.is_done()
+}
@@ -0,0 +1,246 @@
+//! Pseudo-random bit sequences to measure the bit error rate of a link. The sender runs a
+//! [`PrbsGenerator`] and sends its words instead of bittide words, the receiver loads a
+//! [`PrbsChecker`] from the first bits it receives and compares every bit after that with the bit it
+//! generates itself. The links send words most significant bit first, so that is the order the bits
+//! are packed and checked in, and the sequence runs on across words like it does on the data line.
+//!
+//! After loading, a whole word has to match before the checker counts anything, so words that are not
+//! part of the sequence, like the bittide words of a neighbor that is already done, are never
+//! counted. A lost or duplicated word puts the checker out of step, which shows as about half the
+//! bits being wrong. The checker then drops that word from the counts and loads itself from the
+//! received bits again.
+//!
+//! ```ignore
+//! let mut test = BertTest::new(Prbs::Prbs31, words);
+//!
+//! while !poll_bert(&mut test, &mut rx, &mut tx) {}
+//!
+//! defmt::info!("{}", test.result());
+//! ```
+
+use rp_pico::{
+    hal::pio::{Rx, Tx, ValidStateMachine},
+    pac::PIO0,
+};
+
+use crate::status::{take_rx_errors, RxErrors};
+
+/// Errors in a word above which the checker counts as out of step.
+const OUT_OF_STEP_ERRORS: u32 = 8;
+/// Polls without a word of the sequence arriving after which the neighbor counts as done sending, or
+/// absent.
+const IDLE_POLLS: u32 = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Prbs {
+    /// x^7 + x^6 + 1
+    Prbs7,
+    /// x^15 + x^14 + 1
+    Prbs15,
+    /// x^31 + x^28 + 1
+    Prbs31,
+}
+
+impl Prbs {
+    /// The sequence of the given order, 7, 15 or 31.
+    pub fn from_order(order: u32) -> Option<Self> {
+        match order {
+            7 => Some(Prbs::Prbs7),
+            15 => Some(Prbs::Prbs15),
+            31 => Some(Prbs::Prbs31),
+            _ => None,
+        }
+    }
+
+    pub fn order(self) -> u32 {
+        match self {
+            Prbs::Prbs7 => 7,
+            Prbs::Prbs15 => 15,
+            Prbs::Prbs31 => 31,
+        }
+    }
+
+    /// Degree of the middle term of the polynomial.
+    fn tap(self) -> u32 {
+        match self {
+            Prbs::Prbs7 => 6,
+            Prbs::Prbs15 => 14,
+            Prbs::Prbs31 => 28,
+        }
+    }
+
+    fn mask(self) -> u32 {
+        (1 << self.order()) - 1
+    }
+
+    /// Shifts the next bit of the sequence into `state`, which holds the last `order` bits with the
+    /// newest in bit 0, and returns it.
+    fn next_bit(self, state: &mut u32) -> u32 {
+        let bit = ((*state >> (self.order() - 1)) ^ (*state >> (self.tap() - 1))) & 1;
+        *state = ((*state << 1) | bit) & self.mask();
+        bit
+    }
+}
+
+pub struct PrbsGenerator {
+    prbs: Prbs,
+    state: u32,
+}
+
+impl PrbsGenerator {
+    pub fn new(prbs: Prbs) -> Self {
+        Self {
+            prbs,
+            state: prbs.mask(),
+        }
+    }
+
+    pub fn next_word(&mut self) -> u32 {
+        (0..32).fold(0, |word, bit| {
+            word | self.prbs.next_bit(&mut self.state) << (31 - bit)
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, defmt::Format)]
+pub struct BertResult {
+    /// Bits compared with the sequence.
+    pub bits: u64,
+    pub errors: u64,
+    /// Times the checker got out of step and loaded itself again.
+    pub resyncs: u32,
+    /// Errors the receiver flagged during the test.
+    pub rx_errors: RxErrors,
+}
+
+impl BertResult {
+    /// Bit error rate, `None` before any bit was compared.
+    pub fn ber(&self) -> Option<f32> {
+        (self.bits > 0).then(|| self.errors as f32 / self.bits as f32)
+    }
+}
+
+pub struct PrbsChecker {
+    prbs: Prbs,
+    state: u32,
+    /// Bits loaded into `state` from the received bits, the checker compares once it holds `order`.
+    loaded: u32,
+    /// Whether a whole word matched since the checker was loaded.
+    locked: bool,
+    result: BertResult,
+}
+
+impl PrbsChecker {
+    pub fn new(prbs: Prbs) -> Self {
+        Self {
+            prbs,
+            state: 0,
+            loaded: 0,
+            locked: false,
+            result: BertResult::default(),
+        }
+    }
+
+    pub fn result(&self) -> BertResult {
+        self.result
+    }
+
+    /// Takes a received word, and returns whether it was counted.
+    pub fn receive(&mut self, word: u32) -> bool {
+        let whole_word = self.loaded == self.prbs.order();
+        let mut bits = 0;
+        let mut errors = 0;
+
+        for bit in (0..32).rev().map(|bit| (word >> bit) & 1) {
+            if self.loaded < self.prbs.order() {
+                self.state = ((self.state << 1) | bit) & self.prbs.mask();
+                self.loaded += 1;
+                continue;
+            }
+
+            bits += 1;
+            errors += (self.prbs.next_bit(&mut self.state) != bit) as u32;
+        }
+
+        if !self.locked {
+            if errors > 0 {
+                self.loaded = 0;
+            } else if whole_word {
+                self.locked = true;
+            }
+            return false;
+        }
+
+        if errors > OUT_OF_STEP_ERRORS {
+            self.loaded = 0;
+            self.locked = false;
+            self.result.resyncs += 1;
+            return false;
+        }
+
+        self.result.bits += bits;
+        self.result.errors += errors as u64;
+        true
+    }
+}
+
+/// A bit error rate test of one link, sending `words` words of a sequence and checking whatever the
+/// neighbor sends with the same sequence.
+pub struct BertTest {
+    generator: PrbsGenerator,
+    checker: PrbsChecker,
+    words_left: u32,
+    idle_polls: u32,
+}
+
+impl BertTest {
+    pub fn new(prbs: Prbs, words: u32) -> Self {
+        Self {
+            generator: PrbsGenerator::new(prbs),
+            checker: PrbsChecker::new(prbs),
+            words_left: words,
+            idle_polls: 0,
+        }
+    }
+
+    /// Whether every word was sent and no word of the sequence arrived for long enough.
+    pub fn is_done(&self) -> bool {
+        self.words_left == 0 && self.idle_polls >= IDLE_POLLS
+    }
+
+    pub fn result(&self) -> BertResult {
+        self.checker.result()
+    }
+}
+
+/// A single step of a bit error rate test: fills the TX FIFO with the sequence, and checks the
+/// received words. Returns whether the test is done.
+pub fn poll_bert<RX: ValidStateMachine<PIO = PIO0>, TX: ValidStateMachine>(
+    test: &mut BertTest,
+    rx: &mut Rx<RX>,
+    tx: &mut Tx<TX>,
+) -> bool {
+    if test.is_done() {
+        return true;
+    }
+
+    while test.words_left > 0 && !tx.is_full() {
+        tx.write(test.generator.next_word());
+        test.words_left -= 1;
+    }
+
+    test.idle_polls += 1;
+
+    while let Some(word) = rx.read() {
+        if test.checker.receive(word) {
+            test.idle_polls = 0;
+        }
+    }
+
+    let errors = take_rx_errors(rx);
+    let flagged = &mut test.checker.result.rx_errors;
+    flagged.misaligned |= errors.misaligned;
+    flagged.overflowed |= errors.overflowed;
+
+    test.is_done()
+}
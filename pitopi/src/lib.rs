@@ -1,19 +1,28 @@
 #![no_std]
+pub mod align;
+pub mod bert;
+pub mod loopback;
+pub mod rate;
+pub mod status;
+
+use pio::{Instruction, InstructionOperands, JmpCondition};
 use pio_proc::pio_file;
 use rp_pico::{
     hal::{
         gpio::{DynPinId, FunctionPio0, FunctionPio1, Pin, PullDown},
         pio::{
-            InstalledProgram, PIOBuilder, PinDir, Running, Rx, StateMachine, StateMachineIndex, Tx,
-            UninitStateMachine, PIO,
+            InstalledProgram, PIOBuilder, PinDir, Running, Rx, ShiftDirection, StateMachine,
+            StateMachineIndex, Tx, UninitStateMachine, PIO,
         },
     },
     pac::{PIO0, PIO1},
 };
 
 pub const DEFAULT_LINK_CONFIG: LinkConfig = LinkConfig {
-    rx_program: RxProgram::Consecutive,
-    tx_program: TxProgram::SidesetWC,
+    rx_program: None,
+    tx_program: None,
+    bit_clock_divisor: 64,
+    loopback: false,
 };
 
 pub struct Pitopi {
@@ -22,10 +31,14 @@ pub struct Pitopi {
 
     rx_program: Option<InstalledProgram<PIO0>>,
     rx_program_023: Option<InstalledProgram<PIO0>>,
+    rx_program_two_wire: Option<InstalledProgram<PIO0>>,
     tx_program: Option<InstalledProgram<PIO1>>,
+    tx_program_mirror: Option<InstalledProgram<PIO1>>,
+    tx_program_two_wire: Option<InstalledProgram<PIO1>>,
 }
 
-type LinkStateMachines<RXSM, TXSM> = (
+/// The RX state machine and FIFO, and the TX state machine and FIFO of a link.
+pub type LinkStateMachines<RXSM, TXSM> = (
     StateMachine<(PIO0, RXSM), Running>,
     Rx<(PIO0, RXSM)>,
     StateMachine<(PIO1, TXSM), Running>,
@@ -39,7 +52,10 @@ impl Pitopi {
             tx_pio,
             rx_program: None,
             rx_program_023: None,
+            rx_program_two_wire: None,
             tx_program: None,
+            tx_program_mirror: None,
+            tx_program_two_wire: None,
         }
     }
 
@@ -47,14 +63,32 @@ impl Pitopi {
         let pitopi_tx_program = pio_file!("src/programs.pio", select_program("pitopi_tx")).program;
         self.tx_program = Some(self.tx_pio.install(&pitopi_tx_program).unwrap());
 
+        let pitopi_tx_program_mirror = pio_file!(
+            "src/programs.pio",
+            select_program("pitopi_tx_mirror_sideset")
+        )
+        .program;
+        self.tx_program_mirror = Some(self.tx_pio.install(&pitopi_tx_program_mirror).unwrap());
+
         let pitopi_rx_program = pio_file!("src/programs.pio", select_program("pitopi_rx")).program;
         self.rx_program = Some(self.rx_pio.install(&pitopi_rx_program).unwrap());
 
         let pitopi_rx_program_023 =
             pio_file!("src/programs.pio", select_program("pitopi_rx_023")).program;
         self.rx_program_023 = Some(self.rx_pio.install(&pitopi_rx_program_023).unwrap());
+
+        let pitopi_tx_program_two_wire =
+            pio_file!("src/programs.pio", select_program("pitopi_tx_two_wire")).program;
+        self.tx_program_two_wire = Some(self.tx_pio.install(&pitopi_tx_program_two_wire).unwrap());
+
+        let pitopi_rx_program_two_wire =
+            pio_file!("src/programs.pio", select_program("pitopi_rx_two_wire")).program;
+        self.rx_program_two_wire = Some(self.rx_pio.install(&pitopi_rx_program_two_wire).unwrap());
     }
 
+    /// Sets up a link with a data, clock and word wire per direction. The programs follow from the
+    /// order of the pins, see [`RxProgram::for_pins`] and [`TxProgram::for_pins`], unless `link_config`
+    /// names them.
     #[allow(clippy::too_many_arguments)]
     pub fn setup_link<RXSM, TXSM>(
         &mut self,
@@ -72,87 +106,159 @@ impl Pitopi {
         RXSM: StateMachineIndex,
         TXSM: StateMachineIndex,
     {
-        // For the PIO programs to work, the RX pins must be strictly consecutive, in the order clock, word, data.
-        // assert_eq!(rx_clk_pin.id().num + 1, rx_word_pin.id().num);
-        // assert_eq!(rx_clk_pin.id().num + 2, rx_data_pin.id().num);
-        // Also, the tx word and clk pin must be consecutive in that order (if the default TX program is used and not the mirrored)
-        // assert_eq!(tx_word_pin.id().num + 1, tx_clk_pin.id().num);
+        let rx_pins = LinkPins {
+            data: rx_data_pin.id().num,
+            clk: rx_clk_pin.id().num,
+            word: Some(rx_word_pin.id().num),
+        };
+        let tx_pins = LinkPins {
+            data: tx_data_pin.id().num,
+            clk: tx_clk_pin.id().num,
+            word: Some(tx_word_pin.id().num),
+        };
+
+        self.setup(link_config, rx_sm, rx_pins, tx_sm, tx_pins)
+    }
+
+    /// Sets up a link that only has a data and a clock wire per direction, with the
+    /// [`RxProgram::TwoWire`] and [`TxProgram::TwoWire`] programs. The RX clock pin must follow the RX
+    /// data pin. Words only arrive whole after [`align`]ment.
+    pub fn setup_two_wire_link<RXSM, TXSM>(
+        &mut self,
+        link_config: LinkConfig,
+        rx_sm: UninitStateMachine<(PIO0, RXSM)>,
+        rx_data_pin: Pin<DynPinId, FunctionPio0, PullDown>,
+        rx_clk_pin: Pin<DynPinId, FunctionPio0, PullDown>,
+        tx_sm: UninitStateMachine<(PIO1, TXSM)>,
+        tx_data_pin: Pin<DynPinId, FunctionPio1, PullDown>,
+        tx_clk_pin: Pin<DynPinId, FunctionPio1, PullDown>,
+    ) -> Result<LinkStateMachines<RXSM, TXSM>, PitopiError>
+    where
+        RXSM: StateMachineIndex,
+        TXSM: StateMachineIndex,
+    {
+        let rx_pins = LinkPins {
+            data: rx_data_pin.id().num,
+            clk: rx_clk_pin.id().num,
+            word: None,
+        };
+        let tx_pins = LinkPins {
+            data: tx_data_pin.id().num,
+            clk: tx_clk_pin.id().num,
+            word: None,
+        };
+
+        self.setup(link_config, rx_sm, rx_pins, tx_sm, tx_pins)
+    }
+
+    fn setup<RXSM, TXSM>(
+        &mut self,
+        link_config: LinkConfig,
+        rx_sm: UninitStateMachine<(PIO0, RXSM)>,
+        rx_pins: LinkPins,
+        tx_sm: UninitStateMachine<(PIO1, TXSM)>,
+        tx_pins: LinkPins,
+    ) -> Result<LinkStateMachines<RXSM, TXSM>, PitopiError>
+    where
+        RXSM: StateMachineIndex,
+        TXSM: StateMachineIndex,
+    {
+        let rx_pins = if link_config.loopback {
+            tx_pins
+        } else {
+            rx_pins
+        };
 
-        let rx_program = match link_config.rx_program {
+        let rx_program = select(link_config.rx_program, RxProgram::for_pins(rx_pins)).ok_or(
+            PitopiError::InvalidPinLayout {
+                direction: Direction::Rx,
+                pins: rx_pins,
+            },
+        )?;
+        let tx_program = select(link_config.tx_program, TxProgram::for_pins(tx_pins)).ok_or(
+            PitopiError::InvalidPinLayout {
+                direction: Direction::Tx,
+                pins: tx_pins,
+            },
+        )?;
+
+        let rx_installed = match rx_program {
             RxProgram::Consecutive => &mut self.rx_program,
             RxProgram::P023 => &mut self.rx_program_023,
+            RxProgram::TwoWire => &mut self.rx_program_two_wire,
         };
-
-        let Some(rx_program) = rx_program.as_mut() else {
+        let Some(rx_installed) = rx_installed.as_mut() else {
             return Err(PitopiError::RxProgramNotInstalled);
         };
 
-        let rx_base_pin = rx_data_pin.id().num;
-
-        let (mut rx_sm, rx_fifo, _) =
-            PIOBuilder::from_installed_program(unsafe { rx_program.share() })
-                .in_pin_base(rx_base_pin)
-                .clock_divisor_fixed_point(1, 0)
-                .build(rx_sm);
-
-        rx_sm.set_pindirs([
-            (rx_data_pin.id().num, PinDir::Input),
-            (rx_word_pin.id().num, PinDir::Input),
-            (rx_clk_pin.id().num, PinDir::Input),
-        ]);
-
-        match link_config.rx_program {
-            RxProgram::Consecutive => {
-                defmt::info!(
-                    "cons | setting up link, computed pins:\ndata = gpio{}\nword = gpio{}\nclk  = gpio{}",
-                    rx_base_pin,
-                    rx_base_pin + 1,
-                    rx_base_pin + 2
-                );
-            }
-            RxProgram::P023 => {
-                defmt::info!(
-                    "p023 | setting up link, computed pins:\ndata = gpio{}\nword = gpio{}\nclk  = gpio{}",
-                    rx_base_pin,
-                    rx_base_pin + 2,
-                    rx_base_pin + 3
-                );
-            }
+        let tx_installed = match tx_program {
+            TxProgram::SidesetWC => &mut self.tx_program,
+            TxProgram::SidesetCW => &mut self.tx_program_mirror,
+            TxProgram::TwoWire => &mut self.tx_program_two_wire,
+        };
+        let Some(tx_installed) = tx_installed.as_mut() else {
+            return Err(PitopiError::TxProgramNotInstalled);
+        };
+
+        defmt::info!(
+            "setting up link\nrx {}: {}\ntx {}: {}, divisor {}",
+            rx_program,
+            rx_pins,
+            tx_program,
+            tx_pins,
+            link_config.bit_clock_divisor
+        );
+
+        // The data pin is the first input pin, the programs find the other pins relative to it. The
+        // three-wire programs check the word line with `jmp pin`, and the clock line by shifting the
+        // pins they read into the OSR out to the right, starting at the data pin. Bits are shifted in
+        // MSB first.
+        let mut rx_builder = PIOBuilder::from_installed_program(unsafe { rx_installed.share() })
+            .in_pin_base(rx_pins.data)
+            .in_shift_direction(ShiftDirection::Left)
+            .out_shift_direction(ShiftDirection::Right)
+            .clock_divisor_fixed_point(1, 0);
+
+        if let Some(word) = rx_pins.word {
+            rx_builder = rx_builder.jmp_pin(word);
+        }
+
+        let (mut rx_sm, rx_fifo, _) = rx_builder.build(rx_sm);
+
+        // In loopback the TX state machine drives these pins.
+        if !link_config.loopback {
+            rx_sm.set_pindirs(rx_pins.iter().map(|pin| (pin, PinDir::Input)));
         }
 
+        // The error path of the three-wire programs comes before their wrap target, so they do not
+        // start at their first instruction.
+        rx_sm.exec_instruction(Instruction {
+            operands: InstructionOperands::JMP {
+                condition: JmpCondition::Always,
+                address: rx_installed.wrap_target(),
+            },
+            delay: 0,
+            side_set: None,
+        });
+
         let rx_sm = rx_sm.start();
 
-        let Some(tx_program) = self.tx_program.as_mut() else {
-            return Err(PitopiError::TxProgramNotInstalled);
+        // The side-set pins are the clock and word pins, in the order the program expects them. Words
+        // go out MSB first, like the receivers shift them in.
+        let side_set_base = match (tx_program, tx_pins.word) {
+            (TxProgram::SidesetCW, Some(word)) => word,
+            _ => tx_pins.clk,
         };
 
-        // default
         let (mut tx_sm, _, tx_fifo) =
-            PIOBuilder::from_installed_program(unsafe { tx_program.share() })
-                .out_pins(tx_data_pin.id().num, 1)
-                .side_set_pin_base(tx_clk_pin.id().num)
-                .clock_divisor_fixed_point(64, 0)
+            PIOBuilder::from_installed_program(unsafe { tx_installed.share() })
+                .out_pins(tx_pins.data, 1)
+                .out_shift_direction(ShiftDirection::Left)
+                .side_set_pin_base(side_set_base)
+                .clock_divisor_fixed_point(link_config.bit_clock_divisor, 0)
                 .build(tx_sm);
 
-        tx_sm.set_pindirs([
-            (tx_data_pin.id().num, PinDir::Output),
-            (tx_clk_pin.id().num, PinDir::Output),
-            (tx_word_pin.id().num, PinDir::Output),
-        ]);
-
-        // mirror
-        // let (mut tx_sm, _, tx_fifo) =
-        //     PIOBuilder::from_installed_program(unsafe { tx_program.share() })
-        //         .out_pins(tx_data_pin.id().num, 1)
-        //         .side_set_pin_base(tx_word_pin.id().num)
-        //         .clock_divisor_fixed_point(64, 0)
-        //         .build(tx_sm);
-
-        // tx_sm.set_pindirs([
-        //     (tx_data_pin.id().num, PinDir::Output),
-        //     (tx_clk_pin.id().num, PinDir::Output),
-        //     (tx_word_pin.id().num, PinDir::Output),
-        // ]);
+        tx_sm.set_pindirs(tx_pins.iter().map(|pin| (pin, PinDir::Output)));
 
         let tx_sm = tx_sm.start();
 
@@ -164,22 +270,112 @@ impl Pitopi {
     }
 }
 
+/// The program named in a [`LinkConfig`] if it fits the pins, otherwise the one that does.
+fn select<P: PartialEq>(configured: Option<P>, fitting: Option<P>) -> Option<P> {
+    match configured {
+        Some(configured) => fitting.filter(|fitting| *fitting == configured),
+        None => fitting,
+    }
+}
+
+#[derive(Clone, Copy)]
 pub struct LinkConfig {
-    pub rx_program: RxProgram,
-    pub tx_program: TxProgram,
+    /// `None` picks the program that fits the pins.
+    pub rx_program: Option<RxProgram>,
+    pub tx_program: Option<TxProgram>,
+    /// Divides the system clock for the TX state machine, which takes [`rate::cycles_per_bit`] of its
+    /// cycles per bit. The receivers follow the clock line, so it sets the rate of this direction
+    /// only, see [`rate`] to negotiate it with the neighbor.
+    pub bit_clock_divisor: u16,
+    /// The receiver reads the TX pins of its own link instead of the RX pins, which are left alone.
+    /// Only possible where the TX pins also fit an RX program, otherwise setting up the link fails with
+    /// [`PitopiError::InvalidPinLayout`]. The TX pins of the minsync v0.2 board go down from the data
+    /// pin, which no RX program reads, so it needs loopback plugs. See [`loopback`](crate::loopback)
+    /// for a test that works with either.
+    pub loopback: bool,
+}
+
+/// GPIO numbers of one direction of a link, without a word pin on two-wire links.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct LinkPins {
+    pub data: u8,
+    pub clk: u8,
+    pub word: Option<u8>,
 }
 
+impl LinkPins {
+    fn iter(self) -> impl Iterator<Item = u8> {
+        [Some(self.data), Some(self.clk), self.word]
+            .into_iter()
+            .flatten()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
 pub enum RxProgram {
+    /// Data, word and clock on consecutive pins.
     Consecutive,
+    /// Data on the first pin, word and clock two and three pins further.
     P023,
+    /// Data and clock on consecutive pins, without a word line.
+    TwoWire,
 }
 
+impl RxProgram {
+    /// The program that reads these pins, if any.
+    pub fn for_pins(pins: LinkPins) -> Option<Self> {
+        let LinkPins { data, clk, word } = pins;
+
+        match word {
+            Some(word) if word == data + 1 && clk == data + 2 => Some(RxProgram::Consecutive),
+            Some(word) if word == data + 2 && clk == data + 3 => Some(RxProgram::P023),
+            None if clk == data + 1 => Some(RxProgram::TwoWire),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
 pub enum TxProgram {
+    /// Word on the pin after the clock.
     SidesetWC,
+    /// Clock on the pin after the word, the mirror image of [`TxProgram::SidesetWC`].
+    SidesetCW,
+    /// Data and clock, without a word line.
+    TwoWire,
+}
+
+impl TxProgram {
+    /// The program that drives these pins, if any. The data pin can be anywhere.
+    pub fn for_pins(pins: LinkPins) -> Option<Self> {
+        let LinkPins { data, clk, word } = pins;
+
+        if data == clk || Some(data) == word {
+            return None;
+        }
+
+        match word {
+            Some(word) if word == clk + 1 => Some(TxProgram::SidesetWC),
+            Some(word) if clk == word + 1 => Some(TxProgram::SidesetCW),
+            None => Some(TxProgram::TwoWire),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Direction {
+    Rx,
+    Tx,
 }
 
-#[derive(Debug)]
+#[derive(Debug, defmt::Format)]
 pub enum PitopiError {
     TxProgramNotInstalled,
     RxProgramNotInstalled,
+    /// No program fits the pins, or not the program named in the [`LinkConfig`].
+    InvalidPinLayout {
+        direction: Direction,
+        pins: LinkPins,
+    },
 }
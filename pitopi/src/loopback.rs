@@ -0,0 +1,149 @@
+//! Self-test of a link whose TX is wired to its own RX, by a loopback plug or by setting up the link
+//! with [`LinkConfig::loopback`](crate::LinkConfig::loopback). The test sends rounds of patterns that
+//! hold the data line constant, toggle it every bit, and walk a one and a zero through the word, and
+//! compares what comes back.
+//!
+//! ```ignore
+//! let mut test = LoopbackTest::new(4 * PATTERN_WORDS);
+//!
+//! while !poll_loopback(&mut test, &mut rx, &mut tx) {}
+//!
+//! defmt::info!("{}", test.result());
+//! ```
+
+use rp_pico::{
+    hal::pio::{Rx, Tx, ValidStateMachine},
+    pac::PIO0,
+};
+
+use crate::status::{take_rx_errors, RxErrors};
+
+/// Words in a round of patterns.
+pub const PATTERN_WORDS: u32 = 4 + 2 * 32;
+
+/// Words sent but not received yet, at most. The RX FIFO holds this many, so it cannot overflow
+/// between two polls.
+const IN_FLIGHT_WORDS: u32 = 4;
+/// Polls without a word coming back after which the link counts as open.
+const IDLE_POLLS: u32 = 100_000;
+
+/// The `index`th word of the test.
+pub fn pattern(index: u32) -> u32 {
+    match index % PATTERN_WORDS {
+        0 => 0,
+        1 => u32::MAX,
+        2 => 0xaaaa_aaaa,
+        3 => 0x5555_5555,
+        i if i < 4 + 32 => 1 << (i - 4),
+        i => !(1 << (i - 4 - 32)),
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, defmt::Format)]
+pub struct LoopbackResult {
+    pub sent: u32,
+    pub received: u32,
+    /// Received words that differ from the word sent in their place.
+    pub mismatched_words: u32,
+    pub bit_errors: u32,
+    /// Errors the receiver flagged during the test.
+    pub rx_errors: RxErrors,
+}
+
+impl LoopbackResult {
+    /// Whether every word came back unchanged.
+    pub fn passed(&self) -> bool {
+        self.received == self.sent && self.mismatched_words == 0 && !self.rx_errors.any()
+    }
+}
+
+pub struct LoopbackTest {
+    words: u32,
+    result: LoopbackResult,
+    idle_polls: u32,
+}
+
+impl LoopbackTest {
+    /// A test of `words` words.
+    pub const fn new(words: u32) -> Self {
+        Self {
+            words,
+            result: LoopbackResult {
+                sent: 0,
+                received: 0,
+                mismatched_words: 0,
+                bit_errors: 0,
+                rx_errors: RxErrors {
+                    misaligned: false,
+                    overflowed: false,
+                },
+            },
+            idle_polls: 0,
+        }
+    }
+
+    /// Whether every word came back, or the link stayed silent for too long.
+    pub fn is_done(&self) -> bool {
+        self.result.received == self.words || self.idle_polls >= IDLE_POLLS
+    }
+
+    pub fn result(&self) -> LoopbackResult {
+        self.result
+    }
+
+    /// The word to send next, if any is left and not too many are on their way.
+    pub fn next_word(&mut self) -> Option<u32> {
+        let result = &mut self.result;
+
+        if result.sent == self.words || result.sent - result.received >= IN_FLIGHT_WORDS {
+            return None;
+        }
+
+        result.sent += 1;
+        Some(pattern(result.sent - 1))
+    }
+
+    /// Takes a word that came back.
+    pub fn receive(&mut self, word: u32) {
+        let result = &mut self.result;
+        let difference = word ^ pattern(result.received);
+
+        result.received += 1;
+        result.mismatched_words += (difference != 0) as u32;
+        result.bit_errors += difference.count_ones();
+        self.idle_polls = 0;
+    }
+
+    fn flag(&mut self, errors: RxErrors) {
+        self.result.rx_errors.misaligned |= errors.misaligned;
+        self.result.rx_errors.overflowed |= errors.overflowed;
+    }
+}
+
+/// A single step of a loopback test: sends the next word if the TX FIFO has room, and passes the
+/// words that came back to `test`. Returns whether the test is done.
+pub fn poll_loopback<RX: ValidStateMachine<PIO = PIO0>, TX: ValidStateMachine>(
+    test: &mut LoopbackTest,
+    rx: &mut Rx<RX>,
+    tx: &mut Tx<TX>,
+) -> bool {
+    if test.is_done() {
+        return true;
+    }
+
+    test.idle_polls += 1;
+
+    if !tx.is_full() {
+        if let Some(word) = test.next_word() {
+            tx.write(word);
+        }
+    }
+
+    while let Some(word) = rx.read() {
+        test.receive(word);
+    }
+
+    test.flag(take_rx_errors(rx));
+
+    test.is_done()
+}
@@ -0,0 +1,254 @@
+//! Negotiation of the bit rate of a link. The receivers sample the clock line of their neighbor, so
+//! only the TX clock divisor sets the rate, and how fast a link can go depends on the cable. Both
+//! sides start at the fastest of [`BIT_CLOCK_DIVISORS`] and send numbered probe words tagged with
+//! their rate. A side steps down to the next rate when a window of probes passes without
+//! [`GOOD_WORDS`] intact probes in a row in both directions, and follows its neighbor down when it
+//! receives a probe tagged with a slower rate. A side that receives intact probes gives a neighbor that
+//! reached the rate later up to another window to agree. The slowest rate is where neighbors that started
+//! negotiating at different times meet, a side waits there for a while before it gives up.
+//!
+//! Words pass unchanged on three-wire links only, two-wire links need a fixed divisor and
+//! [`align`](crate::align)ment. Neighbors negotiate their links in any order, so every link of a node
+//! should be polled in turn:
+//!
+//! ```ignore
+//! let mut negotiators = [RateNegotiator::new(), RateNegotiator::new()];
+//!
+//! while !negotiators.iter().all(RateNegotiator::is_done) {
+//!     poll_negotiation(&mut negotiators[0], &mut tx_sm0, &mut rx0, &mut tx0);
+//!     poll_negotiation(&mut negotiators[1], &mut tx_sm1, &mut rx1, &mut tx1);
+//! }
+//! ```
+
+use rp_pico::hal::pio::{Running, Rx, StateMachine, Tx, ValidStateMachine};
+
+/// TX clock divisors tried, fastest first.
+pub const BIT_CLOCK_DIVISORS: [u16; 5] = [8, 16, 32, 64, 128];
+
+/// Probes that have to arrive intact and in order before a direction counts as error-free.
+pub const GOOD_WORDS: u32 = 32;
+/// Probes tagged with a slower rate that have to arrive in order before following the neighbor down.
+const FOLLOW_WORDS: u32 = 4;
+/// Probes sent at a rate before stepping down from it.
+const WINDOW_WORDS: u32 = 256;
+/// Probes sent at the slowest rate before the neighbor counts as absent.
+const RENDEZVOUS_WORDS: u32 = 16384;
+/// Probes sent after agreeing, so the neighbor gets to see that this side agrees too.
+const LINGER_WORDS: u32 = 8;
+
+/// Probes carry this in their top byte, followed by the rate index, whether the sender receives
+/// error-free, and a sequence number.
+const PROBE_MAGIC: u32 = 0xa5 << 24;
+const SEQUENCE_MASK: u32 = (1 << 19) - 1;
+
+fn probe(rate: usize, ok: bool, sequence: u32) -> u32 {
+    PROBE_MAGIC | (rate as u32) << 20 | (ok as u32) << 19 | (sequence & SEQUENCE_MASK)
+}
+
+/// The rate index, ok flag and sequence number of a probe.
+fn parse_probe(word: u32) -> Option<(usize, bool, u32)> {
+    let rate = ((word >> 20) & 0xf) as usize;
+
+    (word & (0xff << 24) == PROBE_MAGIC && rate < BIT_CLOCK_DIVISORS.len()).then_some((
+        rate,
+        (word >> 19) & 1 == 1,
+        word & SEQUENCE_MASK,
+    ))
+}
+
+/// System clock cycles per bit at a TX clock divisor, the TX programs take two cycles per bit.
+pub const fn cycles_per_bit(divisor: u16) -> u32 {
+    2 * divisor as u32
+}
+
+/// System clock cycles per word at a TX clock divisor, the TX programs take a bit's worth of cycles
+/// between words.
+pub const fn cycles_per_word(divisor: u16) -> u32 {
+    33 * cycles_per_bit(divisor)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum NegotiationState {
+    Probing,
+    /// Both directions were error-free at the current rate.
+    Agreed,
+    /// No neighbor answered at the slowest rate, which the link keeps.
+    Failed,
+}
+
+pub struct RateNegotiator {
+    state: NegotiationState,
+    /// Index into [`BIT_CLOCK_DIVISORS`].
+    rate: usize,
+    /// Whether the rate changed since the TX state machine was last told.
+    changed: bool,
+    sent: u32,
+    sequence: u32,
+    expected: Option<u32>,
+    /// Probes received in order with the same rate tag, and that rate.
+    run: u32,
+    run_rate: usize,
+    neighbor_ok: bool,
+    lingered: u32,
+}
+
+impl Default for RateNegotiator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RateNegotiator {
+    pub const fn new() -> Self {
+        Self {
+            state: NegotiationState::Probing,
+            rate: 0,
+            changed: true,
+            sent: 0,
+            sequence: 0,
+            expected: None,
+            run: 0,
+            run_rate: 0,
+            neighbor_ok: false,
+            lingered: 0,
+        }
+    }
+
+    pub fn state(&self) -> NegotiationState {
+        self.state
+    }
+
+    /// The TX clock divisor of the current rate.
+    pub fn divisor(&self) -> u16 {
+        BIT_CLOCK_DIVISORS[self.rate]
+    }
+
+    /// Whether the rate is settled and nothing is left to send.
+    pub fn is_done(&self) -> bool {
+        match self.state {
+            NegotiationState::Probing => false,
+            NegotiationState::Agreed => self.lingered >= LINGER_WORDS,
+            NegotiationState::Failed => true,
+        }
+    }
+
+    /// The divisor to set the TX state machine to, if it changed since the last call.
+    pub fn take_divisor_change(&mut self) -> Option<u16> {
+        core::mem::take(&mut self.changed).then(|| self.divisor())
+    }
+
+    /// The word to send to the neighbor next, `None` once negotiation is done.
+    pub fn next_word(&mut self) -> Option<u32> {
+        match self.state {
+            NegotiationState::Probing => {
+                let slowest = self.rate + 1 == BIT_CLOCK_DIVISORS.len();
+                let window = if slowest {
+                    RENDEZVOUS_WORDS
+                } else {
+                    WINDOW_WORDS
+                };
+
+                // The neighbor's window at this rate ends at most a window after ours, by then it either
+                // agreed or stepped down.
+                let waiting = self.receives_intact() && !self.neighbor_ok && self.sent < 2 * window;
+
+                if self.sent >= window && !waiting {
+                    if slowest {
+                        defmt::warn!("No neighbor answered at divisor {}", self.divisor());
+                        self.state = NegotiationState::Failed;
+                        return None;
+                    }
+
+                    self.step_down(self.rate + 1);
+                }
+
+                self.sent += 1;
+            }
+            NegotiationState::Agreed if self.lingered < LINGER_WORDS => self.lingered += 1,
+            NegotiationState::Agreed | NegotiationState::Failed => return None,
+        }
+
+        self.sequence = self.sequence.wrapping_add(1);
+        Some(probe(self.rate, self.receives_intact(), self.sequence))
+    }
+
+    /// Whether enough probes of the current rate arrived intact in a row.
+    fn receives_intact(&self) -> bool {
+        self.run_rate == self.rate && self.run >= GOOD_WORDS
+    }
+
+    /// Takes a received word.
+    pub fn receive(&mut self, word: u32) {
+        if self.state != NegotiationState::Probing {
+            return;
+        }
+
+        let Some((rate, ok, sequence)) = parse_probe(word) else {
+            self.run = 0;
+            self.expected = None;
+            return;
+        };
+
+        self.run = if self.expected == Some(sequence) && rate == self.run_rate {
+            self.run + 1
+        } else {
+            1
+        };
+        self.run_rate = rate;
+        self.expected = Some((sequence + 1) & SEQUENCE_MASK);
+
+        if rate == self.rate {
+            self.neighbor_ok = ok;
+
+            if self.receives_intact() && self.neighbor_ok {
+                defmt::info!("Link agreed on divisor {}", self.divisor());
+                self.state = NegotiationState::Agreed;
+            }
+        } else if rate > self.rate && self.run >= FOLLOW_WORDS {
+            // The neighbor already gave up on this rate. A faster neighbor follows once it receives
+            // ours.
+            self.step_down(rate);
+        }
+    }
+
+    fn step_down(&mut self, rate: usize) {
+        defmt::debug!(
+            "Stepping down from divisor {} to {}",
+            self.divisor(),
+            BIT_CLOCK_DIVISORS[rate]
+        );
+
+        self.rate = rate;
+        self.changed = true;
+        self.sent = 0;
+        self.expected = None;
+        self.run = 0;
+        self.neighbor_ok = false;
+    }
+}
+
+/// A single step of negotiating the rate of a link: moves the TX state machine to the current rate,
+/// sends the next probe if the TX FIFO has room, and passes the received words to `negotiator`.
+/// Returns whether the link is done.
+pub fn poll_negotiation<RX: ValidStateMachine, TX: ValidStateMachine>(
+    negotiator: &mut RateNegotiator,
+    tx_sm: &mut StateMachine<TX, Running>,
+    rx: &mut Rx<RX>,
+    tx: &mut Tx<TX>,
+) -> bool {
+    if let Some(divisor) = negotiator.take_divisor_change() {
+        tx_sm.clock_divisor_fixed_point(divisor, 0);
+    }
+
+    if !tx.is_full() {
+        if let Some(word) = negotiator.next_word() {
+            tx.write(word);
+        }
+    }
+
+    while let Some(word) = rx.read() {
+        negotiator.receive(word);
+    }
+
+    negotiator.is_done()
+}
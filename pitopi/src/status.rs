@@ -0,0 +1,48 @@
+//! Errors flagged by the receivers of three-wire links. A receiver that sees the word line drop
+//! before a whole word arrived drops the partial word, raises its relative IRQ flag and is aligned
+//! again by the next word, see `pitopi_rx` in `programs.pio`. A word that arrives while the RX FIFO
+//! is full is dropped, which sets the sticky RX stall flag of the state machine.
+//!
+//! Both flags stay set until taken, so however often an error happened in between, it is seen once.
+
+use rp_pico::{
+    hal::pio::{Rx, ValidStateMachine},
+    pac::PIO0,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, defmt::Format)]
+pub struct RxErrors {
+    /// The word line dropped before a whole word arrived.
+    pub misaligned: bool,
+    /// A word arrived while the RX FIFO was full.
+    pub overflowed: bool,
+}
+
+impl RxErrors {
+    pub fn any(self) -> bool {
+        self.misaligned || self.overflowed
+    }
+}
+
+/// Takes the error flags of the receiver behind `rx`, and clears them.
+pub fn take_rx_errors<SM: ValidStateMachine<PIO = PIO0>>(_rx: &Rx<SM>) -> RxErrors {
+    let mask = 1 << SM::id();
+    // Safety: both registers are write-one-to-clear, and only the bit of this state machine is
+    // written, which the owner of its RX FIFO may do.
+    let pio = unsafe { &*PIO0::ptr() };
+
+    let misaligned = pio.irq().read().irq().bits() & mask != 0;
+    let overflowed = pio.fdebug().read().rxstall().bits() & mask != 0;
+
+    if misaligned {
+        pio.irq().write(|w| unsafe { w.irq().bits(mask) });
+    }
+    if overflowed {
+        pio.fdebug().write(|w| unsafe { w.rxstall().bits(mask) });
+    }
+
+    RxErrors {
+        misaligned,
+        overflowed,
+    }
+}
@@ -0,0 +1,66 @@
+use std::{
+    error::Error,
+    time::{Duration, Instant},
+};
+
+use clap::Parser;
+use probe_rs::{probe::list::Lister, rtt::Rtt, Permissions};
+
+/// Sends parameter commands to a running node over the "params" RTT channels and prints the replies,
+/// e.g. `params 3 "set kp 0.002" apply get`.
+#[derive(Debug, Parser)]
+struct Arguments {
+    /// SWDIO pin of the node.
+    pin: u8,
+    /// Commands, one per argument.
+    commands: Vec<String>,
+    /// How long to wait for replies after the last command, in milliseconds.
+    #[arg(long, default_value_t = 500)]
+    timeout: u64,
+}
+
+const CHANNEL_NAME: &str = "params";
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = Arguments::parse();
+
+    scripts::usb::usb(args.pin)?;
+
+    let lister = Lister::new();
+    let probes = lister.list_all();
+    let probe = probes.first().ok_or("No probes found")?.open()?;
+    let mut session = probe.attach("rp2040", Permissions::default())?;
+    let mut core = session.core(0)?;
+    let mut rtt = Rtt::attach(&mut core)?;
+
+    let down = rtt
+        .down_channels()
+        .iter_mut()
+        .position(|channel| channel.name() == Some(CHANNEL_NAME))
+        .ok_or("The node has no parameter command channel")?;
+    let up = rtt
+        .up_channels()
+        .iter_mut()
+        .position(|channel| channel.name() == Some(CHANNEL_NAME))
+        .ok_or("The node has no parameter reply channel")?;
+
+    for command in args.commands {
+        let line = format!("{command}\n");
+        let mut written = 0;
+
+        // The down channel is small, the node empties it once per main loop iteration.
+        while written < line.len() {
+            written += rtt.down_channels()[down].write(&mut core, &line.as_bytes()[written..])?;
+        }
+    }
+
+    let deadline = Instant::now() + Duration::from_millis(args.timeout);
+    let mut buffer = [0; 256];
+
+    while Instant::now() < deadline {
+        let read = rtt.up_channels()[up].read(&mut core, &mut buffer)?;
+        print!("{}", String::from_utf8_lossy(&buffer[..read]));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,148 @@
+use std::{error::Error, fs, path::PathBuf};
+
+use clap::Parser;
+use controllers::{
+    controller::Controller,
+    law::Integrating,
+    pid::{PidLaw, PidSettings},
+    replay::{
+        parse_trace, record_corrections, replay, ActuatorOutput, ReplayActuator, ReplaySettings,
+    },
+    schedule::GainSchedule,
+    si5351::{pid_law, schedule_law, Si5351FracActuator},
+    sim::{simulated_si5351, Si5351Model},
+};
+use fixed::types::I16F16;
+
+/// `BUFFER_SIZE` of the minsync v0.2 boards.
+const BUFFER_SIZE: usize = 64;
+const DEGREE: usize = 4;
+
+/// Replays a trace of buffer levels recorded on a node through a candidate Si5351 PID controller, and
+/// compares its corrections with those of the node, e.g. `replay trace.csv --kp 0.002 --ki 0 --kd 0.05`.
+/// With `--acquire` the candidate runs a gain schedule instead, with the gains of `--kp`, `--ki` and
+/// `--kd` for tracking.
+#[derive(Debug, Parser)]
+struct Arguments {
+    /// One run per line: the buffer level of every link, optionally followed by the correction in ppm.
+    trace: PathBuf,
+    /// Links of the recorded node.
+    #[arg(long, default_value_t = DEGREE)]
+    links: usize,
+    /// Gains of the candidate.
+    #[arg(long, allow_negative_numbers = true)]
+    kp: f64,
+    #[arg(long, allow_negative_numbers = true)]
+    ki: f64,
+    #[arg(long, allow_negative_numbers = true)]
+    kd: f64,
+    /// Gains the node ran with, to compute the corrections of a trace without them. With `--acquire`
+    /// these are its tracking gains, and the node ran the same schedule.
+    #[arg(long, num_args = 3, value_names = ["KP", "KI", "KD"], allow_negative_numbers = true)]
+    recorded: Option<Vec<f64>>,
+    /// Gains while acquiring, which make the candidate run a gain schedule.
+    #[arg(long, num_args = 3, value_names = ["KP", "KI", "KD"], allow_negative_numbers = true)]
+    acquire: Option<Vec<f64>>,
+    /// Gains of the schedule in holdover.
+    #[arg(
+        long,
+        num_args = 3,
+        value_names = ["KP", "KI", "KD"],
+        default_values_t = [0., 0., 0.],
+        allow_negative_numbers = true
+    )]
+    holdover: Vec<f64>,
+    /// Total deviation in words within which the schedule counts as locked.
+    #[arg(long, default_value_t = 4.)]
+    lock_threshold: f64,
+    /// Total deviation in words beyond which a locked schedule acquires again.
+    #[arg(long, default_value_t = 16.)]
+    unlock_threshold: f64,
+    /// Runs within the lock threshold after which the schedule tracks.
+    #[arg(long, default_value_t = 1000)]
+    lock_runs: u32,
+    /// Difference between the corrections in ppm above which the candidate counts as diverged.
+    #[arg(long, default_value_t = 1.)]
+    divergence_tolerance: f64,
+}
+
+fn gains(gains: &[f64]) -> PidSettings {
+    PidSettings {
+        kp: I16F16::from_num(gains[0]),
+        ki: I16F16::from_num(gains[1]),
+        kd: I16F16::from_num(gains[2]),
+    }
+}
+
+/// A controller with `track` as its gains, in the schedule of `args` if there is one.
+fn controller(
+    track: &[f64],
+    args: &Arguments,
+) -> (
+    Controller<Integrating<PidLaw>, ReplayActuator, DEGREE>,
+    ActuatorOutput,
+) {
+    let (si, _) = simulated_si5351(Si5351Model::minsync(), 0x7ffff, 0.);
+    let (actuator, output) = ReplayActuator::like(&Si5351FracActuator::new(si));
+
+    let law = match &args.acquire {
+        Some(acquire) => schedule_law(GainSchedule {
+            acquire: gains(acquire),
+            track: gains(track),
+            holdover: gains(&args.holdover),
+            lock_threshold: I16F16::from_num(args.lock_threshold),
+            unlock_threshold: I16F16::from_num(args.unlock_threshold),
+            lock_runs: args.lock_runs,
+        }),
+        None => pid_law(gains(track)),
+    };
+
+    (Controller::new(law, actuator), output)
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = Arguments::parse();
+
+    if args.links > DEGREE {
+        return Err(format!("At most {DEGREE} links are supported").into());
+    }
+
+    let text = fs::read_to_string(&args.trace)?;
+    let mut trace = parse_trace(&text, args.links).map_err(|error| format!("{error:?}"))?;
+
+    if let Some(gains) = &args.recorded {
+        let (mut recorded, output) = controller(gains, &args);
+        record_corrections::<_, BUFFER_SIZE>(&mut trace, &mut recorded, &output)
+            .map_err(|error| format!("{error:?}"))?;
+    }
+
+    let (mut candidate, output) = controller(&[args.kp, args.ki, args.kd], &args);
+    let settings = ReplaySettings {
+        divergence_tolerance: args.divergence_tolerance,
+        ..Default::default()
+    };
+    let report = replay::<_, BUFFER_SIZE>(&trace, &mut candidate, &output, settings)
+        .map_err(|error| format!("{error:?}"))?;
+
+    println!("runs:                   {}", report.runs);
+    println!("rms divergence:         {:.3} ppm", report.rms_divergence);
+    println!("max divergence:         {:.3} ppm", report.max_divergence);
+    match report.first_divergence {
+        Some(run) => println!("first divergence:       run {run}"),
+        None => println!("first divergence:       none"),
+    }
+    match report.predicted_settling_run {
+        Some(run) => println!("predicted lock:         from run {run}"),
+        None => println!("predicted lock:         not within the trace"),
+    }
+    println!(
+        "predicted steady state: {:.2} words",
+        report.predicted_steady_state_error
+    );
+    println!(
+        "predicted max error:    {:.2} words",
+        report.predicted_max_error
+    );
+
+    Ok(())
+}